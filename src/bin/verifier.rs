@@ -1,59 +1,865 @@
+use alloy_primitives::Address;
 use alloy_signer_local::PrivateKeySigner;
 use dotenvy::dotenv;
-use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
-use reqwest::Client;
-use sha3::{Digest, Keccak256};
+use k256::ecdsa::SigningKey;
+use oyster::attestation::{self, AWS_ROOT_KEY, AttestationDecoded, AttestationExpectations};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_keypair::Keypair;
+use solana_signer::Signer;
 use std::env;
-use std::sync::Arc;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use x402_chain_eip155::V2Eip155ExactClient;
+use x402_chain_solana::V2SolanaExactClient;
+use x402_gateway::credit::CreditAuthorization;
+use x402_gateway::receipt::{RECEIPT_HEADER, Receipt};
+use x402_gateway::signing::{
+    SIGNATURE_CHUNK_HEADER, SIGNATURE_HEADER, SIGNATURE_TIMESTAMP_HEADER, unix_now,
+};
+use x402_gateway::verify::{SignedResponse, address, public_key_hex};
 use x402_reqwest::{ReqwestWithPayments, ReqwestWithPaymentsBuild, X402Client};
+use x402_types::chain::ChainIdPattern;
+use x402_types::networks::{chain_id_by_network_name, network_name_by_chain_id};
+use x402_types::scheme::client::{PaymentCandidate, PaymentSelector, PreferChain};
+use x402_types::util::Base64Bytes;
 
-fn build_signing_message(
-    request_method: &str,
-    request_path_and_query: &str,
-    request_body: &[u8],
-    response_body: &[u8],
-) -> Vec<u8> {
-    let method = request_method.as_bytes();
-    let path_and_query = request_path_and_query.as_bytes();
-
-    let mut message = Vec::with_capacity(
-        16 + method.len() + path_and_query.len() + request_body.len() + response_body.len(),
-    );
-    message.extend_from_slice(b"oyster-signature-v2\0");
-    message.extend_from_slice(&(method.len() as u32).to_be_bytes());
-    message.extend_from_slice(method);
-    message.extend_from_slice(&(path_and_query.len() as u32).to_be_bytes());
-    message.extend_from_slice(path_and_query);
-    message.extend_from_slice(&(request_body.len() as u64).to_be_bytes());
-    message.extend_from_slice(request_body);
-    message.extend_from_slice(&(response_body.len() as u64).to_be_bytes());
-    message.extend_from_slice(response_body);
-    message
+/// Decode a receipt header value and recover the key that signed it.
+fn verify_receipt(header: &[u8]) -> Result<(Receipt, String), Box<dyn std::error::Error>> {
+    let receipt = Receipt::from_header(header)?;
+    let pubkey = public_key_hex(&receipt.recover()?);
+    Ok((receipt, pubkey))
+}
+
+fn print_receipt(receipt: &Receipt, pubkey: &str) {
+    println!("Receipt payer: {}", receipt.payer);
+    println!("Receipt network: {}", receipt.network);
+    println!("Receipt amount: {}", receipt.amount);
+    println!("Receipt transaction: {}", receipt.transaction);
+    println!("Receipt request hash: {}", receipt.request_hash);
+    println!("Receipt timestamp: {}", receipt.timestamp);
+    println!("Receipt pubkey: {}", pubkey);
+}
+
+/// The signer the caller trusts, from `--expect-pubkey` and `--expect-address`.
+#[derive(Debug, Default)]
+struct ExpectedSigner {
+    pubkey: Option<String>,
+    address: Option<Address>,
+}
+
+impl ExpectedSigner {
+    fn from_args(args: &mut Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let pubkey = match take_flag(args, "--expect-pubkey")?.pop() {
+            Some(pubkey) => Some(normalize_pubkey(&pubkey)?),
+            None => None,
+        };
+        let address = match take_flag(args, "--expect-address")?.pop() {
+            Some(address) => Some(address.parse()?),
+            None => None,
+        };
+        Ok(Self { pubkey, address })
+    }
+
+    /// Fail unless `pubkey`, as returned by [`recover_pubkey`], is the
+    /// expected signer.
+    fn check(&self, pubkey: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(expected) = &self.pubkey
+            && expected != pubkey
+        {
+            return Err(format!("signed by {} but expected {}", pubkey, expected).into());
+        }
+        if let Some(expected) = self.address {
+            let address = pubkey_address(pubkey)?;
+            if address != expected {
+                return Err(format!("signed by {} but expected {}", address, expected).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase hex of the 64-byte public key, accepting a `0x` or `04` prefix.
+fn normalize_pubkey(pubkey: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let pubkey = pubkey.trim().trim_start_matches("0x").to_ascii_lowercase();
+    let pubkey = match pubkey.strip_prefix("04") {
+        Some(stripped) if pubkey.len() == 130 => stripped.to_string(),
+        _ => pubkey,
+    };
+    if hex::decode(&pubkey).map_or(true, |bytes| bytes.len() != 64) {
+        return Err(format!("expected a 64-byte secp256k1 public key, got {}", pubkey).into());
+    }
+    Ok(pubkey)
+}
+
+fn pubkey_address(pubkey: &str) -> Result<Address, Box<dyn std::error::Error>> {
+    Ok(Address::from_raw_public_key(&hex::decode(
+        normalize_pubkey(pubkey)?,
+    )?))
+}
+
+/// Enclave measurements `verify-gateway` accepts, from `--image-id` and
+/// `--pcrs` flags. Either kind of entry admits an enclave.
+#[derive(Debug, Default)]
+struct Allowlist {
+    image_ids: Vec<[u8; 32]>,
+    /// PCR0, PCR1, PCR2 and PCR16, which is zero when not given.
+    pcrs: Vec<[[u8; 48]; 4]>,
+}
+
+impl Allowlist {
+    fn from_args(args: &mut Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let image_ids = take_flag(args, "--image-id")?
+            .iter()
+            .map(|image_id| parse_hex_array(image_id))
+            .collect::<Result<_, _>>()?;
+        let pcrs = take_flag(args, "--pcrs")?
+            .iter()
+            .map(|pcrs| parse_pcrs(pcrs))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { image_ids, pcrs })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.image_ids.is_empty() && self.pcrs.is_empty()
+    }
+
+    fn permits(&self, attestation: &AttestationDecoded) -> bool {
+        self.image_ids.contains(&attestation.image_id) || self.pcrs.contains(&attestation.pcrs)
+    }
+}
+
+/// `PCR0,PCR1,PCR2[,PCR16]` as hex.
+fn parse_pcrs(list: &str) -> Result<[[u8; 48]; 4], Box<dyn std::error::Error>> {
+    let values: Vec<&str> = list.split(',').collect();
+    if !(3..=4).contains(&values.len()) {
+        return Err(format!("--pcrs takes PCR0,PCR1,PCR2[,PCR16], got {}", list).into());
+    }
+    let mut pcrs = [[0u8; 48]; 4];
+    for (pcr, value) in pcrs.iter_mut().zip(values) {
+        *pcr = parse_hex_array(value)?;
+    }
+    Ok(pcrs)
+}
+
+fn parse_hex_array<const N: usize>(value: &str) -> Result<[u8; N], Box<dyn std::error::Error>> {
+    let bytes = hex::decode(value.trim().trim_start_matches("0x"))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("expected {} bytes of hex, got {}", N, value).into())
+}
+
+/// Remove every `--name value` and `--name=value` from `args` and return the
+/// values in order.
+fn take_flag(
+    args: &mut Vec<String>,
+    name: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut values = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        if args[index] == name {
+            if index + 1 == args.len() {
+                return Err(format!("{} needs a value", name).into());
+            }
+            values.push(args.remove(index + 1));
+            args.remove(index);
+        } else if let Some(value) = args[index]
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            values.push(value.to_string());
+            args.remove(index);
+        } else {
+            index += 1;
+        }
+    }
+    Ok(values)
+}
+
+/// [`take_flag`] for a flag with several spellings, such as `-H` and
+/// `--header`.
+fn take_flags(
+    args: &mut Vec<String>,
+    names: &[&str],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut values = Vec::new();
+    for name in names {
+        values.extend(take_flag(args, name)?);
+    }
+    Ok(values)
+}
+
+/// Remove every `name` from `args` and return whether there was one.
+fn take_switch(args: &mut Vec<String>, name: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != before
+}
+
+/// How old an attestation `verify-gateway` accepts unless `--max-age` is
+/// given, in seconds.
+const DEFAULT_MAX_ATTESTATION_AGE_SECS: u64 = 300;
+
+/// What a gateway publishes at `/.well-known/oyster`.
+#[derive(Debug, Deserialize)]
+struct GatewayInfo {
+    public_key: String,
+    address: String,
+    attestation: Option<String>,
+}
+
+/// Check the key and attestation a gateway publishes against the allowlist.
+/// The attestation must chain to the AWS Nitro root, be at most `max_age_secs`
+/// old and vouch for the published key.
+async fn verify_gateway(
+    url: &str,
+    allowlist: &Allowlist,
+    expected: &ExpectedSigner,
+    max_age_secs: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if allowlist.is_empty() {
+        return Err("verify-gateway needs at least one --image-id or --pcrs".into());
+    }
+    let mut info_url: reqwest::Url = url.parse()?;
+    info_url.set_path("/.well-known/oyster");
+    info_url.set_query(None);
+    let info: GatewayInfo = Client::new()
+        .get(info_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let pubkey = normalize_pubkey(&info.public_key)?;
+    if pubkey_address(&pubkey)? != info.address.parse::<Address>()? {
+        return Err("published address does not belong to the published key".into());
+    }
+    let document = hex::decode(info.attestation.ok_or("gateway publishes no attestation")?)?;
+    let attestation = attestation::verify(
+        &document,
+        AttestationExpectations {
+            root_public_key: Some(&AWS_ROOT_KEY),
+            age_ms: Some((
+                max_age_secs.saturating_mul(1000),
+                unix_now().saturating_mul(1000),
+            )),
+            ..Default::default()
+        },
+    )?;
+
+    println!("Image ID: {}", hex::encode(attestation.image_id));
+    for (index, pcr) in [0, 1, 2, 16].iter().zip(&attestation.pcrs) {
+        println!("PCR{}: {}", index, hex::encode(pcr));
+    }
+    println!("Gateway pubkey: {}", pubkey);
+    println!("Gateway address: {}", info.address);
+
+    if !allowlist.permits(&attestation) {
+        return Err("enclave measurements are not in the allowlist".into());
+    }
+    check_key_binding(&attestation, &pubkey, expected)?;
+    println!("Attestation matches the allowlist");
+    Ok(())
+}
+
+/// Fail unless the attestation vouches for the published key: either the
+/// attested enclave key is the gateway key, or the caller gave the key
+/// `kms-derive` returns for the allowlisted image as `--expect-pubkey` or
+/// `--expect-address`, which only an enclave running that image can hold.
+fn check_key_binding(
+    attestation: &AttestationDecoded,
+    pubkey: &str,
+    expected: &ExpectedSigner,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let attested = normalize_pubkey(&hex::encode(&attestation.public_key)).ok();
+    if attested.as_deref() == Some(pubkey) {
+        return expected.check(pubkey);
+    }
+    if expected.pubkey.is_none() && expected.address.is_none() {
+        return Err("the attestation does not carry the published key; pass the kms-derive key as --expect-pubkey".into());
+    }
+    expected.check(pubkey)?;
+    println!("Gateway key matches the expected KMS-derived key");
+    Ok(())
+}
+
+/// How `verifier <url>` makes its request and reports the result.
+#[derive(Debug)]
+struct RequestOptions {
+    method: Method,
+    /// Sent as is and signed as is, so it is never re-encoded.
+    body: Vec<u8>,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
+    json: bool,
+    transcript_dir: Option<PathBuf>,
+    /// Networks to pay on when a 402 offers several, most preferred first.
+    networks: Vec<ChainIdPattern>,
+    /// Draw on prepaid credit, signing with `EVM_PRIVATE_KEY`, instead of
+    /// paying.
+    credit: bool,
+}
+
+impl RequestOptions {
+    fn from_args(args: &mut Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let method = match take_flags(args, &["-X", "--method"])?.pop() {
+            Some(method) => Method::from_bytes(method.to_ascii_uppercase().as_bytes())?,
+            None => Method::GET,
+        };
+
+        let data = take_flags(args, &["-d", "--data"])?.pop();
+        let data_file = take_flag(args, "--data-file")?.pop();
+        let body = match (data, data_file) {
+            (Some(_), Some(_)) => return Err("--data and --data-file cannot be combined".into()),
+            (Some(data), None) => data.into_bytes(),
+            (None, Some(path)) => {
+                std::fs::read(&path).map_err(|err| format!("{}: {}", path, err))?
+            }
+            (None, None) => Vec::new(),
+        };
+
+        let mut headers = HeaderMap::new();
+        for header in take_flags(args, &["-H", "--header"])? {
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| format!("--header takes \"Name: value\", got {}", header))?;
+            headers.append(
+                HeaderName::from_bytes(name.trim().as_bytes())?,
+                HeaderValue::from_str(value.trim())?,
+            );
+        }
+        // Gateways that do not know v4 answer with v2, which is checked as well
+        headers
+            .entry("X-Signature-Version")
+            .or_insert(HeaderValue::from_static("oyster-signature-v4"));
+
+        let timeout = match take_flag(args, "--timeout")?.pop() {
+            Some(secs) => Some(
+                Duration::try_from_secs_f64(secs.parse()?)
+                    .map_err(|_| format!("--timeout takes seconds, got {}", secs))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            method,
+            body,
+            headers,
+            timeout,
+            json: take_switch(args, "--json"),
+            transcript_dir: take_flag(args, "--transcript-dir")?
+                .pop()
+                .map(PathBuf::from),
+            networks: take_flag(args, "--network")?
+                .iter()
+                .map(|network| parse_network(network))
+                .collect::<Result<_, _>>()?,
+            credit: take_switch(args, "--credit"),
+        })
+    }
+}
+
+/// Sign the request with `EVM_PRIVATE_KEY` to draw on its prepaid credit.
+fn credit_authorization(
+    options: &RequestOptions,
+    path_and_query: &str,
+) -> Result<CreditAuthorization, Box<dyn std::error::Error>> {
+    let key = env::var("EVM_PRIVATE_KEY").map_err(|_| "--credit needs EVM_PRIVATE_KEY")?;
+    let key = SigningKey::from_slice(&hex::decode(key.trim().trim_start_matches("0x"))?)?;
+    let mut nonce = [0u8; 16];
+    getrandom::fill(&mut nonce).map_err(|e| format!("no random nonce: {}", e))?;
+    Ok(CreditAuthorization::sign(
+        &key,
+        unix_now(),
+        nonce,
+        &options.method,
+        path_and_query,
+        &options.body,
+    ))
+}
+
+/// A network name such as `base-sepolia`, or a CAIP-2 chain ID pattern such
+/// as `eip155:84532` or `solana:*`.
+fn parse_network(network: &str) -> Result<ChainIdPattern, Box<dyn std::error::Error>> {
+    if network.contains(':') {
+        return Ok(network.parse()?);
+    }
+    let chain_id =
+        chain_id_by_network_name(network).ok_or_else(|| format!("unknown network {}", network))?;
+    Ok(chain_id.clone().into())
+}
+
+/// A Solana keypair from `SOLANA_PRIVATE_KEY`: base58, as wallets export it,
+/// or the JSON byte array that `solana-keygen` writes.
+fn parse_solana_keypair(key: &str) -> Result<Keypair, Box<dyn std::error::Error>> {
+    let key = key.trim();
+    let bytes: Vec<u8> = if key.starts_with('[') {
+        serde_json::from_str(key)?
+    } else {
+        bs58::decode(key).into_vec()?
+    };
+    Ok(Keypair::try_from(bytes.as_slice())?)
+}
+
+/// The payment the verifier made in answer to a 402 challenge.
+#[derive(Debug, Clone, Serialize)]
+struct ChosenPayment {
+    network: Option<String>,
+    chain_id: String,
+    scheme: String,
+    asset: String,
+    amount: String,
+    pay_to: String,
+}
+
+impl From<&PaymentCandidate> for ChosenPayment {
+    fn from(candidate: &PaymentCandidate) -> Self {
+        Self {
+            network: network_name_by_chain_id(&candidate.chain_id).map(str::to_owned),
+            chain_id: candidate.chain_id.to_string(),
+            scheme: candidate.scheme.clone(),
+            asset: candidate.asset.clone(),
+            amount: candidate.amount.to_string(),
+            pay_to: candidate.pay_to.clone(),
+        }
+    }
+}
+
+/// Pays on the first `--network` the challenge offers, falling back to the
+/// first payment the registered signers can make, and remembers the choice.
+struct NetworkPreference {
+    preferred: PreferChain,
+    chosen: Arc<Mutex<Option<ChosenPayment>>>,
+}
+
+impl PaymentSelector for NetworkPreference {
+    fn select<'a>(&self, candidates: &'a [PaymentCandidate]) -> Option<&'a PaymentCandidate> {
+        let candidate = self.preferred.select(candidates)?;
+        *self.chosen.lock().unwrap() = Some(candidate.into());
+        Some(candidate)
+    }
+}
+
+/// The outcome of checking one exchange, printed as text or, with `--json`,
+/// as a JSON object. `verified` is the verdict.
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    verified: bool,
+    error: Option<String>,
+    transcript: Option<String>,
+    method: String,
+    url: String,
+    payment: Option<ChosenPayment>,
+    /// Payer whose prepaid credit the request drew on, with `--credit`.
+    credit_payer: Option<String>,
+    status: Option<u16>,
+    #[serde(skip)]
+    response_headers: Option<HeaderMap>,
+    response_body: Option<String>,
+    signature_version: Option<String>,
+    signed_at: Option<u64>,
+    signature: Option<String>,
+    pubkey: Option<String>,
+    address: Option<String>,
+    receipt: Option<Receipt>,
+    receipt_pubkey: Option<String>,
+}
+
+impl Report {
+    fn conclude(&mut self, outcome: &Result<(), Box<dyn std::error::Error>>) {
+        self.verified = outcome.is_ok();
+        self.error = outcome.as_ref().err().map(|err| err.to_string());
+    }
+
+    fn print(&self) {
+        if let Some(transcript) = &self.transcript {
+            println!("Transcript: {}", transcript);
+        }
+        if let Some(payment) = &self.payment {
+            println!(
+                "Paid on {} ({}): {} of {} to {} with the {} scheme",
+                payment.network.as_deref().unwrap_or("unknown network"),
+                payment.chain_id,
+                payment.amount,
+                payment.asset,
+                payment.pay_to,
+                payment.scheme,
+            );
+        }
+        if let Some(payer) = &self.credit_payer {
+            println!("Paid from the prepaid credit of {}", payer);
+        }
+        if let Some(headers) = &self.response_headers {
+            println!("Response Headers: {:?}", headers);
+        }
+        if let Some(body) = &self.response_body {
+            println!("Response: {:?}", body);
+        }
+        if let Some(version) = &self.signature_version {
+            println!("Signature version: {}", version);
+        }
+        if let Some(timestamp) = self.signed_at {
+            println!("Signed at: {}", timestamp);
+        }
+        if let Some(signature) = &self.signature {
+            println!("Signature: {}", signature);
+        }
+        if let Some(pubkey) = &self.pubkey {
+            println!("Pubkey: {}", pubkey);
+        }
+        if let Some(address) = &self.address {
+            println!("Address: {}", address);
+        }
+        if let (Some(receipt), Some(pubkey)) = (&self.receipt, &self.receipt_pubkey) {
+            print_receipt(receipt, pubkey);
+            if self.verified {
+                println!("Receipt matches the request and the response signer");
+            }
+        }
+    }
+}
+
+const TRANSCRIPT_VERSION: &str = "oyster-transcript-v1";
+
+/// One request and its response as the verifier saw them, with everything
+/// needed to check the signature and receipt later without the gateway.
+/// Bodies are base64 so they are kept byte for byte.
+#[derive(Debug, Deserialize, Serialize)]
+struct Transcript {
+    version: String,
+    recorded_at: u64,
+    url: String,
+    method: String,
+    path_and_query: String,
+    request_body: String,
+    status: u16,
+    /// Every response header in the order received, for the fields a v3 or
+    /// v4 signature covers.
+    response_headers: Vec<(String, String)>,
+    response_body: String,
+    signature: Option<String>,
+    receipt: Option<String>,
+}
+
+impl Transcript {
+    async fn record(
+        url: &str,
+        path_and_query: &str,
+        options: &RequestOptions,
+        response: reqwest::Response,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let status = response.status().as_u16();
+        let mut response_headers = Vec::new();
+        for (name, value) in response.headers() {
+            let value = value
+                .to_str()
+                .map_err(|_| format!("{} header is not text", name))?;
+            response_headers.push((name.to_string(), value.to_owned()));
+        }
+        let header = |name: &str| {
+            response_headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+        let receipt = header(RECEIPT_HEADER);
+        let mut response_body = response.bytes().await?;
+        // A streamed response ends with a final chunk holding its signature
+        let signature = match (header(SIGNATURE_HEADER), header(SIGNATURE_CHUNK_HEADER)) {
+            (Some(signature), _) => Some(signature),
+            (None, Some(len)) => {
+                let len: usize = len.parse()?;
+                let body_len = response_body
+                    .len()
+                    .checked_sub(len)
+                    .ok_or("response is shorter than its signature chunk")?;
+                let chunk = response_body.split_off(body_len);
+                Some(String::from_utf8(chunk.to_vec())?)
+            }
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            version: TRANSCRIPT_VERSION.to_owned(),
+            recorded_at: unix_now(),
+            url: url.to_owned(),
+            method: options.method.to_string(),
+            path_and_query: path_and_query.to_owned(),
+            request_body: Base64Bytes::encode(&options.body).to_string(),
+            status,
+            response_headers,
+            response_body: Base64Bytes::encode(&response_body).to_string(),
+            signature,
+            receipt,
+        })
+    }
+
+    fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let json = std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let transcript: Self = serde_json::from_slice(&json)?;
+        if transcript.version != TRANSCRIPT_VERSION {
+            return Err(format!("unsupported transcript version {}", transcript.version).into());
+        }
+        Ok(transcript)
+    }
+
+    /// Write the transcript to a new file in `dir`, named after the time it
+    /// was recorded and the start of its signature.
+    fn save(&self, dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(dir)?;
+        let tag = match &self.signature {
+            Some(signature) => signature.chars().take(16).collect(),
+            None => "unsigned".to_owned(),
+        };
+        let json = serde_json::to_vec_pretty(self)?;
+        for attempt in 0.. {
+            let name = match attempt {
+                0 => format!("{}-{}.json", self.recorded_at, tag),
+                _ => format!("{}-{}-{}.json", self.recorded_at, tag, attempt),
+            };
+            let path = dir.join(name);
+            match File::create_new(&path) {
+                Ok(mut file) => {
+                    file.write_all(&json)?;
+                    return Ok(path);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(format!("{}: {}", path.display(), err).into()),
+            }
+        }
+        unreachable!("attempts are unbounded")
+    }
+
+    fn headers(&self) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.response_headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// The transcript files under `path`: the file itself, or every `.json` file
+/// in a directory, in name order.
+fn transcript_paths(path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Check the signature and receipt in `transcript` against the request it
+/// records, filling in `report` as far as the checks get.
+fn verify_transcript(
+    transcript: &Transcript,
+    expected: &ExpectedSigner,
+    report: &mut Report,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request_body = Base64Bytes::from(transcript.request_body.as_bytes()).decode()?;
+    let response_body = Base64Bytes::from(transcript.response_body.as_bytes()).decode()?;
+    let headers = transcript.headers()?;
+    let method = Method::from_bytes(transcript.method.as_bytes())?;
+    report.method = transcript.method.clone();
+    report.url = transcript.url.clone();
+    report.status = Some(transcript.status);
+    report.response_body = Some(String::from_utf8_lossy(&response_body).into_owned());
+    report.signed_at = headers
+        .get(SIGNATURE_TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    report.response_headers = Some(headers.clone());
+
+    let response = SignedResponse {
+        method: &method,
+        path_and_query: &transcript.path_and_query,
+        request_body: &request_body,
+        status: StatusCode::from_u16(transcript.status)?,
+        headers: &headers,
+        body: &response_body,
+    };
+    report.signature_version = Some(response.version()?.as_str().to_owned());
+
+    let signature_hex = transcript
+        .signature
+        .as_deref()
+        .ok_or("no X-Signature header")?;
+    report.signature = Some(signature_hex.to_owned());
+
+    let key = response.recover(signature_hex)?;
+    let pubkey = public_key_hex(&key);
+    report.pubkey = Some(pubkey.clone());
+    report.address = Some(address(&key).to_string());
+    expected.check(&pubkey)?;
+
+    if let Some(header) = &transcript.receipt {
+        let (receipt, receipt_pubkey) = verify_receipt(header.as_bytes())?;
+        let request_hash = receipt.request_hash.clone();
+        report.receipt = Some(receipt);
+        report.receipt_pubkey = Some(receipt_pubkey.clone());
+
+        let request_digest = response.request_digest()?;
+        if request_hash != format!("0x{}", hex::encode(request_digest.0)) {
+            return Err("receipt is for a different request".into());
+        }
+        if receipt_pubkey != pubkey {
+            return Err("receipt and response were signed by different keys".into());
+        }
+    }
+    Ok(())
+}
+
+/// Record `response`, save it when `--transcript-dir` is given, and verify
+/// it.
+async fn check_response(
+    response: reqwest::Response,
+    url: &str,
+    path_and_query: &str,
+    options: &RequestOptions,
+    expected: &ExpectedSigner,
+    report: &mut Report,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let transcript = Transcript::record(url, path_and_query, options, response).await?;
+    if let Some(dir) = &options.transcript_dir {
+        report.transcript = Some(transcript.save(dir)?.display().to_string());
+    }
+    verify_transcript(&transcript, expected, report)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let expected = ExpectedSigner::from_args(&mut args)?;
+    let allowlist = Allowlist::from_args(&mut args)?;
+
+    if args.first().map(String::as_str) == Some("verify-gateway") {
+        let max_age_secs = match take_flag(&mut args, "--max-age")?.pop() {
+            Some(secs) => secs.parse()?,
+            None => DEFAULT_MAX_ATTESTATION_AGE_SECS,
+        };
+        let url = args.get(1).expect(
+            "Usage: verifier verify-gateway <url> (--image-id <hex> | --pcrs <pcr0>,<pcr1>,<pcr2>[,<pcr16>])... [--max-age <secs>]",
+        );
+        return verify_gateway(url, &allowlist, &expected, max_age_secs).await;
+    }
+    if !allowlist.is_empty() {
+        return Err("--image-id and --pcrs only apply to verify-gateway".into());
+    }
+
+    // Receipts carry everything needed to check them, so no request is made
+    if args.first().map(String::as_str) == Some("verify-receipt") {
+        let receipt = args
+            .get(1)
+            .expect("Usage: verifier verify-receipt <X-Payment-Receipt value>");
+        let (receipt, pubkey) = verify_receipt(receipt.trim().as_bytes())?;
+        print_receipt(&receipt, &pubkey);
+        return expected.check(&pubkey);
+    }
+
+    let options = RequestOptions::from_args(&mut args)?;
+
+    if args.first().map(String::as_str) == Some("verify-transcript") {
+        let path = args
+            .get(1)
+            .expect("Usage: verifier verify-transcript <file or directory>");
+        let mut reports = Vec::new();
+        for path in transcript_paths(Path::new(path))? {
+            let mut report = Report {
+                transcript: Some(path.display().to_string()),
+                ..Default::default()
+            };
+            let outcome = Transcript::load(&path)
+                .and_then(|transcript| verify_transcript(&transcript, &expected, &mut report));
+            report.conclude(&outcome);
+            reports.push(report);
+        }
+        let failed = reports.iter().filter(|report| !report.verified).count();
+
+        if options.json {
+            println!("{}", serde_json::to_string_pretty(&reports)?);
+        } else {
+            for report in &reports {
+                report.print();
+                match &report.error {
+                    Some(error) => println!("Error: {}", error),
+                    None => println!("Verified"),
+                }
+                println!();
+            }
+        }
+        if failed > 0 {
+            if options.json {
+                std::process::exit(1);
+            }
+            return Err(format!("{} of {} transcripts failed", failed, reports.len()).into());
+        }
+        return Ok(());
+    }
+
     let mut x402_client = X402Client::new();
     {
         let signer: Option<PrivateKeySigner> =
             env::var("EVM_PRIVATE_KEY").ok().and_then(|key| key.parse().ok());
         if let Some(signer) = signer {
-            println!("Using EVM signer address: {:?}", signer.address());
+            if !options.json {
+                println!("Using EVM signer address: {:?}", signer.address());
+            }
             let signer = Arc::new(signer);
             x402_client = x402_client
                 .register(V2Eip155ExactClient::new(signer));
-            println!("Enabled eip155 exact scheme");
+            if !options.json {
+                println!("Enabled eip155 exact scheme");
+            }
+        }
+        if let Ok(key) = env::var("SOLANA_PRIVATE_KEY") {
+            let keypair = Arc::new(parse_solana_keypair(&key)?);
+            if !options.json {
+                println!("Using Solana signer address: {}", keypair.pubkey());
+            }
+            let rpc_url = env::var("SOLANA_RPC_URL")
+                .unwrap_or_else(|_| "https://api.devnet.solana.com".to_owned());
+            x402_client = x402_client.register(V2SolanaExactClient::new(
+                keypair,
+                Arc::new(RpcClient::new(rpc_url)),
+            ));
+            if !options.json {
+                println!("Enabled solana exact scheme");
+            }
         }
     };
+    let chosen_payment = Arc::new(Mutex::new(None));
+    let x402_client = x402_client.with_selector(NetworkPreference {
+        preferred: PreferChain::new(options.networks.clone()),
+        chosen: chosen_payment.clone(),
+    });
 
     let http_client = Client::new().with_payments(x402_client).build();
 
-    let url = std::env::args()
-        .nth(1)
-        .expect("Usage: verifier <url>");
+    let url = args.first().cloned().expect(
+        "Usage: verifier [--method <method>] [--data <body> | --data-file <path>] [--header <name: value>]... [--timeout <secs>] [--json] [--transcript-dir <dir>] [--network <name>]... [--credit] [--expect-pubkey <hex>] [--expect-address <address>] <url>",
+    );
     let parsed_url: reqwest::Url = url.parse()?;
     let path_and_query = format!(
         "{}{}",
@@ -64,43 +870,289 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .unwrap_or_default()
     );
 
-    let response = http_client.get(url).send().await?;
-    println!("Response Headers: {:?}", response.headers());
+    let mut report = Report {
+        method: options.method.to_string(),
+        url: url.clone(),
+        ..Default::default()
+    };
+    let sent: Result<_, Box<dyn std::error::Error>> = if options.credit {
+        // Paid from the balance only, so a 402 is reported rather than paid
+        let authorization = credit_authorization(&options, &path_and_query)?;
+        report.credit_payer = Some(authorization.payer.to_checksum(None));
+        let mut headers = options.headers.clone();
+        authorization.insert_headers(&mut headers);
+        let mut request = Client::new()
+            .request(options.method.clone(), parsed_url)
+            .headers(headers)
+            .body(options.body.clone());
+        if let Some(timeout) = options.timeout {
+            request = request.timeout(timeout);
+        }
+        request.send().await.map_err(Into::into)
+    } else {
+        let mut request = http_client
+            .request(options.method.clone(), parsed_url)
+            .headers(options.headers.clone())
+            .body(options.body.clone());
+        if let Some(timeout) = options.timeout {
+            request = request.timeout(timeout);
+        }
+        request.send().await.map_err(Into::into)
+    };
+    let outcome = match sent {
+        Ok(response) => {
+            check_response(
+                response,
+                &url,
+                &path_and_query,
+                &options,
+                &expected,
+                &mut report,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+    report.payment = chosen_payment.lock().unwrap().take();
+    report.conclude(&outcome);
 
-    let signature_hex = response
-        .headers()
-        .get("X-Signature")
-        .expect("no X-Signature header")
-        .to_str()
-        .expect("invalid header value")
-        .to_owned();
-    let signature_bytes = hex::decode(&signature_hex).expect("invalid signature hex");
-    if signature_bytes.len() != 65 {
-        return Err("expected 65-byte signature".into());
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if !report.verified {
+            std::process::exit(1);
+        }
+        return Ok(());
     }
+    report.print();
+    outcome
+}
 
-    let response_body = response.bytes().await?.to_vec();
-    println!("Response: {:?}", String::from_utf8_lossy(&response_body));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x402_gateway::signing::{build_signing_message, sign_message};
 
-    let signing_message = build_signing_message("GET", &path_and_query, b"", &response_body);
+    const PUBKEY: &str = "1b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f70beaf8f588b541507fed6a642c5ab42dfdf8120a7f639de5122d47a69a8e8d1";
 
-    let mut hasher = Keccak256::new();
-    hasher.update(&signing_message);
-    let hash = hasher.finalize();
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
 
-    let signature =
-        Signature::from_slice(&signature_bytes[0..64]).expect("failed to parse signature");
+    #[test]
+    fn test_take_flag() {
+        let mut list = args(&["--pcrs", "a", "verify-gateway", "--pcrs=b", "http://x"]);
+        assert_eq!(take_flag(&mut list, "--pcrs").unwrap(), ["a", "b"]);
+        assert_eq!(list, ["verify-gateway", "http://x"]);
 
-    let recid_byte = signature_bytes[64];
-    let recovery_id = RecoveryId::from_byte(recid_byte - 27).expect("failed to parse recovery id");
+        let mut list = args(&["http://x", "--expect-pubkey"]);
+        assert!(take_flag(&mut list, "--expect-pubkey").is_err());
+    }
 
-    let verifying_key = VerifyingKey::recover_from_prehash(&hash, &signature, recovery_id)
-        .expect("failed to recover pubkey");
+    #[test]
+    fn test_request_options() {
+        let mut list = args(&[
+            "-X",
+            "post",
+            "--data",
+            "{\"model\": \"qwen3:0.6b\"}",
+            "-H",
+            "Content-Type: application/json",
+            "--header=X-Signature-Version: oyster-signature-v2",
+            "--timeout",
+            "2.5",
+            "--json",
+            "--credit",
+            "http://x/api/chat",
+        ]);
+        let options = RequestOptions::from_args(&mut list).unwrap();
+        assert_eq!(list, ["http://x/api/chat"]);
+        assert_eq!(options.method, Method::POST);
+        assert_eq!(options.body, b"{\"model\": \"qwen3:0.6b\"}");
+        assert_eq!(options.headers["content-type"], "application/json");
+        assert_eq!(
+            options.headers["x-signature-version"],
+            "oyster-signature-v2"
+        );
+        assert_eq!(options.timeout, Some(Duration::from_millis(2500)));
+        assert!(options.json);
+        assert!(options.credit);
 
-    let pubkey = hex::encode(&verifying_key.to_encoded_point(false).as_bytes()[1..]);
+        let defaults = RequestOptions::from_args(&mut args(&["http://x"])).unwrap();
+        assert_eq!(defaults.method, Method::GET);
+        assert!(defaults.body.is_empty());
+        assert_eq!(
+            defaults.headers["x-signature-version"],
+            "oyster-signature-v4"
+        );
+        assert!(!defaults.json);
+        assert!(!defaults.credit);
 
-    println!("Signature: {}", signature_hex);
-    println!("Pubkey: {}", pubkey);
+        // File bodies are sent byte for byte, trailing newline included
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), b"line\n").unwrap();
+        let path = file.path().to_str().unwrap();
+        let options = RequestOptions::from_args(&mut args(&["--data-file", path])).unwrap();
+        assert_eq!(options.body, b"line\n");
+        assert!(RequestOptions::from_args(&mut args(&["-d", "x", "--data-file", path])).is_err());
+        assert!(RequestOptions::from_args(&mut args(&["-H", "no colon"])).is_err());
+    }
 
-    Ok(())
+    #[test]
+    fn test_expected_signer() {
+        let mut list = args(&[
+            "--expect-pubkey",
+            &format!("0x04{}", PUBKEY.to_uppercase()),
+            "--expect-address",
+            "0x1a642f0e3c3af545e7acbd38b07251b3990914f1",
+        ]);
+        let expected = ExpectedSigner::from_args(&mut list).unwrap();
+        assert!(list.is_empty());
+        assert!(expected.check(PUBKEY).is_ok());
+
+        let other = hex::encode(
+            &SigningKey::from_bytes(&[2u8; 32].into())
+                .unwrap()
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()[1..],
+        );
+        assert!(expected.check(&other).is_err());
+        let by_address = ExpectedSigner {
+            address: expected.address,
+            ..Default::default()
+        };
+        assert!(by_address.check(&other).is_err());
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(
+            parse_network("solana-devnet").unwrap().to_string(),
+            "solana:EtWTRABZaYq6iMfeYKouRu166VU2xqa1"
+        );
+        assert_eq!(parse_network("eip155:*").unwrap().to_string(), "eip155:*");
+        assert!(parse_network("nowhere").is_err());
+    }
+
+    #[test]
+    fn test_parse_solana_keypair() {
+        let keypair = Keypair::new_from_array([7u8; 32]);
+        let base58 = parse_solana_keypair(&keypair.to_base58_string()).unwrap();
+        assert_eq!(base58.pubkey(), keypair.pubkey());
+        let json = serde_json::to_string(&keypair.to_bytes().to_vec()).unwrap();
+        assert_eq!(
+            parse_solana_keypair(&json).unwrap().pubkey(),
+            keypair.pubkey()
+        );
+        assert!(parse_solana_keypair("not a key").is_err());
+    }
+
+    #[test]
+    fn test_transcript() {
+        let signing_key = SigningKey::from_bytes(&[1u8; 32].into()).unwrap();
+        let message = build_signing_message(&Method::POST, "/api/chat?x=1", b"{}\n", b"hello");
+        let signature = sign_message(&signing_key, &message);
+
+        let mut transcript = Transcript {
+            version: TRANSCRIPT_VERSION.to_owned(),
+            recorded_at: 1_700_000_000,
+            url: "http://x/api/chat?x=1".to_owned(),
+            method: "POST".to_owned(),
+            path_and_query: "/api/chat?x=1".to_owned(),
+            request_body: Base64Bytes::encode(b"{}\n").to_string(),
+            status: 200,
+            response_headers: vec![("content-type".to_owned(), "text/plain".to_owned())],
+            response_body: Base64Bytes::encode(b"hello").to_string(),
+            signature: Some(signature),
+            receipt: None,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let first = transcript.save(dir.path()).unwrap();
+        let second = transcript.save(dir.path()).unwrap();
+        assert_ne!(first, second);
+        std::fs::write(dir.path().join("notes.txt"), "skipped").unwrap();
+        let paths = transcript_paths(dir.path()).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains(&first) && paths.contains(&second));
+        assert_eq!(
+            transcript_paths(&first).unwrap(),
+            std::slice::from_ref(&first)
+        );
+
+        let mut report = Report::default();
+        let loaded = Transcript::load(&first).unwrap();
+        verify_transcript(&loaded, &ExpectedSigner::default(), &mut report).unwrap();
+        assert_eq!(report.pubkey.as_deref(), Some(PUBKEY));
+        assert_eq!(
+            report.signature_version.as_deref(),
+            Some("oyster-signature-v2")
+        );
+
+        // A body that lost its trailing newline no longer matches
+        transcript.request_body = Base64Bytes::encode(b"{}").to_string();
+        let mut report = Report::default();
+        verify_transcript(&transcript, &ExpectedSigner::default(), &mut report).unwrap();
+        assert_ne!(report.pubkey.as_deref(), Some(PUBKEY));
+
+        transcript.signature = None;
+        assert!(verify_transcript(&transcript, &ExpectedSigner::default(), &mut report).is_err());
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let pcr = |byte: u8| hex::encode([byte; 48]);
+        let mut list = args(&[
+            "--pcrs",
+            &format!("{},{},{}", pcr(0), pcr(1), pcr(2)),
+            "--image-id",
+            &hex::encode([9u8; 32]),
+        ]);
+        let allowlist = Allowlist::from_args(&mut list).unwrap();
+
+        let mut attestation = AttestationDecoded {
+            root_public_key: Default::default(),
+            image_id: [0u8; 32],
+            pcrs: [[0; 48], [1; 48], [2; 48], [0; 48]],
+            timestamp_ms: 0,
+            public_key: Default::default(),
+            user_data: Default::default(),
+        };
+        assert!(allowlist.permits(&attestation));
+        attestation.pcrs[3] = [16; 48];
+        assert!(!allowlist.permits(&attestation));
+        attestation.image_id = [9u8; 32];
+        assert!(allowlist.permits(&attestation));
+
+        assert!(parse_pcrs(&pcr(0)).is_err());
+        assert!(parse_pcrs(&format!("{},{},00", pcr(0), pcr(1))).is_err());
+    }
+
+    #[test]
+    fn test_key_binding() {
+        let mut attestation = AttestationDecoded {
+            root_public_key: Default::default(),
+            image_id: [0u8; 32],
+            pcrs: [[0; 48]; 4],
+            timestamp_ms: 0,
+            public_key: hex::decode(PUBKEY).unwrap().into(),
+            user_data: Default::default(),
+        };
+        let unexpected = ExpectedSigner::default();
+        assert!(check_key_binding(&attestation, PUBKEY, &unexpected).is_ok());
+
+        // An enclave key that is not the gateway key needs the KMS-derived key
+        attestation.public_key = vec![7u8; 32].into();
+        assert!(check_key_binding(&attestation, PUBKEY, &unexpected).is_err());
+        let kms_derived = ExpectedSigner {
+            pubkey: Some(PUBKEY.to_string()),
+            address: None,
+        };
+        assert!(check_key_binding(&attestation, PUBKEY, &kms_derived).is_ok());
+        let other = ExpectedSigner {
+            pubkey: Some("ab".repeat(64)),
+            address: None,
+        };
+        assert!(check_key_binding(&attestation, PUBKEY, &other).is_err());
+    }
 }
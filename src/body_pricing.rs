@@ -0,0 +1,300 @@
+use crate::config::BodyPriceRule;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+pub const PRICE_BREAKDOWN_HEADER: &str = "X-Price-Breakdown";
+
+/// Largest request body read to price a request; bigger bodies get `413`.
+pub const MAX_PRICED_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// The subset of JSONPath used by pricing rules: `$`, `.key`, `["key"]` and `[0]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    steps: Vec<Step>,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid JSON path {}: {}", path, reason);
+        let mut rest = path
+            .strip_prefix('$')
+            .ok_or_else(|| invalid("must start with '$'"))?;
+
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    return Err(invalid("empty key"));
+                }
+                steps.push(Step::Key(after[..end].to_string()));
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(|| invalid("unclosed '['"))?;
+                let inner = &after[..end];
+                let quoted = inner
+                    .strip_prefix('"')
+                    .and_then(|k| k.strip_suffix('"'))
+                    .or_else(|| inner.strip_prefix('\'').and_then(|k| k.strip_suffix('\'')));
+                let step = match quoted {
+                    Some(key) => Step::Key(key.to_string()),
+                    None => Step::Index(
+                        inner
+                            .parse()
+                            .map_err(|_| invalid("expected an index or a quoted key"))?,
+                    ),
+                };
+                steps.push(step);
+                rest = &after[end + 1..];
+            } else {
+                return Err(invalid("expected '.' or '['"));
+            }
+        }
+        Ok(Self { steps })
+    }
+
+    pub fn get<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.steps.iter().try_fold(value, |value, step| match step {
+            Step::Key(key) => value.get(key),
+            Step::Index(index) => value.get(index),
+        })
+    }
+}
+
+/// A `json_path` from the config, parsed once when the config is read. An
+/// invalid path keeps its error for config validation to report.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct ConfigJsonPath {
+    text: String,
+    parsed: Result<JsonPath, String>,
+}
+
+impl ConfigJsonPath {
+    pub fn parsed(&self) -> Result<&JsonPath, &str> {
+        self.parsed.as_ref().map_err(String::as_str)
+    }
+
+    /// The value at the path; `None` when the path is invalid.
+    pub fn get<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.parsed.as_ref().ok()?.get(value)
+    }
+}
+
+impl From<String> for ConfigJsonPath {
+    fn from(text: String) -> Self {
+        Self {
+            parsed: JsonPath::parse(&text),
+            text,
+        }
+    }
+}
+
+impl From<&str> for ConfigJsonPath {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+impl fmt::Display for ConfigJsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// What one rule added to the price.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RulePrice {
+    pub json_path: String,
+    /// The listed value that matched, the number of units, or `default`.
+    pub value: String,
+    pub amount: u64,
+}
+
+/// How the price of a request was computed, returned in [`PRICE_BREAKDOWN_HEADER`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PriceBreakdown {
+    pub base: u64,
+    pub rules: Vec<RulePrice>,
+    pub total: u64,
+}
+
+impl fmt::Display for PriceBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "base {}", self.base)?;
+        for rule in &self.rules {
+            write!(f, " + {} {}: {}", rule.json_path, rule.value, rule.amount)?;
+        }
+        write!(f, " = {} USDC microunits", self.total)
+    }
+}
+
+/// Price a request from its body. A body that is missing or not JSON prices
+/// every rule at its default.
+///
+/// Fails on a negative unit count for a `per_unit` rule without `max_units`.
+pub fn price_request(
+    base: u64,
+    rules: &[BodyPriceRule],
+    body: &[u8],
+) -> Result<PriceBreakdown, String> {
+    let json: Value = serde_json::from_slice(body).unwrap_or(Value::Null);
+    let mut total = base;
+    let mut priced = Vec::with_capacity(rules.len());
+
+    for rule in rules {
+        let (json_path, value, amount) = match rule {
+            BodyPriceRule::Match {
+                json_path,
+                prices,
+                default,
+            } => {
+                let found = json_path.get(&json).map(|value| match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                });
+                match found.and_then(|key| prices.get_key_value(&key)) {
+                    Some((key, &amount)) => (json_path, key.clone(), amount),
+                    None => (json_path, "default".to_string(), *default),
+                }
+            }
+            BodyPriceRule::PerUnit {
+                json_path,
+                usdc_per_unit,
+                default_units,
+                max_units,
+            } => {
+                let number = json_path.get(&json).filter(|value| value.is_number());
+                let (value, units) = match number {
+                    // Negative counts mean "no limit" to upstreams such as Ollama
+                    Some(n) if n.as_f64().is_some_and(|f| f < 0.0) => match max_units {
+                        Some(max) => (format!("{} units (max)", max), *max),
+                        None => return Err(format!("{} must not be negative", json_path)),
+                    },
+                    Some(n) => {
+                        let units = n
+                            .as_u64()
+                            .unwrap_or_else(|| n.as_f64().map_or(0, |f| f.ceil() as u64));
+                        (format!("{} units", units), units)
+                    }
+                    None => (format!("{} units (default)", default_units), *default_units),
+                };
+                (json_path, value, units.saturating_mul(*usdc_per_unit))
+            }
+        };
+        total = total.saturating_add(amount);
+        priced.push(RulePrice {
+            json_path: json_path.to_string(),
+            value,
+            amount,
+        });
+    }
+
+    Ok(PriceBreakdown {
+        base,
+        rules: priced,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ollama_rules() -> Vec<BodyPriceRule> {
+        vec![
+            BodyPriceRule::Match {
+                json_path: "$.model".into(),
+                prices: [
+                    ("qwen3:0.6b".to_string(), 10),
+                    ("qwen3:32b".to_string(), 500),
+                ]
+                .into(),
+                default: 100,
+            },
+            BodyPriceRule::PerUnit {
+                json_path: "$.options.num_predict".into(),
+                usdc_per_unit: 2,
+                default_units: 256,
+                max_units: Some(4096),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_json_path() {
+        let value = json!({ "a": { "b c": [1, { "d": true }] } });
+        let path = JsonPath::parse("$.a['b c'][1].d").unwrap();
+        assert_eq!(path.get(&value), Some(&json!(true)));
+        assert_eq!(JsonPath::parse("$").unwrap().get(&value), Some(&value));
+        assert_eq!(JsonPath::parse("$.a.x").unwrap().get(&value), None);
+
+        assert!(JsonPath::parse("model").is_err());
+        assert!(JsonPath::parse("$..model").is_err());
+        assert!(JsonPath::parse("$.a[x]").is_err());
+        assert!(JsonPath::parse("$.a[0").is_err());
+    }
+
+    #[test]
+    fn test_price_request_by_model_and_tokens() {
+        let body = json!({ "model": "qwen3:32b", "options": { "num_predict": 100 } });
+        let breakdown = price_request(1, &ollama_rules(), body.to_string().as_bytes()).unwrap();
+        assert_eq!(breakdown.total, 1 + 500 + 200);
+        assert_eq!(breakdown.rules[0].value, "qwen3:32b");
+        assert_eq!(breakdown.rules[1].value, "100 units");
+        assert_eq!(
+            breakdown.to_string(),
+            "base 1 + $.model qwen3:32b: 500 + $.options.num_predict 100 units: 200 = 701 USDC microunits"
+        );
+
+        let small = json!({ "model": "qwen3:0.6b", "options": { "num_predict": 100 } });
+        let breakdown = price_request(1, &ollama_rules(), small.to_string().as_bytes()).unwrap();
+        assert_eq!(breakdown.total, 1 + 10 + 200);
+    }
+
+    #[test]
+    fn test_price_request_defaults() {
+        let body = json!({ "model": "unknown-model" });
+        let breakdown = price_request(0, &ollama_rules(), body.to_string().as_bytes()).unwrap();
+        assert_eq!(breakdown.rules[0].value, "default");
+        assert_eq!(breakdown.rules[1].value, "256 units (default)");
+        assert_eq!(breakdown.total, 100 + 512);
+
+        let breakdown = price_request(0, &ollama_rules(), b"not json").unwrap();
+        assert_eq!(breakdown.total, 100 + 512);
+    }
+
+    #[test]
+    fn test_price_request_negative_units() {
+        let body = json!({ "model": "qwen3:0.6b", "options": { "num_predict": -1 } });
+        let breakdown = price_request(0, &ollama_rules(), body.to_string().as_bytes()).unwrap();
+        assert_eq!(breakdown.rules[1].value, "4096 units (max)");
+        assert_eq!(breakdown.total, 10 + 4096 * 2);
+
+        let mut rules = ollama_rules();
+        if let BodyPriceRule::PerUnit { max_units, .. } = &mut rules[1] {
+            *max_units = None;
+        }
+        let err = price_request(0, &rules, body.to_string().as_bytes()).unwrap_err();
+        assert_eq!(err, "$.options.num_predict must not be negative");
+    }
+
+    #[test]
+    fn test_config_json_path() {
+        let path = ConfigJsonPath::from("$.a[1]");
+        assert_eq!(path.to_string(), "$.a[1]");
+        assert_eq!(path.get(&json!({ "a": [1, 2] })), Some(&json!(2)));
+
+        let invalid = ConfigJsonPath::from("a");
+        assert!(invalid.parsed().is_err());
+        assert_eq!(invalid.get(&json!({ "a": 1 })), None);
+    }
+}
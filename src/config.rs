@@ -1,7 +1,12 @@
+use crate::body_pricing::ConfigJsonPath;
+use crate::pricing;
+use crate::routes::{RoutePattern, normalize_path};
+use axum::http::{HeaderName, Method, StatusCode, header};
 use serde::Deserialize;
-use std::fs;
+use std::{borrow::Cow, collections::BTreeMap, fmt, fs, net::IpAddr};
+use x402_gateway::signing::SignatureVersion;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum NetworkConfig {
     Evm {
@@ -14,26 +19,938 @@ pub enum NetworkConfig {
     },
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// How a paid WebSocket session on a protected route is billed.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "billing", rename_all = "snake_case")]
+pub enum WebSocketBilling {
+    /// One payment at the handshake covers the whole connection.
+    #[default]
+    PerConnection,
+    /// One payment at the handshake covers this many client messages. The
+    /// gateway then closes the session and the client reconnects with a new payment.
+    PerMessage { messages_per_payment: u64 },
+}
+
+/// What a protected route does with methods that have no price of their own.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlistedMethods {
+    /// Proxy them without payment.
+    Free,
+    /// Answer `405 Method Not Allowed`.
+    Reject,
+}
+
+/// Adds to a route's price based on a value in the JSON request body.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BodyPriceRule {
+    /// Add the price listed for the value at `json_path`, or `default`.
+    Match {
+        json_path: ConfigJsonPath,
+        prices: BTreeMap<String, u64>,
+        #[serde(default)]
+        default: u64,
+    },
+    /// Add `usdc_per_unit` for every unit of the number at `json_path`, or
+    /// for `default_units` when the body does not set it.
+    PerUnit {
+        json_path: ConfigJsonPath,
+        usdc_per_unit: u64,
+        #[serde(default)]
+        default_units: u64,
+        /// Units charged for a negative number, which upstreams such as
+        /// Ollama read as no limit. Without it such requests get `400`.
+        #[serde(default)]
+        max_units: Option<u64>,
+    },
+}
+
+impl BodyPriceRule {
+    pub fn json_path(&self) -> &ConfigJsonPath {
+        match self {
+            Self::Match { json_path, .. } | Self::PerUnit { json_path, .. } => json_path,
+        }
+    }
+}
+
+/// Where the usage of a metered response is read from.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum UsageSource {
+    /// A number in the JSON response body, e.g. Ollama's `$.eval_count`. For
+    /// NDJSON and SSE bodies the last event that has it wins.
+    JsonPath { json_path: ConfigJsonPath },
+    /// A number in an upstream response header.
+    Header { name: String },
+    /// The size of the response body in bytes.
+    Bytes,
+}
+
+/// Usage-based settlement with the x402 `upto` scheme: the client authorizes
+/// the route price as a maximum and is charged for what the response used.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Metering {
+    pub usdc_per_unit: u64,
+    pub usage: UsageSource,
+}
+
+/// Time-limited access after one payment: a settled payment on the route is
+/// answered with a pass signed by the gateway, and requests carrying a valid
+/// pass are proxied without paying until it expires.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct AccessPassPolicy {
+    pub duration_secs: u64,
+    /// Paths of protected routes the pass opens. Defaults to this route.
+    #[serde(default)]
+    pub scope: Vec<String>,
+}
+
+/// A response status that counts as success: a code such as `200`, or a
+/// class such as `"2xx"`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StatusPattern {
+    Code(u16),
+    Class(String),
+}
+
+impl StatusPattern {
+    /// The first digit of a class pattern, if it is well formed.
+    fn class_digit(class: &str) -> Option<u16> {
+        match class.as_bytes() {
+            [digit @ b'1'..=b'5', b'x' | b'X', b'x' | b'X'] => Some((digit - b'0') as u16),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, status: StatusCode) -> bool {
+        match self {
+            Self::Code(code) => status.as_u16() == *code,
+            Self::Class(class) => Self::class_digit(class) == Some(status.as_u16() / 100),
+        }
+    }
+}
+
+/// When a verified payment is settled: only after the upstream answered with
+/// a success status. Any other response is returned and nothing is charged.
+///
+/// A `101` WebSocket handshake always counts as a success, since the session
+/// it opens cannot be charged once it is running.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct SettlementPolicy {
+    #[serde(default = "default_success_statuses")]
+    pub success_statuses: Vec<StatusPattern>,
+}
+
+fn default_success_statuses() -> Vec<StatusPattern> {
+    vec![StatusPattern::Class("2xx".to_string())]
+}
+
+impl Default for SettlementPolicy {
+    fn default() -> Self {
+        Self {
+            success_statuses: default_success_statuses(),
+        }
+    }
+}
+
+impl SettlementPolicy {
+    pub fn is_success(&self, status: StatusCode) -> bool {
+        status == StatusCode::SWITCHING_PROTOCOLS
+            || self.success_statuses.iter().any(|p| p.matches(status))
+    }
+}
+
+/// How responses are signed.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Signing {
+    /// Version used when the client does not ask for one, by its
+    /// `X-Signature-Version` name.
+    #[serde(default = "default_signature_version")]
+    pub version: String,
+    /// Upstream response headers covered by v4 signatures, in signing order.
+    #[serde(default = "default_signed_headers")]
+    pub signed_headers: Vec<String>,
+}
+
+fn default_signature_version() -> String {
+    SignatureVersion::V4.as_str().to_string()
+}
+
+fn default_signed_headers() -> Vec<String> {
+    vec!["content-type".to_string(), "content-encoding".to_string()]
+}
+
+impl Default for Signing {
+    fn default() -> Self {
+        Self {
+            version: default_signature_version(),
+            signed_headers: default_signed_headers(),
+        }
+    }
+}
+
+impl Signing {
+    /// The configured default version; v4 if the name is unknown, which
+    /// validation rejects.
+    pub fn default_version(&self) -> SignatureVersion {
+        SignatureVersion::from_name(&self.version).unwrap_or(SignatureVersion::V4)
+    }
+}
+
+/// Prepaid credit: payments to `top_up_path` are credited to the payer, and
+/// requests to protected routes signed by the payer draw on that balance
+/// instead of carrying a payment each.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Credits {
+    /// SQLite file holding balances and usage.
+    pub db_path: String,
+    /// Paid route, answered by the gateway, that sells `top_up_usdc_amount`
+    /// of credit.
+    #[serde(default = "default_top_up_path")]
+    pub top_up_path: String,
+    pub top_up_usdc_amount: u64,
+    /// Route where a payer reads their balance and usage with a signed
+    /// request.
+    #[serde(default = "default_account_path")]
+    pub account_path: String,
+}
+
+fn default_top_up_path() -> String {
+    "/credits/top-up".to_string()
+}
+
+fn default_account_path() -> String {
+    "/credits".to_string()
+}
+
+/// Where a route is proxied. Every field is optional: by default requests go
+/// to the fallback upstream with their path unchanged.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamTarget {
+    /// Name of an entry in `upstreams`.
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Path prefix removed before forwarding, e.g. `/search`.
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    /// Prefix put in front of the (stripped) path, e.g. `/v1`.
+    #[serde(default)]
+    pub rewrite_prefix: Option<String>,
+}
+
+/// How an upstream picks one of its backends for a request.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest requests in flight.
+    LeastConnections,
+    /// Backends get a share of requests inversely proportional to their
+    /// recent response latency.
+    LatencyWeighted,
+}
+
+/// Active health probes sent to every backend of an upstream.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Probed with `GET`; any status below 400 counts as healthy.
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    #[serde(default = "default_health_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_timeout_secs() -> u64 {
+    2
+}
+
+/// Passive health: a backend that fails `max_failures` requests in a row is
+/// taken out of rotation for `ejection_secs`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct PassiveHealth {
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_ejection_secs")]
+    pub ejection_secs: u64,
+}
+
+fn default_max_failures() -> u32 {
+    3
+}
+
+fn default_ejection_secs() -> u64 {
+    30
+}
+
+/// A backend the gateway proxies to, possibly as a pool of replicas.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Upstream {
+    /// A single backend.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Replicas of the same backend.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub passive_health: Option<PassiveHealth>,
+}
+
+impl Upstream {
+    /// Base URLs of every backend, `url` first.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.url.iter().chain(&self.urls).map(String::as_str)
+    }
+}
+
+/// A route proxied without payment to a specific upstream.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct FreeRoute {
+    pub path: String,
+    #[serde(flatten)]
+    pub target: UpstreamTarget,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct ProtectedRoute {
     pub path: String,
-    pub usdc_amount: u64,
+    #[serde(flatten)]
+    pub target: UpstreamTarget,
+    /// Price for every method that is not listed in `methods`.
+    #[serde(default)]
+    pub usdc_amount: Option<u64>,
+    /// Price per HTTP method, e.g. `{ "GET": 1000, "POST": 5000 }`.
+    #[serde(default)]
+    pub methods: BTreeMap<String, u64>,
+    /// Handling of methods missing from `methods` when there is no
+    /// `usdc_amount`. Defaults to free.
+    #[serde(default)]
+    pub unlisted_methods: Option<UnlistedMethods>,
+    /// Rules that add to the method price based on the JSON request body.
+    #[serde(default)]
+    pub body_pricing: Vec<BodyPriceRule>,
+    /// Settle for measured usage instead of the full price.
+    #[serde(default)]
+    pub metering: Option<Metering>,
+    /// Overrides the top-level `settlement` policy for this route.
+    #[serde(default)]
+    pub settlement: Option<SettlementPolicy>,
+    #[serde(default)]
+    pub websocket: WebSocketBilling,
+    /// Sell time-limited access instead of single requests.
+    #[serde(default)]
+    pub access_pass: Option<AccessPassPolicy>,
+}
+
+impl ProtectedRoute {
+    /// The settlement policy in effect for this route.
+    pub fn settlement<'a>(&'a self, config: &'a Config) -> &'a SettlementPolicy {
+        self.settlement.as_ref().unwrap_or(&config.settlement)
+    }
+
+    /// Route paths opened by a pass bought on this route.
+    pub fn pass_scope(&self) -> Vec<String> {
+        match &self.access_pass {
+            Some(policy) if !policy.scope.is_empty() => policy.scope.clone(),
+            _ => vec![self.path.clone()],
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct Config {
     pub gateway_port: u16,
+    /// Port of the admin server that serves `/metrics`; off when unset.
+    #[serde(default)]
+    pub admin_port: Option<u16>,
+    /// Address the admin server binds to; `127.0.0.1` when unset.
+    #[serde(default)]
+    pub admin_bind_address: Option<IpAddr>,
+    /// SQLite file that settled payments are written to; off when unset.
+    #[serde(default)]
+    pub ledger_path: Option<String>,
+    /// Prepaid credit; off when unset.
+    #[serde(default)]
+    pub credits: Option<Credits>,
     pub facilitator_url: String,
-    pub target_api_url: String,
+    /// Single backend for every request; use `upstreams` for several.
+    #[serde(default)]
+    pub target_api_url: Option<String>,
+    /// Named backends that routes point at.
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, Upstream>,
+    /// Upstream for requests that match no route naming one. Required with
+    /// `upstreams`.
+    #[serde(default)]
+    pub fallback_upstream: Option<String>,
     pub networks: Vec<NetworkConfig>,
     pub protected_routes: Vec<ProtectedRoute>,
+    #[serde(default)]
+    pub free_routes: Vec<FreeRoute>,
+    #[serde(default)]
+    pub settlement: SettlementPolicy,
+    #[serde(default)]
+    pub signing: Signing,
+}
+
+/// One problem found while validating a config, located by its JSON path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
 }
 
-pub fn load_config() -> Config {
-    let config_path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| "config.json".to_string());
-    let config_str = fs::read_to_string(&config_path)
-        .unwrap_or_else(|_| panic!("Failed to read config file: {}", config_path));
-    serde_json::from_str(&config_str).expect("Failed to parse config.json")
+impl ConfigError {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// The named upstream, or the fallback upstream for `None`. A
+    /// `target_api_url` is returned as a one-backend upstream named `default`.
+    pub fn upstream(&self, name: Option<&str>) -> Option<(&str, Cow<'_, Upstream>)> {
+        match name.or(self.fallback_upstream.as_deref()) {
+            Some(name) => self
+                .upstreams
+                .get_key_value(name)
+                .map(|(name, upstream)| (name.as_str(), Cow::Borrowed(upstream))),
+            None => self.target_api_url.as_ref().map(|url| {
+                let upstream = Upstream {
+                    url: Some(url.clone()),
+                    ..Default::default()
+                };
+                ("default", Cow::Owned(upstream))
+            }),
+        }
+    }
+
+    /// Check everything the router build relies on, so a bad file is rejected
+    /// up front instead of panicking once the gateway is running.
+    ///
+    /// Every problem is collected rather than stopping at the first one.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        if self.networks.is_empty() {
+            errors.push(ConfigError::new(
+                "$.networks",
+                "at least one network must be configured",
+            ));
+        }
+        for (i, net_config) in self.networks.iter().enumerate() {
+            let path = format!("$.networks[{}]", i);
+            match net_config {
+                NetworkConfig::Evm {
+                    network,
+                    payment_address,
+                } => {
+                    if pricing::get_evm_usdc(network).is_none() {
+                        errors.push(ConfigError::new(
+                            format!("{}.network", path),
+                            format!("unsupported EVM network: {}", network),
+                        ));
+                    }
+                    if let Err(e) = pricing::parse_evm_address(payment_address) {
+                        errors.push(ConfigError::new(format!("{}.payment_address", path), e));
+                    }
+                }
+                NetworkConfig::Solana {
+                    network,
+                    payment_address,
+                } => {
+                    if pricing::get_solana_usdc(network).is_none() {
+                        errors.push(ConfigError::new(
+                            format!("{}.network", path),
+                            format!("unsupported Solana network: {}", network),
+                        ));
+                    }
+                    if let Err(e) = pricing::parse_solana_address(payment_address) {
+                        errors.push(ConfigError::new(format!("{}.payment_address", path), e));
+                    }
+                }
+            }
+        }
+
+        if self.admin_port == Some(self.gateway_port) {
+            errors.push(ConfigError::new(
+                "$.admin_port",
+                "must differ from gateway_port so /metrics is not served publicly",
+            ));
+        }
+
+        if let Some(credits) = &self.credits {
+            errors.extend(self.validate_credits(credits));
+        }
+        errors.extend(self.validate_upstreams());
+        errors.extend(validate_settlement(&self.settlement, "$.settlement"));
+        errors.extend(validate_signing(&self.signing));
+
+        // Protected and free routes share one routing table
+        let mut patterns: Vec<(String, RoutePattern)> = Vec::new();
+        let mut check_path = |route_path: &str, path: &str, errors: &mut Vec<ConfigError>| {
+            match RoutePattern::parse(route_path) {
+                Ok(pattern) => {
+                    if let Some((other, _)) =
+                        patterns.iter().find(|(_, p)| p.is_equivalent(&pattern))
+                    {
+                        errors.push(ConfigError::new(
+                            format!("{}.path", path),
+                            format!(
+                                "duplicate route path: {} matches the same paths as {}",
+                                route_path, other
+                            ),
+                        ));
+                    }
+                    patterns.push((path.to_string(), pattern));
+                }
+                Err(e) => errors.push(ConfigError::new(format!("{}.path", path), e)),
+            }
+        };
+
+        for (i, route) in self.protected_routes.iter().enumerate() {
+            let path = format!("$.protected_routes[{}]", i);
+            check_path(&route.path, &path, &mut errors);
+            errors.extend(self.validate_target(&route.target, &path));
+            if route.usdc_amount.is_none() && route.methods.is_empty() {
+                errors.push(ConfigError::new(
+                    path.clone(),
+                    "set usdc_amount, methods, or both",
+                ));
+            }
+            if route.usdc_amount.is_some() && route.unlisted_methods.is_some() {
+                errors.push(ConfigError::new(
+                    format!("{}.unlisted_methods", path),
+                    "has no effect when usdc_amount is set",
+                ));
+            }
+            let mut methods: Vec<Method> = Vec::new();
+            for name in route.methods.keys() {
+                match Method::from_bytes(name.to_ascii_uppercase().as_bytes()) {
+                    Ok(method) if methods.contains(&method) => errors.push(ConfigError::new(
+                        format!("{}.methods.{}", path, name),
+                        format!("method {} is listed twice", method),
+                    )),
+                    Ok(method) => methods.push(method),
+                    Err(_) => errors.push(ConfigError::new(
+                        format!("{}.methods.{}", path, name),
+                        format!("invalid HTTP method: {}", name),
+                    )),
+                }
+            }
+            for (j, rule) in route.body_pricing.iter().enumerate() {
+                if let Err(e) = rule.json_path().parsed() {
+                    errors.push(ConfigError::new(
+                        format!("{}.body_pricing[{}].json_path", path, j),
+                        e,
+                    ));
+                }
+            }
+            if let Some(metering) = &route.metering {
+                errors.extend(self.validate_metering(metering, &format!("{}.metering", path)));
+            }
+            if let Some(settlement) = &route.settlement {
+                errors.extend(validate_settlement(
+                    settlement,
+                    &format!("{}.settlement", path),
+                ));
+            }
+            if let WebSocketBilling::PerMessage {
+                messages_per_payment: 0,
+            } = route.websocket
+            {
+                errors.push(ConfigError::new(
+                    format!("{}.websocket.messages_per_payment", path),
+                    "must be at least 1",
+                ));
+            }
+            if let Some(policy) = &route.access_pass {
+                errors.extend(self.validate_access_pass(
+                    route,
+                    policy,
+                    &format!("{}.access_pass", path),
+                ));
+            }
+        }
+
+        for (i, route) in self.free_routes.iter().enumerate() {
+            let path = format!("$.free_routes[{}]", i);
+            check_path(&route.path, &path, &mut errors);
+            errors.extend(self.validate_target(&route.target, &path));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate_upstreams(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        match (&self.target_api_url, self.upstreams.is_empty()) {
+            (None, true) => errors.push(ConfigError::new("$", "set target_api_url or upstreams")),
+            (Some(_), false) => errors.push(ConfigError::new(
+                "$.target_api_url",
+                "cannot be combined with upstreams; add it to upstreams instead",
+            )),
+            (Some(url), true) => {
+                if let Err(e) = check_upstream_url(url) {
+                    errors.push(ConfigError::new("$.target_api_url", e));
+                }
+            }
+            (None, false) => {}
+        }
+        for (name, upstream) in &self.upstreams {
+            errors.extend(validate_upstream(
+                upstream,
+                &format!("$.upstreams.{}", name),
+            ));
+        }
+        match &self.fallback_upstream {
+            None if !self.upstreams.is_empty() => errors.push(ConfigError::new(
+                "$.fallback_upstream",
+                "required with upstreams: name the upstream for requests no route sends elsewhere",
+            )),
+            Some(name) if !self.upstreams.contains_key(name) => errors.push(ConfigError::new(
+                "$.fallback_upstream",
+                format!("unknown upstream: {}", name),
+            )),
+            _ => {}
+        }
+        errors
+    }
+
+    fn validate_target(&self, target: &UpstreamTarget, path: &str) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if let Some(name) = &target.upstream
+            && !self.upstreams.contains_key(name)
+        {
+            errors.push(ConfigError::new(
+                format!("{}.upstream", path),
+                format!("unknown upstream: {}", name),
+            ));
+        }
+        for (field, prefix) in [
+            ("strip_prefix", &target.strip_prefix),
+            ("rewrite_prefix", &target.rewrite_prefix),
+        ] {
+            if let Some(prefix) = prefix
+                && (!prefix.starts_with('/') || prefix.ends_with('/'))
+            {
+                errors.push(ConfigError::new(
+                    format!("{}.{}", path, field),
+                    format!("{} must start with '/' and not end with '/'", prefix),
+                ));
+            }
+        }
+        errors
+    }
+
+    fn validate_credits(&self, credits: &Credits) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if !self.has_evm_network() {
+            errors.push(ConfigError::new(
+                "$.credits",
+                "credit is drawn with EVM signatures, which needs an EVM network to top up on",
+            ));
+        }
+        if credits.top_up_usdc_amount == 0 {
+            errors.push(ConfigError::new(
+                "$.credits.top_up_usdc_amount",
+                "must be at least 1",
+            ));
+        }
+        for (field, path) in [
+            ("top_up_path", &credits.top_up_path),
+            ("account_path", &credits.account_path),
+        ] {
+            let field = format!("$.credits.{}", field);
+            if !path.starts_with('/') || path.contains(['*', '{', '}']) {
+                errors.push(ConfigError::new(
+                    field,
+                    format!("{} must be a literal path starting with '/'", path),
+                ));
+                continue;
+            }
+            if normalize_path(path) != *path {
+                errors.push(ConfigError::new(
+                    field.clone(),
+                    format!("{} must be written as {}", path, normalize_path(path)),
+                ));
+            } else if path == "/.well-known/oyster" || path.starts_with("/.well-known/oyster/") {
+                errors.push(ConfigError::new(
+                    field.clone(),
+                    format!("{} is reserved for the gateway's own routes", path),
+                ));
+            }
+            // The credit routes take precedence, so they would hide a
+            // configured route
+            let configured = self.protected_routes.iter().map(|route| &route.path);
+            let free = self.free_routes.iter().map(|route| &route.path);
+            for route in configured.chain(free) {
+                if RoutePattern::parse(route).is_ok_and(|pattern| pattern.matches(path)) {
+                    errors.push(ConfigError::new(
+                        field.clone(),
+                        format!("{} is also matched by route {}", path, route),
+                    ));
+                }
+            }
+        }
+        if credits.top_up_path == credits.account_path {
+            errors.push(ConfigError::new(
+                "$.credits.account_path",
+                "must differ from top_up_path",
+            ));
+        }
+        errors
+    }
+
+    fn validate_access_pass(
+        &self,
+        route: &ProtectedRoute,
+        policy: &AccessPassPolicy,
+        path: &str,
+    ) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if policy.duration_secs == 0 {
+            errors.push(ConfigError::new(
+                format!("{}.duration_secs", path),
+                "must be at least 1",
+            ));
+        }
+        if route.metering.is_some() {
+            errors.push(ConfigError::new(
+                path,
+                "cannot be combined with metering, which charges per request",
+            ));
+        }
+        for (i, scope) in policy.scope.iter().enumerate() {
+            match self.protected_routes.iter().find(|r| &r.path == scope) {
+                None => errors.push(ConfigError::new(
+                    format!("{}.scope[{}]", path, i),
+                    format!("not the path of a protected route: {}", scope),
+                )),
+                // A metered route charges each request for its usage, so a
+                // pass must not open it; the route's own path is reported above
+                Some(scoped) if scoped.metering.is_some() && scoped.path != route.path => errors
+                    .push(ConfigError::new(
+                        format!("{}.scope[{}]", path, i),
+                        format!("{} is metered, which charges per request", scope),
+                    )),
+                Some(_) => {}
+            }
+        }
+        errors
+    }
+
+    fn has_evm_network(&self) -> bool {
+        self.networks
+            .iter()
+            .any(|net| matches!(net, NetworkConfig::Evm { .. }))
+    }
+
+    fn validate_metering(&self, metering: &Metering, path: &str) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if !self.has_evm_network() {
+            errors.push(ConfigError::new(
+                path,
+                "metered routes settle with the upto scheme, which needs an EVM network",
+            ));
+        }
+        if metering.usdc_per_unit == 0 {
+            errors.push(ConfigError::new(
+                format!("{}.usdc_per_unit", path),
+                "must be at least 1",
+            ));
+        }
+        match &metering.usage {
+            UsageSource::JsonPath { json_path } => {
+                if let Err(e) = json_path.parsed() {
+                    errors.push(ConfigError::new(format!("{}.usage.json_path", path), e));
+                }
+            }
+            UsageSource::Header { name } => {
+                if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    errors.push(ConfigError::new(
+                        format!("{}.usage.name", path),
+                        format!("invalid header name: {}", name),
+                    ));
+                }
+            }
+            UsageSource::Bytes => {}
+        }
+        errors
+    }
+}
+
+fn validate_upstream(upstream: &Upstream, path: &str) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    match (&upstream.url, upstream.urls.is_empty()) {
+        (None, true) => errors.push(ConfigError::new(path, "set url or urls")),
+        (Some(_), false) => errors.push(ConfigError::new(path, "set url or urls, not both")),
+        _ => {}
+    }
+    if let Some(url) = &upstream.url
+        && let Err(e) = check_upstream_url(url)
+    {
+        errors.push(ConfigError::new(format!("{}.url", path), e));
+    }
+    for (i, url) in upstream.urls.iter().enumerate() {
+        if let Err(e) = check_upstream_url(url) {
+            errors.push(ConfigError::new(format!("{}.urls[{}]", path, i), e));
+        }
+    }
+    if let Some(check) = &upstream.health_check {
+        if !check.path.starts_with('/') {
+            errors.push(ConfigError::new(
+                format!("{}.health_check.path", path),
+                "must start with '/'",
+            ));
+        }
+        for (field, value) in [
+            ("interval_secs", check.interval_secs),
+            ("timeout_secs", check.timeout_secs),
+        ] {
+            if value == 0 {
+                errors.push(ConfigError::new(
+                    format!("{}.health_check.{}", path, field),
+                    "must be at least 1",
+                ));
+            }
+        }
+    }
+    if let Some(passive) = &upstream.passive_health {
+        for (field, value) in [
+            ("max_failures", u64::from(passive.max_failures)),
+            ("ejection_secs", passive.ejection_secs),
+        ] {
+            if value == 0 {
+                errors.push(ConfigError::new(
+                    format!("{}.passive_health.{}", path, field),
+                    "must be at least 1",
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn check_upstream_url(url: &str) -> Result<(), String> {
+    match url::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        Ok(parsed) => Err(format!(
+            "unsupported URL scheme {}: use http or https",
+            parsed.scheme()
+        )),
+        Err(e) => Err(format!("invalid URL {}: {}", url, e)),
+    }
+}
+
+fn validate_settlement(policy: &SettlementPolicy, path: &str) -> Vec<ConfigError> {
+    let path = format!("{}.success_statuses", path);
+    if policy.success_statuses.is_empty() {
+        return vec![ConfigError::new(path, "must list at least one status")];
+    }
+    let mut errors = Vec::new();
+    for (i, pattern) in policy.success_statuses.iter().enumerate() {
+        let invalid = match pattern {
+            StatusPattern::Code(code) if !(100..=599).contains(code) => Some(code.to_string()),
+            StatusPattern::Class(class) if StatusPattern::class_digit(class).is_none() => {
+                Some(format!("{:?}", class))
+            }
+            _ => None,
+        };
+        if let Some(status) = invalid {
+            errors.push(ConfigError::new(
+                format!("{}[{}]", path, i),
+                format!(
+                    "invalid status {}: expected a code from 100 to 599 or a class from \"1xx\" to \"5xx\"",
+                    status
+                ),
+            ));
+        }
+    }
+    errors
+}
+
+fn validate_signing(signing: &Signing) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    if SignatureVersion::from_name(&signing.version).is_none() {
+        let names: Vec<&str> = SignatureVersion::ALL.iter().map(|v| v.as_str()).collect();
+        errors.push(ConfigError::new(
+            "$.signing.version",
+            format!(
+                "unknown signature version {:?}: expected one of {}",
+                signing.version,
+                names.join(", ")
+            ),
+        ));
+    }
+    let mut seen = Vec::new();
+    for (i, name) in signing.signed_headers.iter().enumerate() {
+        let path = format!("$.signing.signed_headers[{}]", i);
+        let name = match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => {
+                errors.push(ConfigError::new(
+                    path,
+                    format!("invalid header name {:?}", name),
+                ));
+                continue;
+            }
+        };
+        if name == header::CONTENT_LENGTH || name == header::TRANSFER_ENCODING {
+            errors.push(ConfigError::new(
+                path,
+                format!("{} is rewritten by the gateway and cannot be signed", name),
+            ));
+        } else if seen.contains(&name) {
+            errors.push(ConfigError::new(path, format!("{} is listed twice", name)));
+        } else {
+            seen.push(name);
+        }
+    }
+    errors
+}
+
+pub fn config_path() -> String {
+    std::env::var("CONFIG_PATH").unwrap_or_else(|_| "config.json".to_string())
+}
+
+/// Read and parse a config file without panicking, at startup and for reloads.
+pub fn read_config(config_path: &str) -> Result<Config, String> {
+    let config_str = fs::read_to_string(config_path)
+        .map_err(|e| format!("Failed to read config file {}: {}", config_path, e))?;
+    serde_json::from_str(&config_str)
+        .map_err(|e| format!("Failed to parse config file {}: {}", config_path, e))
 }
 
 #[cfg(test)]
@@ -69,7 +986,10 @@ mod tests {
         let config: Config = serde_json::from_str(sample_config_json()).unwrap();
         assert_eq!(config.gateway_port, 3000);
         assert_eq!(config.facilitator_url, "https://example.com/facilitator");
-        assert_eq!(config.target_api_url, "http://127.0.0.1:3001");
+        assert_eq!(
+            config.target_api_url.as_deref(),
+            Some("http://127.0.0.1:3001")
+        );
         assert_eq!(config.networks.len(), 2);
         assert_eq!(config.protected_routes.len(), 2);
     }
@@ -112,11 +1032,29 @@ mod tests {
         let json = r#"{ "path": "/api/data", "usdc_amount": 2500 }"#;
         let route: ProtectedRoute = serde_json::from_str(json).unwrap();
         assert_eq!(route.path, "/api/data");
-        assert_eq!(route.usdc_amount, 2500);
+        assert_eq!(route.usdc_amount, Some(2500));
+        assert!(route.methods.is_empty());
+        assert_eq!(route.websocket, WebSocketBilling::PerConnection);
     }
 
     #[test]
-    fn test_load_config_from_file() {
+    fn test_deserialize_websocket_billing() {
+        let json = r#"{
+            "path": "/ws",
+            "usdc_amount": 100,
+            "websocket": { "billing": "per_message", "messages_per_payment": 50 }
+        }"#;
+        let route: ProtectedRoute = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            route.websocket,
+            WebSocketBilling::PerMessage {
+                messages_per_payment: 50
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_config_from_file() {
         let dir = tempfile::tempdir().unwrap();
         let file_path = dir.path().join("test_config.json");
         fs::write(&file_path, sample_config_json()).unwrap();
@@ -125,7 +1063,7 @@ mod tests {
         unsafe {
             std::env::set_var("CONFIG_PATH", file_path.to_str().unwrap());
         }
-        let config = load_config();
+        let config = read_config(&config_path()).unwrap();
         assert_eq!(config.gateway_port, 3000);
         assert_eq!(config.networks.len(), 2);
         unsafe {
@@ -134,15 +1072,468 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Failed to read config file")]
-    fn test_load_config_missing_file() {
-        // SAFETY: This test runs in isolation; mutating env vars is acceptable.
-        unsafe {
-            std::env::set_var("CONFIG_PATH", "/tmp/nonexistent_x402_config_12345.json");
-        }
-        let _config = load_config();
-        unsafe {
-            std::env::remove_var("CONFIG_PATH");
-        }
+    fn test_read_config_reports_parse_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("broken.json");
+        fs::write(&file_path, "{ not json").unwrap();
+
+        let err = read_config(file_path.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("Failed to parse config file"));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_admin_port() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        assert_eq!(config.admin_port, None);
+        config.admin_port = Some(9090);
+        assert!(config.validate().is_ok());
+
+        config.admin_port = Some(config.gateway_port);
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "$.admin_port");
+
+        assert_eq!(config.admin_bind_address, None);
+        let mut value: serde_json::Value = serde_json::from_str(sample_config_json()).unwrap();
+        value["admin_bind_address"] = "0.0.0.0".into();
+        let config: Config = serde_json::from_value(value).unwrap();
+        assert_eq!(config.admin_bind_address, Some([0, 0, 0, 0].into()));
+    }
+
+    #[test]
+    fn test_validate_credits() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.credits = Some(
+            serde_json::from_value(serde_json::json!({
+                "db_path": "credits.db",
+                "top_up_usdc_amount": 1000000
+            }))
+            .unwrap(),
+        );
+        assert!(config.validate().is_ok());
+        let credits = config.credits.as_mut().unwrap();
+        assert_eq!(credits.top_up_path, "/credits/top-up");
+        assert_eq!(credits.account_path, "/credits");
+
+        credits.top_up_usdc_amount = 0;
+        credits.top_up_path = "/credits/*".to_string();
+        credits.account_path = "/credits/*".to_string();
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "$.credits.top_up_usdc_amount",
+                "$.credits.top_up_path",
+                "$.credits.account_path",
+                "$.credits.account_path",
+            ]
+        );
+
+        let credits = config.credits.as_mut().unwrap();
+        credits.top_up_usdc_amount = 1;
+        credits.top_up_path = "/.well-known/oyster/signatures/top-up".to_string();
+        credits.account_path = "/premium".to_string();
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message.contains("reserved"));
+        assert!(errors[1].message.contains("also matched by route /premium"));
+
+        let credits = config.credits.as_mut().unwrap();
+        credits.top_up_path = "/credits/../top-up".to_string();
+        credits.account_path = "/credits/".to_string();
+        let errors = config.validate().unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "/credits/../top-up must be written as /top-up",
+                "/credits/ must be written as /credits",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_access_pass() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[1].access_pass =
+            Some(serde_json::from_value(serde_json::json!({ "duration_secs": 3600 })).unwrap());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.protected_routes[1].pass_scope(), ["/premium"]);
+
+        config.protected_routes[1].access_pass = Some(AccessPassPolicy {
+            duration_secs: 0,
+            scope: vec!["/premium".to_string(), "/premium/**".to_string()],
+        });
+        config.protected_routes[1].metering = Some(Metering {
+            usdc_per_unit: 1,
+            usage: UsageSource::Bytes,
+        });
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "$.protected_routes[1].access_pass.duration_secs",
+                "$.protected_routes[1].access_pass",
+                "$.protected_routes[1].access_pass.scope[1]",
+            ]
+        );
+
+        config.protected_routes[1].metering = None;
+        config.protected_routes[0].metering = Some(Metering {
+            usdc_per_unit: 1,
+            usage: UsageSource::Bytes,
+        });
+        config.protected_routes[1].access_pass = Some(AccessPassPolicy {
+            duration_secs: 60,
+            scope: vec!["/premium".to_string(), "/protected".to_string()],
+        });
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "$.protected_routes[1].access_pass.scope[1]");
+        assert_eq!(
+            errors[0].message,
+            "/protected is metered, which charges per request"
+        );
+    }
+
+    #[test]
+    fn test_validate_collects_all_errors() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.networks = vec![
+            NetworkConfig::Evm {
+                network: "unknown-chain".to_string(),
+                // Mixed case with a broken EIP-55 checksum
+                payment_address: "0xD232a8b0F63a555d054134f67b298ffE955f3BAf".to_string(),
+            },
+            NetworkConfig::Solana {
+                network: "solana-devnet".to_string(),
+                payment_address: "not-base58!".to_string(),
+            },
+        ];
+        config.protected_routes[1].path = "/protected".to_string();
+
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "$.networks[0].network",
+                "$.networks[0].payment_address",
+                "$.networks[1].payment_address",
+                "$.protected_routes[1].path",
+            ]
+        );
+        assert!(errors[1].message.contains("checksum"));
+        assert!(errors[3].to_string().contains("duplicate route path"));
+    }
+
+    #[test]
+    fn test_validate_route_patterns() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[0].path = "/v1/models/{id}/generate".to_string();
+        config.protected_routes[1].path = "/v1/models/*/generate".to_string();
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "$.protected_routes[1].path");
+        assert!(errors[0].message.contains("$.protected_routes[0]"));
+
+        config.protected_routes[1].path = "/api/**/generate".to_string();
+        let errors = config.validate().unwrap_err();
+        assert!(errors[0].message.contains("last segment"));
+    }
+
+    #[test]
+    fn test_validate_method_prices() {
+        let json = r#"{ "path": "/items", "methods": { "GET": 100, "post": 500 }, "unlisted_methods": "reject" }"#;
+        let route: ProtectedRoute = serde_json::from_str(json).unwrap();
+        assert_eq!(route.usdc_amount, None);
+        assert_eq!(route.unlisted_methods, Some(UnlistedMethods::Reject));
+
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[0] = route;
+        assert!(config.validate().is_ok());
+
+        config.protected_routes[0].usdc_amount = Some(1);
+        config.protected_routes[0]
+            .methods
+            .insert("get".to_string(), 10);
+        config.protected_routes[1].usdc_amount = None;
+        let rendered: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            rendered,
+            [
+                "$.protected_routes[0].unlisted_methods: has no effect when usdc_amount is set",
+                "$.protected_routes[0].methods.get: method GET is listed twice",
+                "$.protected_routes[1]: set usdc_amount, methods, or both",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deserialize_body_pricing() {
+        let json = r#"{
+            "path": "/api/chat",
+            "usdc_amount": 10,
+            "body_pricing": [
+                { "type": "match", "json_path": "$.model", "prices": { "qwen3:32b": 500 }, "default": 50 },
+                { "type": "per_unit", "json_path": "$.options.num_predict", "usdc_per_unit": 2 }
+            ]
+        }"#;
+        let route: ProtectedRoute = serde_json::from_str(json).unwrap();
+        assert_eq!(route.body_pricing.len(), 2);
+        assert_eq!(
+            route.body_pricing[1],
+            BodyPriceRule::PerUnit {
+                json_path: "$.options.num_predict".into(),
+                usdc_per_unit: 2,
+                default_units: 0,
+                max_units: None,
+            }
+        );
+
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[0] = route;
+        assert!(config.validate().is_ok());
+
+        config.protected_routes[0].body_pricing[0] = BodyPriceRule::Match {
+            json_path: "model".into(),
+            prices: BTreeMap::new(),
+            default: 0,
+        };
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors[0].path,
+            "$.protected_routes[0].body_pricing[0].json_path"
+        );
+    }
+
+    #[test]
+    fn test_validate_metering() {
+        let json = r#"{
+            "path": "/api/chat",
+            "usdc_amount": 5000,
+            "metering": { "usdc_per_unit": 2, "usage": { "source": "json_path", "json_path": "$.eval_count" } }
+        }"#;
+        let route: ProtectedRoute = serde_json::from_str(json).unwrap();
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[0] = route;
+        assert!(config.validate().is_ok());
+
+        config.protected_routes[0].metering = Some(Metering {
+            usdc_per_unit: 0,
+            usage: UsageSource::Header {
+                name: "bad header".to_string(),
+            },
+        });
+        config
+            .networks
+            .retain(|net| matches!(net, NetworkConfig::Solana { .. }));
+        let paths: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "$.protected_routes[0].metering",
+                "$.protected_routes[0].metering.usdc_per_unit",
+                "$.protected_routes[0].metering.usage.name",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settlement_policy() {
+        let policy = SettlementPolicy::default();
+        assert!(policy.is_success(StatusCode::OK));
+        assert!(policy.is_success(StatusCode::SWITCHING_PROTOCOLS));
+        assert!(!policy.is_success(StatusCode::NOT_MODIFIED));
+        assert!(!policy.is_success(StatusCode::BAD_GATEWAY));
+
+        let json = r#"{ "path": "/search", "usdc_amount": 10, "settlement": { "success_statuses": ["2XX", 404] } }"#;
+        let route: ProtectedRoute = serde_json::from_str(json).unwrap();
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[0] = route;
+        let policy = config.protected_routes[0].settlement(&config);
+        assert!(policy.is_success(StatusCode::NOT_FOUND));
+        // An upgrade is settled whatever the policy lists
+        assert!(policy.is_success(StatusCode::SWITCHING_PROTOCOLS));
+        assert!(!policy.is_success(StatusCode::CONTINUE));
+        assert_eq!(
+            config.protected_routes[1].settlement(&config),
+            &SettlementPolicy::default()
+        );
+        assert!(config.validate().is_ok());
+
+        config.settlement.success_statuses = vec![];
+        config.protected_routes[0].settlement = Some(SettlementPolicy {
+            success_statuses: vec![
+                StatusPattern::Code(600),
+                StatusPattern::Class("2x".to_string()),
+            ],
+        });
+        let rendered: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            rendered,
+            [
+                "$.settlement.success_statuses: must list at least one status",
+                "$.protected_routes[0].settlement.success_statuses[0]: invalid status 600: expected a code from 100 to 599 or a class from \"1xx\" to \"5xx\"",
+                "$.protected_routes[0].settlement.success_statuses[1]: invalid status \"2x\": expected a code from 100 to 599 or a class from \"1xx\" to \"5xx\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_signed_headers() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        assert_eq!(
+            config.signing.signed_headers,
+            ["content-type", "content-encoding"]
+        );
+
+        assert_eq!(config.signing.default_version(), SignatureVersion::V4);
+        config.signing.version = "oyster-signature-v2".to_string();
+        assert_eq!(config.signing.default_version(), SignatureVersion::V2);
+        config.signing.version = "v5".to_string();
+        config.signing.signed_headers = [
+            "Content-Type",
+            "x-request-id",
+            "content-type",
+            "bad header",
+            "Content-Length",
+        ]
+        .map(String::from)
+        .to_vec();
+        let rendered: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            rendered,
+            [
+                "$.signing.version: unknown signature version \"v5\": expected one of oyster-signature-v2, oyster-signature-v3, oyster-signature-v4",
+                "$.signing.signed_headers[2]: content-type is listed twice",
+                "$.signing.signed_headers[3]: invalid header name \"bad header\"",
+                "$.signing.signed_headers[4]: content-length is rewritten by the gateway and cannot be signed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_upstreams() {
+        let json = r#"{
+            "gateway_port": 3000,
+            "facilitator_url": "https://example.com/facilitator",
+            "upstreams": {
+                "ollama": { "url": "http://127.0.0.1:11434" },
+                "search": { "url": "http://127.0.0.1:8080" }
+            },
+            "fallback_upstream": "ollama",
+            "networks": [
+                { "type": "evm", "network": "base-sepolia", "payment_address": "0xd232A8b0F63a555d054134f67b298ffE955f3BAf" }
+            ],
+            "protected_routes": [
+                { "path": "/search/paid/**", "usdc_amount": 10, "upstream": "search", "strip_prefix": "/search" }
+            ],
+            "free_routes": [
+                { "path": "/search/**", "upstream": "search", "strip_prefix": "/search", "rewrite_prefix": "/v1" }
+            ]
+        }"#;
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            config.protected_routes[0].target.upstream.as_deref(),
+            Some("search")
+        );
+        assert_eq!(
+            config.free_routes[0].target.rewrite_prefix.as_deref(),
+            Some("/v1")
+        );
+        let (name, fallback) = config.upstream(None).unwrap();
+        assert_eq!(name, "ollama");
+        assert_eq!(fallback.url.as_deref(), Some("http://127.0.0.1:11434"));
+        assert!(config.validate().is_ok());
+
+        config.target_api_url = Some("http://127.0.0.1:3001".to_string());
+        config.fallback_upstream = None;
+        config.upstreams.get_mut("search").unwrap().url = Some("ftp://127.0.0.1".to_string());
+        config.protected_routes[0].target.upstream = Some("embeddings".to_string());
+        config.free_routes[0].target.strip_prefix = Some("/search/".to_string());
+        config.free_routes.push(FreeRoute {
+            path: "/search/paid/**".to_string(),
+            ..Default::default()
+        });
+        let rendered: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            rendered,
+            [
+                "$.target_api_url: cannot be combined with upstreams; add it to upstreams instead",
+                "$.upstreams.search.url: unsupported URL scheme ftp: use http or https",
+                "$.fallback_upstream: required with upstreams: name the upstream for requests no route sends elsewhere",
+                "$.protected_routes[0].upstream: unknown upstream: embeddings",
+                "$.free_routes[0].strip_prefix: /search/ must start with '/' and not end with '/'",
+                "$.free_routes[1].path: duplicate route path: /search/paid/** matches the same paths as $.protected_routes[0]",
+            ]
+        );
+
+        let config = Config {
+            networks: config.networks,
+            ..Default::default()
+        };
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors[0].to_string(), "$: set target_api_url or upstreams");
+    }
+
+    #[test]
+    fn test_validate_route_paths_and_networks() {
+        let mut config: Config = serde_json::from_str(sample_config_json()).unwrap();
+        config.protected_routes[0].path = "protected".to_string();
+        config.protected_routes[1].websocket = WebSocketBilling::PerMessage {
+            messages_per_payment: 0,
+        };
+        config.networks.clear();
+
+        let errors = config.validate().unwrap_err();
+        let rendered: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            rendered,
+            [
+                "$.networks: at least one network must be configured",
+                "$.protected_routes[0].path: route path must start with '/': protected",
+                "$.protected_routes[1].websocket.messages_per_payment: must be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_config_missing_file() {
+        let err = read_config("/tmp/nonexistent_x402_config_12345.json").unwrap_err();
+        assert!(err.starts_with("Failed to read config file"));
     }
 }
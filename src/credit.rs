@@ -0,0 +1,217 @@
+use crate::signing::sign_digest;
+use crate::verify::{VerifyError, address, recover_digest};
+use alloy_primitives::Address;
+use http::{HeaderMap, HeaderValue, Method};
+use k256::ecdsa::SigningKey;
+use sha3::{Digest, Keccak256};
+
+/// Request headers of a request paid from prepaid credit.
+pub const CREDIT_PAYER_HEADER: &str = "X-Credit-Payer";
+pub const CREDIT_TIMESTAMP_HEADER: &str = "X-Credit-Timestamp";
+pub const CREDIT_NONCE_HEADER: &str = "X-Credit-Nonce";
+pub const CREDIT_SIGNATURE_HEADER: &str = "X-Credit-Signature";
+/// Response header with the payer's balance after the request was charged.
+pub const CREDIT_BALANCE_HEADER: &str = "X-Credit-Balance";
+
+const CREDIT_DOMAIN_V1: &[u8] = b"oyster-credit-v1\0";
+const EIP191_PREFIX_32: &[u8] = b"\x19Ethereum Signed Message:\n32";
+
+/// A request signed by a payer to draw on their prepaid credit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreditAuthorization {
+    pub payer: Address,
+    /// Unix seconds when the client signed.
+    pub timestamp: u64,
+    /// Random per request, so identical requests are told apart.
+    pub nonce: [u8; 16],
+    /// `r || s || v` as hex, like `X-Signature`.
+    pub signature: String,
+}
+
+impl CreditAuthorization {
+    /// Authorize a request with the payer's key.
+    pub fn sign(
+        payer_key: &SigningKey,
+        timestamp: u64,
+        nonce: [u8; 16],
+        method: &Method,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Self {
+        let mut authorization = Self {
+            payer: address(payer_key.verifying_key()),
+            timestamp,
+            nonce,
+            signature: String::new(),
+        };
+        authorization.signature = sign_digest(
+            payer_key,
+            authorization.digest(method, path_and_query, body),
+        );
+        authorization
+    }
+
+    /// Hash the payer signs, the EIP-191 personal message of
+    ///
+    /// ```text
+    /// keccak256("oyster-credit-v1\0" || payer || u64be(timestamp) || nonce ||
+    ///           u32be(len(method)) || method ||
+    ///           u32be(len(path_and_query)) || path_and_query ||
+    ///           u64be(len(body)) || body)
+    /// ```
+    ///
+    /// so wallets can sign the 32 bytes with `personal_sign`.
+    pub fn digest(&self, method: &Method, path_and_query: &str, body: &[u8]) -> Keccak256 {
+        let method = method.as_str().as_bytes();
+        let message = Keccak256::new()
+            .chain_update(CREDIT_DOMAIN_V1)
+            .chain_update(self.payer)
+            .chain_update(self.timestamp.to_be_bytes())
+            .chain_update(self.nonce)
+            .chain_update((method.len() as u32).to_be_bytes())
+            .chain_update(method)
+            .chain_update((path_and_query.len() as u32).to_be_bytes())
+            .chain_update(path_and_query)
+            .chain_update((body.len() as u64).to_be_bytes())
+            .chain_update(body)
+            .finalize();
+        Keccak256::new_with_prefix(EIP191_PREFIX_32).chain_update(message)
+    }
+
+    /// Check that the payer signed this request.
+    pub fn verify(
+        &self,
+        method: &Method,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Result<(), VerifyError> {
+        let key = recover_digest(self.digest(method, path_and_query, body), &self.signature)?;
+        if address(&key) != self.payer {
+            return Err(VerifyError::Message(format!(
+                "signed by {}, not {}",
+                address(&key),
+                self.payer
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read the authorization from request headers; `None` when the request
+    /// is not paid from credit.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, VerifyError> {
+        if !headers.contains_key(CREDIT_SIGNATURE_HEADER) {
+            return Ok(None);
+        }
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .ok_or_else(|| VerifyError::Message(format!("no {} header", name)))?
+                .to_str()
+                .map_err(|_| VerifyError::Message(format!("{} header is not text", name)))
+        };
+
+        let payer = header(CREDIT_PAYER_HEADER)?.parse().map_err(|_| {
+            VerifyError::Message(format!("{} is not an address", CREDIT_PAYER_HEADER))
+        })?;
+        let timestamp = header(CREDIT_TIMESTAMP_HEADER)?.parse().map_err(|_| {
+            VerifyError::Message(format!("{} is not Unix seconds", CREDIT_TIMESTAMP_HEADER))
+        })?;
+        let nonce = hex::decode(header(CREDIT_NONCE_HEADER)?)
+            .ok()
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or_else(|| {
+                VerifyError::Message(format!("expected 16-byte {}", CREDIT_NONCE_HEADER))
+            })?;
+        Ok(Some(Self {
+            payer,
+            timestamp,
+            nonce,
+            signature: header(CREDIT_SIGNATURE_HEADER)?.to_string(),
+        }))
+    }
+
+    /// Add the authorization to request headers.
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        let value =
+            |text: String| HeaderValue::from_str(&text).expect("hex and digits are header-safe");
+        headers.insert(CREDIT_PAYER_HEADER, value(self.payer.to_checksum(None)));
+        headers.insert(CREDIT_TIMESTAMP_HEADER, value(self.timestamp.to_string()));
+        headers.insert(CREDIT_NONCE_HEADER, value(hex::encode(self.nonce)));
+        headers.insert(CREDIT_SIGNATURE_HEADER, value(self.signature.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorization_round_trip() {
+        let key = SigningKey::from_bytes(&[2u8; 32].into()).unwrap();
+        let authorization = CreditAuthorization::sign(
+            &key,
+            1_700_000_000,
+            [9u8; 16],
+            &Method::POST,
+            "/api/chat?stream=false",
+            b"{}",
+        );
+        assert_eq!(authorization.payer, address(key.verifying_key()));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(CreditAuthorization::from_headers(&headers).unwrap(), None);
+        authorization.insert_headers(&mut headers);
+        let decoded = CreditAuthorization::from_headers(&headers)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded, authorization);
+        decoded
+            .verify(&Method::POST, "/api/chat?stream=false", b"{}")
+            .unwrap();
+
+        // The signature covers the request and the claimed payer
+        assert!(
+            decoded
+                .verify(&Method::POST, "/api/chat?stream=true", b"{}")
+                .is_err()
+        );
+        assert!(
+            decoded
+                .verify(&Method::POST, "/api/chat?stream=false", b"")
+                .is_err()
+        );
+        let mut other = decoded.clone();
+        other.payer = Address::repeat_byte(1);
+        assert!(
+            other
+                .verify(&Method::POST, "/api/chat?stream=false", b"{}")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_digest_is_eip191_personal_message() {
+        let authorization = CreditAuthorization {
+            payer: Address::repeat_byte(1),
+            timestamp: 1,
+            nonce: [0u8; 16],
+            signature: String::new(),
+        };
+        let digest = authorization.digest(&Method::GET, "/credits", b"");
+
+        let mut message = CREDIT_DOMAIN_V1.to_vec();
+        message.extend_from_slice(&[1u8; 20]);
+        message.extend_from_slice(&1u64.to_be_bytes());
+        message.extend_from_slice(&[0u8; 16]);
+        message.extend_from_slice(&3u32.to_be_bytes());
+        message.extend_from_slice(b"GET");
+        message.extend_from_slice(&8u32.to_be_bytes());
+        message.extend_from_slice(b"/credits");
+        message.extend_from_slice(&0u64.to_be_bytes());
+        let message = Keccak256::digest(message);
+        assert_eq!(
+            <[u8; 32]>::from(digest.finalize()),
+            alloy_primitives::eip191_hash_message(message).0
+        );
+    }
+}
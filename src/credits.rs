@@ -0,0 +1,538 @@
+use crate::body_pricing::MAX_PRICED_BODY_BYTES;
+use alloy_primitives::Address;
+use axum::{
+    Json, Router,
+    body::{Body, to_bytes},
+    extract::State,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tracing::error;
+use x402_gateway::credit::CreditAuthorization;
+use x402_gateway::signing::unix_now;
+
+/// Admin route listing every balance.
+pub const CREDITS_PATH: &str = "/credits";
+
+/// How far a credit authorization's timestamp may be from the gateway clock.
+pub const AUTHORIZATION_WINDOW_SECS: u64 = 300;
+
+/// Usage entries an account lookup returns, newest first.
+const USAGE_LIMIT: u32 = 100;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS balances (
+    payer TEXT PRIMARY KEY,
+    balance INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    payer TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    route TEXT NOT NULL,
+    reference TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS usage_payer ON usage (payer);
+CREATE TABLE IF NOT EXISTS nonces (
+    payer TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    PRIMARY KEY (payer, nonce)
+);
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageKind {
+    TopUp,
+    Draw,
+}
+
+impl UsageKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::TopUp => "top_up",
+            Self::Draw => "draw",
+        }
+    }
+}
+
+/// One change to a balance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub id: i64,
+    /// Unix seconds.
+    pub timestamp: u64,
+    pub kind: UsageKind,
+    /// USDC microunits added by a top-up or drawn by a request.
+    pub amount: u64,
+    pub route: String,
+    /// Settlement transaction of a top-up; the request hash of a draw, as a
+    /// payment receipt would name it.
+    pub reference: String,
+}
+
+/// A payer's balance with their most recent usage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Account {
+    pub payer: String,
+    pub balance: u64,
+    /// Newest first.
+    pub usage: Vec<Usage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PayerBalance {
+    pub payer: String,
+    pub balance: u64,
+}
+
+/// Why credit could not be drawn for a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DrawError {
+    /// The payer already used this nonce.
+    Replayed,
+    Insufficient {
+        balance: u64,
+    },
+    Store(String),
+}
+
+impl fmt::Display for DrawError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replayed => write!(f, "credit authorization was already used"),
+            Self::Insufficient { balance } => write!(f, "insufficient credit: {}", balance),
+            Self::Store(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Prepaid balances in an SQLite database. A request draws in two steps: its
+/// maximum price is reserved before the upstream is called, and what it did
+/// not use is returned afterwards, so concurrent requests cannot overdraw.
+pub struct CreditStore {
+    connection: Mutex<Connection>,
+}
+
+impl CreditStore {
+    /// Open the store at `path`, creating it if needed.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let connection = Connection::open(path)
+            .map_err(|e| format!("Failed to open credit store {}: {}", path, e))?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .and_then(|()| connection.execute_batch(SCHEMA))
+            .map_err(|e| format!("Failed to initialize credit store {}: {}", path, e))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Credit `amount`, paid in the `settlement` transaction, to `payer` and
+    /// return the new balance.
+    pub fn top_up(
+        &self,
+        payer: Address,
+        amount: u64,
+        route: &str,
+        settlement: &str,
+        now: u64,
+    ) -> Result<u64, String> {
+        let payer = payer.to_checksum(None);
+        let mut connection = self.connection.lock().expect("credit store lock poisoned");
+        let failed = |e: rusqlite::Error| format!("Failed to top up {}: {}", payer, e);
+        let transaction = connection.transaction().map_err(failed)?;
+        transaction
+            .execute(
+                "INSERT INTO balances (payer, balance) VALUES (?1, ?2) \
+                 ON CONFLICT (payer) DO UPDATE SET balance = balance + ?2",
+                rusqlite::params![payer, amount as i64],
+            )
+            .map_err(failed)?;
+        record_usage(
+            &transaction,
+            now,
+            &payer,
+            UsageKind::TopUp,
+            amount,
+            route,
+            settlement,
+        )
+        .map_err(failed)?;
+        let balance = balance(&transaction, &payer).map_err(failed)?;
+        transaction.commit().map_err(failed)?;
+        Ok(balance)
+    }
+
+    /// Take `amount` from the payer's balance for an authorized request,
+    /// using up its nonce.
+    pub fn reserve(
+        &self,
+        authorization: &CreditAuthorization,
+        amount: u64,
+        now: u64,
+    ) -> Result<(), DrawError> {
+        let payer = authorization.payer.to_checksum(None);
+        let mut connection = self.connection.lock().expect("credit store lock poisoned");
+        let failed = |e: rusqlite::Error| DrawError::Store(format!("Failed to draw credit: {}", e));
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(failed)?;
+
+        // Older nonces are outside the window and rejected anyway
+        transaction
+            .execute(
+                "DELETE FROM nonces WHERE timestamp < ?1",
+                [now.saturating_sub(AUTHORIZATION_WINDOW_SECS) as i64],
+            )
+            .map_err(failed)?;
+        let fresh = transaction
+            .execute(
+                "INSERT OR IGNORE INTO nonces (payer, nonce, timestamp) VALUES (?1, ?2, ?3)",
+                rusqlite::params![
+                    payer,
+                    hex::encode(authorization.nonce),
+                    authorization.timestamp as i64
+                ],
+            )
+            .map_err(failed)?;
+        if fresh == 0 {
+            return Err(DrawError::Replayed);
+        }
+        let drawn = transaction
+            .execute(
+                "UPDATE balances SET balance = balance - ?2 WHERE payer = ?1 AND balance >= ?2",
+                rusqlite::params![payer, amount as i64],
+            )
+            .map_err(failed)?;
+        if drawn == 0 {
+            // Rolled back with the nonce, so the request can be retried as is
+            return Err(DrawError::Insufficient {
+                balance: balance(&transaction, &payer).map_err(failed)?,
+            });
+        }
+        transaction.commit().map_err(failed)
+    }
+
+    /// Close a reservation of `reserved`: keep `charged` of it, recorded
+    /// against `route` and `request_id`, and return the rest. Returns the
+    /// new balance.
+    pub fn settle(
+        &self,
+        payer: Address,
+        reserved: u64,
+        charged: u64,
+        route: &str,
+        request_id: &str,
+        now: u64,
+    ) -> Result<u64, String> {
+        let payer = payer.to_checksum(None);
+        let mut connection = self.connection.lock().expect("credit store lock poisoned");
+        let failed = |e: rusqlite::Error| format!("Failed to settle credit of {}: {}", payer, e);
+        let transaction = connection.transaction().map_err(failed)?;
+        transaction
+            .execute(
+                "UPDATE balances SET balance = balance + ?2 WHERE payer = ?1",
+                rusqlite::params![payer, reserved.saturating_sub(charged) as i64],
+            )
+            .map_err(failed)?;
+        if charged > 0 {
+            record_usage(
+                &transaction,
+                now,
+                &payer,
+                UsageKind::Draw,
+                charged,
+                route,
+                request_id,
+            )
+            .map_err(failed)?;
+        }
+        let balance = balance(&transaction, &payer).map_err(failed)?;
+        transaction.commit().map_err(failed)?;
+        Ok(balance)
+    }
+
+    /// The payer's balance and recent usage; an unknown payer has none.
+    pub fn account(&self, payer: Address) -> Result<Account, String> {
+        let payer = payer.to_checksum(None);
+        let connection = self.connection.lock().expect("credit store lock poisoned");
+        let failed = |e: rusqlite::Error| format!("Failed to read credit of {}: {}", payer, e);
+        let mut statement = connection
+            .prepare(
+                "SELECT id, timestamp, kind, amount, route, reference FROM usage \
+                 WHERE payer = ?1 ORDER BY id DESC LIMIT ?2",
+            )
+            .map_err(failed)?;
+        let usage = statement
+            .query_map(rusqlite::params![payer, USAGE_LIMIT], |row| {
+                Ok(Usage {
+                    id: row.get(0)?,
+                    timestamp: row.get::<_, i64>(1)? as u64,
+                    kind: match row.get::<_, String>(2)?.as_str() {
+                        "top_up" => UsageKind::TopUp,
+                        _ => UsageKind::Draw,
+                    },
+                    amount: row.get::<_, i64>(3)? as u64,
+                    route: row.get(4)?,
+                    reference: row.get(5)?,
+                })
+            })
+            .and_then(Iterator::collect)
+            .map_err(failed)?;
+        Ok(Account {
+            balance: balance(&connection, &payer).map_err(failed)?,
+            payer,
+            usage,
+        })
+    }
+
+    /// Every payer with a balance record, largest balance first.
+    pub fn balances(&self) -> Result<Vec<PayerBalance>, String> {
+        let connection = self.connection.lock().expect("credit store lock poisoned");
+        let failed = |e: rusqlite::Error| format!("Failed to read balances: {}", e);
+        let mut statement = connection
+            .prepare("SELECT payer, balance FROM balances ORDER BY balance DESC, payer")
+            .map_err(failed)?;
+        statement
+            .query_map([], |row| {
+                Ok(PayerBalance {
+                    payer: row.get(0)?,
+                    balance: row.get::<_, i64>(1)? as u64,
+                })
+            })
+            .and_then(Iterator::collect)
+            .map_err(failed)
+    }
+}
+
+fn balance(connection: &Connection, payer: &str) -> rusqlite::Result<u64> {
+    let balance: Option<i64> = connection
+        .query_row(
+            "SELECT balance FROM balances WHERE payer = ?1",
+            [payer],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(balance.unwrap_or(0) as u64)
+}
+
+fn record_usage(
+    connection: &Connection,
+    now: u64,
+    payer: &str,
+    kind: UsageKind,
+    amount: u64,
+    route: &str,
+    reference: &str,
+) -> rusqlite::Result<usize> {
+    connection.execute(
+        "INSERT INTO usage (timestamp, payer, kind, amount, route, reference) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            now as i64,
+            payer,
+            kind.as_str(),
+            amount as i64,
+            route,
+            reference
+        ],
+    )
+}
+
+/// Check that `req` is signed by the payer it names, within
+/// [`AUTHORIZATION_WINDOW_SECS`]. The body is read to check the signature,
+/// so the request is handed back rebuilt.
+pub async fn authorize(
+    req: Request<Body>,
+) -> Result<(CreditAuthorization, Request<Body>), Response> {
+    let unauthorized = |message: String| (StatusCode::UNAUTHORIZED, message).into_response();
+    let authorization = match CreditAuthorization::from_headers(req.headers()) {
+        Ok(Some(authorization)) => authorization,
+        Ok(None) => {
+            return Err(unauthorized(
+                "sign the request with X-Credit-* headers".into(),
+            ));
+        }
+        Err(e) => return Err(unauthorized(e.to_string())),
+    };
+    if authorization.timestamp.abs_diff(unix_now()) > AUTHORIZATION_WINDOW_SECS {
+        return Err(unauthorized(
+            "credit authorization timestamp is too far from the gateway clock".into(),
+        ));
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = to_bytes(body, MAX_PRICED_BODY_BYTES)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+    let path_and_query = parts.uri.path_and_query().map_or("/", |pq| pq.as_str());
+    authorization
+        .verify(&parts.method, path_and_query, &body)
+        .map_err(|e| unauthorized(e.to_string()))?;
+    Ok((authorization, Request::from_parts(parts, Body::from(body))))
+}
+
+/// `GET` on `account_path`: the signing payer's [`Account`].
+pub async fn account(State(store): State<Arc<CreditStore>>, req: Request<Body>) -> Response {
+    let authorization = match authorize(req).await {
+        Ok((authorization, _)) => authorization,
+        Err(response) => return response,
+    };
+    match tokio::task::spawn_blocking(move || store.account(authorization.payer))
+        .await
+        .expect("credit lookup panicked")
+    {
+        Ok(account) => Json(account).into_response(),
+        Err(e) => {
+            error!(error = %e, "Credit lookup failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Admin API: `GET /credits` lists every balance.
+pub fn credits_router(store: Arc<CreditStore>) -> Router {
+    Router::new()
+        .route(CREDITS_PATH, get(list_balances))
+        .with_state(store)
+}
+
+async fn list_balances(
+    State(store): State<Arc<CreditStore>>,
+) -> Result<Json<Vec<PayerBalance>>, StatusCode> {
+    tokio::task::spawn_blocking(move || store.balances())
+        .await
+        .expect("credit lookup panicked")
+        .map(Json)
+        .map_err(|e| {
+            error!(error = %e, "Balance listing failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+    use x402_gateway::verify::address;
+
+    fn authorization(key: &SigningKey, nonce: u8, timestamp: u64) -> CreditAuthorization {
+        CreditAuthorization::sign(
+            key,
+            timestamp,
+            [nonce; 16],
+            &axum::http::Method::POST,
+            "/api/chat",
+            b"{}",
+        )
+    }
+
+    #[test]
+    fn test_reserve_and_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CreditStore::open(dir.path().join("credits.db").to_str().unwrap()).unwrap();
+        let key = SigningKey::from_bytes(&[2u8; 32].into()).unwrap();
+        let payer = address(key.verifying_key());
+        let now = 1_700_000_000;
+
+        assert_eq!(
+            store.reserve(&authorization(&key, 1, now), 10, now),
+            Err(DrawError::Insufficient { balance: 0 })
+        );
+        assert_eq!(
+            store.top_up(payer, 100, "/credits/top-up", "0x01", now),
+            Ok(100)
+        );
+
+        // The nonce of the refused request is still free
+        store
+            .reserve(&authorization(&key, 1, now), 60, now)
+            .unwrap();
+        assert_eq!(
+            store.reserve(&authorization(&key, 1, now), 10, now),
+            Err(DrawError::Replayed)
+        );
+        assert_eq!(
+            store.reserve(&authorization(&key, 2, now), 50, now),
+            Err(DrawError::Insufficient { balance: 40 })
+        );
+        assert_eq!(
+            store.settle(payer, 60, 25, "/api/**", "0xab", now + 1),
+            Ok(75)
+        );
+        // A request that is not charged leaves no usage
+        store
+            .reserve(&authorization(&key, 3, now), 75, now)
+            .unwrap();
+        assert_eq!(
+            store.settle(payer, 75, 0, "/api/**", "0xcd", now + 2),
+            Ok(75)
+        );
+
+        let account = store.account(payer).unwrap();
+        assert_eq!(account.payer, payer.to_checksum(None));
+        assert_eq!(account.balance, 75);
+        let usage: Vec<_> = account
+            .usage
+            .iter()
+            .map(|u| (u.kind, u.amount, u.reference.as_str()))
+            .collect();
+        assert_eq!(
+            usage,
+            [
+                (UsageKind::Draw, 25, "0xab"),
+                (UsageKind::TopUp, 100, "0x01")
+            ]
+        );
+        assert_eq!(
+            store.balances().unwrap(),
+            [PayerBalance {
+                payer: payer.to_checksum(None),
+                balance: 75
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let key = SigningKey::from_bytes(&[2u8; 32].into()).unwrap();
+        let signed = |timestamp: u64, body: &'static str| {
+            let authorization = CreditAuthorization::sign(
+                &key,
+                timestamp,
+                [7u8; 16],
+                &axum::http::Method::POST,
+                "/api/chat",
+                b"{}",
+            );
+            let mut req = Request::post("/api/chat").body(Body::from(body)).unwrap();
+            authorization.insert_headers(req.headers_mut());
+            req
+        };
+
+        let (authorization, req) = authorize(signed(unix_now(), "{}")).await.unwrap();
+        assert_eq!(authorization.payer, address(key.verifying_key()));
+        let body = to_bytes(req.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"{}");
+
+        for req in [
+            signed(unix_now(), "{\"x\":1}"),
+            signed(unix_now() - AUTHORIZATION_WINDOW_SECS - 10, "{}"),
+            Request::post("/api/chat").body(Body::from("{}")).unwrap(),
+        ] {
+            let response = authorize(req).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::signing::sign_digest;
+use http::{HeaderMap, HeaderValue, header};
+use k256::ecdsa::SigningKey;
+use sha3::{Digest, Keccak256};
+
+pub const EVENT_SIGNATURES_HEADER: &str = "X-Event-Signatures";
+pub const EVENT_SIGNATURE_VERSION: &str = "oyster-event-v1";
+
+const EVENT_DOMAIN_V1: &[u8] = b"oyster-event-v1\0";
+
+/// Longest event the signer holds back while waiting for its delimiter.
+pub const MAX_EVENT_BYTES: usize = 1024 * 1024;
+
+/// Streaming formats whose events are signed one at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    /// `text/event-stream`; events end with a blank line.
+    Sse,
+    /// Newline-delimited JSON; every line is an event.
+    Ndjson,
+}
+
+impl EventFormat {
+    pub fn from_content_type(value: &HeaderValue) -> Option<Self> {
+        let mime = value.to_str().ok()?.split(';').next()?.trim();
+        if mime.eq_ignore_ascii_case("text/event-stream") {
+            Some(Self::Sse)
+        } else if [
+            "application/x-ndjson",
+            "application/ndjson",
+            "application/jsonl",
+        ]
+        .iter()
+        .any(|ndjson| mime.eq_ignore_ascii_case(ndjson))
+        {
+            Some(Self::Ndjson)
+        } else {
+            None
+        }
+    }
+
+    /// Detect an event stream from upstream response headers. Encoded bodies
+    /// are left alone since events cannot be split without decoding them.
+    pub fn from_response_headers(headers: &HeaderMap) -> Option<Self> {
+        let encoded = headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|encoding| encoding != "identity");
+        if encoded {
+            return None;
+        }
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(Self::from_content_type)
+    }
+
+    /// Length of the next complete event at the start of `buf`, including its delimiter.
+    fn next_event_len(self, buf: &[u8]) -> Option<usize> {
+        match self {
+            Self::Ndjson => buf.iter().position(|&b| b == b'\n').map(|i| i + 1),
+            Self::Sse => sse_event_len(buf),
+        }
+    }
+
+    fn signature_record(self, index: u64, signature: &str) -> String {
+        match self {
+            Self::Sse => format!(": x-signature {} {}\n\n", index, signature),
+            Self::Ndjson => format!(
+                "{{\"x_signature\":{{\"index\":{},\"signature\":\"{}\"}}}}\n",
+                index, signature
+            ),
+        }
+    }
+}
+
+/// Digest of one event, chained to the digest of the event before it.
+///
+/// ```text
+/// keccak256("oyster-event-v1\0" || previous_digest || u64be(index) ||
+///           u64be(len(event)) || event)
+/// ```
+///
+/// The first event chains to the digest of the signed request prefix, so every
+/// event is bound to the request that produced it.
+pub fn event_digest(previous: &[u8; 32], index: u64, event: &[u8]) -> Keccak256 {
+    let mut hasher = Keccak256::new();
+    hasher.update(EVENT_DOMAIN_V1);
+    hasher.update(previous);
+    hasher.update(index.to_be_bytes());
+    hasher.update((event.len() as u64).to_be_bytes());
+    hasher.update(event);
+    hasher
+}
+
+/// Splits an upstream byte stream into events and appends a chained signature
+/// record after each one.
+///
+/// An event longer than [`MAX_EVENT_BYTES`] is not held back: the signer then
+/// forwards it and everything after it without records.
+pub struct EventSigner {
+    format: EventFormat,
+    buffer: Vec<u8>,
+    previous: [u8; 32],
+    index: u64,
+    signing_key: SigningKey,
+    passthrough: bool,
+}
+
+impl EventSigner {
+    pub fn new(format: EventFormat, anchor: [u8; 32], signing_key: SigningKey) -> Self {
+        Self {
+            format,
+            buffer: Vec::new(),
+            previous: anchor,
+            index: 0,
+            signing_key,
+            passthrough: false,
+        }
+    }
+
+    /// Whether an oversized event stopped the records.
+    pub fn is_passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    /// Feed upstream bytes, returning whatever is ready to forward.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        if self.passthrough {
+            return chunk.to_vec();
+        }
+        self.buffer.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        let mut start = 0;
+        while let Some(len) = self.format.next_event_len(&self.buffer[start..]) {
+            let event = self.buffer[start..start + len].to_vec();
+            self.sign_event(&event, &mut out);
+            start += len;
+        }
+        self.buffer.drain(..start);
+        if self.buffer.len() > MAX_EVENT_BYTES {
+            self.passthrough = true;
+            out.append(&mut self.buffer);
+        }
+        out
+    }
+
+    /// Emit a trailing event that was not terminated by a delimiter.
+    pub fn flush(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.buffer.is_empty() {
+            let event = std::mem::take(&mut self.buffer);
+            self.sign_event(&event, &mut out);
+        }
+        out
+    }
+
+    fn sign_event(&mut self, event: &[u8], out: &mut Vec<u8>) {
+        let hasher = event_digest(&self.previous, self.index, event);
+        self.previous = hasher.clone().finalize().into();
+        let signature = sign_digest(&self.signing_key, hasher);
+
+        out.extend_from_slice(event);
+        if self.format == EventFormat::Ndjson && !event.ends_with(b"\n") {
+            out.push(b'\n');
+        }
+        out.extend_from_slice(
+            self.format
+                .signature_record(self.index, &signature)
+                .as_bytes(),
+        );
+        self.index += 1;
+    }
+}
+
+/// Length of the first SSE event in `buf`, up to the end of the blank line
+/// that terminates it. Lines end with `\r\n`, `\n` or a lone `\r`, so a
+/// trailing `\r` is only taken once the next byte shows whether a `\n`
+/// belongs to it.
+fn sse_event_len(buf: &[u8]) -> Option<usize> {
+    let mut after_line_end = false;
+    let mut i = 0;
+    while i < buf.len() {
+        let line_end = match buf[i] {
+            b'\n' => i + 1,
+            b'\r' => match buf.get(i + 1) {
+                Some(b'\n') => i + 2,
+                Some(_) => i + 1,
+                None => return None,
+            },
+            _ => {
+                after_line_end = false;
+                i += 1;
+                continue;
+            }
+        };
+        if after_line_end {
+            return Some(line_end);
+        }
+        after_line_end = true;
+        i = line_end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+
+    fn test_signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[1u8; 32].into()).unwrap()
+    }
+
+    fn recover(hasher: Keccak256, signature_hex: &str) -> VerifyingKey {
+        let bytes = hex::decode(signature_hex).unwrap();
+        let signature = Signature::from_slice(&bytes[..64]).unwrap();
+        let recovery_id = RecoveryId::from_byte(bytes[64] - 27).unwrap();
+        VerifyingKey::recover_from_digest(hasher, &signature, recovery_id).unwrap()
+    }
+
+    #[test]
+    fn test_detect_event_formats() {
+        let sse = HeaderValue::from_static("text/event-stream; charset=utf-8");
+        let ndjson = HeaderValue::from_static("application/x-ndjson");
+        let json = HeaderValue::from_static("application/json");
+        assert_eq!(EventFormat::from_content_type(&sse), Some(EventFormat::Sse));
+        assert_eq!(
+            EventFormat::from_content_type(&ndjson),
+            Some(EventFormat::Ndjson)
+        );
+        assert_eq!(EventFormat::from_content_type(&json), None);
+    }
+
+    #[test]
+    fn test_encoded_event_stream_is_not_split() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        assert_eq!(
+            EventFormat::from_response_headers(&headers),
+            Some(EventFormat::Sse)
+        );
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(EventFormat::from_response_headers(&headers), None);
+    }
+
+    #[test]
+    fn test_ndjson_events_split_across_chunks() {
+        let key = test_signing_key();
+        let mut signer = EventSigner::new(EventFormat::Ndjson, [0u8; 32], key.clone());
+
+        let mut out = signer.push(b"{\"a\":1}\n{\"a\"");
+        out.extend(signer.push(b":2}\n"));
+        out.extend(signer.flush());
+
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "{\"a\":1}");
+        assert_eq!(lines[2], "{\"a\":2}");
+
+        let record: serde_json::Value = serde_json::from_str(lines[3]).unwrap();
+        assert_eq!(record["x_signature"]["index"], 1);
+
+        let first = event_digest(&[0u8; 32], 0, b"{\"a\":1}\n");
+        let first_digest: [u8; 32] = first.clone().finalize().into();
+        let second = event_digest(&first_digest, 1, b"{\"a\":2}\n");
+        let signature = record["x_signature"]["signature"].as_str().unwrap();
+        assert_eq!(recover(second, signature), *key.verifying_key());
+    }
+
+    #[test]
+    fn test_sse_events_get_comment_records() {
+        let mut signer = EventSigner::new(EventFormat::Sse, [7u8; 32], test_signing_key());
+        let out = signer.push(b"data: one\n\ndata: tw");
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("data: one\n\n: x-signature 0 "));
+        assert!(text.ends_with("\n\n"));
+        assert!(!text.contains("tw"));
+
+        let rest = String::from_utf8(signer.push(b"o\r\n\r\n")).unwrap();
+        assert!(rest.starts_with("data: two\r\n\r\n: x-signature 1 "));
+    }
+
+    #[test]
+    fn test_sse_event_delimiters() {
+        assert_eq!(sse_event_len(b"data: a\n\ndata: b"), Some(9));
+        assert_eq!(sse_event_len(b"data: a\r\n\r\n"), Some(11));
+        assert_eq!(sse_event_len(b"data: a\r\rdata: b"), Some(9));
+        assert_eq!(sse_event_len(b"data: a\r\ndata: b\n\n"), Some(18));
+        assert_eq!(sse_event_len(b"data: a\n\r\n"), Some(10));
+        // A lone \r may still be followed by \n
+        assert_eq!(sse_event_len(b"data: a\r\n\r"), None);
+        assert_eq!(sse_event_len(b"data: a\n"), None);
+
+        let mut signer = EventSigner::new(EventFormat::Sse, [7u8; 32], test_signing_key());
+        let text = String::from_utf8(signer.push(b"data: one\r\rdata: two\r\r")).unwrap();
+        assert!(text.starts_with("data: one\r\r: x-signature 0 "));
+        assert!(!text.contains("two"));
+        let text = String::from_utf8(signer.push(b"data: three")).unwrap();
+        assert!(text.starts_with("data: two\r\r: x-signature 1 "));
+    }
+
+    #[test]
+    fn test_oversized_event_passes_through() {
+        let mut signer = EventSigner::new(EventFormat::Ndjson, [0u8; 32], test_signing_key());
+        let text = String::from_utf8(signer.push(b"{\"a\":1}\n")).unwrap();
+        assert_eq!(text.lines().count(), 2);
+
+        let long = vec![b'x'; MAX_EVENT_BYTES + 1];
+        assert_eq!(signer.push(&long), long);
+        assert!(signer.is_passthrough());
+        assert_eq!(signer.push(b"\n{\"a\":2}\n"), b"\n{\"a\":2}\n");
+        assert!(signer.flush().is_empty());
+    }
+}
@@ -1,30 +1,55 @@
+use crate::metrics::UpstreamError;
 use crate::state::AppState;
+use crate::streaming::{
+    MAX_BUFFERED_BODY_BYTES, SignatureDelivery, StreamSigning, UpstreamBytes, accepts_trailers,
+    buffer_body, content_length, hash_request_body, signed_stream_body,
+};
+use crate::upstream::{Forward, unrouted};
+use crate::websocket::{is_websocket_upgrade, proxy_websocket};
 use axum::{
     body::Body,
     extract::State,
-    http::{Method, Request, StatusCode},
+    http::{Method, Request, StatusCode, header},
     response::Response,
 };
-use k256::ecdsa::SigningKey;
-use sha3::{Digest, Keccak256};
-use std::sync::Arc;
-use tracing::error;
+use futures_util::StreamExt;
+use http_body::Body as _;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, warn};
+use x402_gateway::events::{EVENT_SIGNATURE_VERSION, EVENT_SIGNATURES_HEADER, EventFormat};
+use x402_gateway::receipt::RequestDigest;
+use x402_gateway::signing::{
+    ResponseHead, SIGNATURE_CHUNK_HEADER, SIGNATURE_HEADER, SIGNATURE_HEX_LEN,
+    SIGNATURE_NONCE_HEADER, SIGNATURE_TIMESTAMP_HEADER, SIGNATURE_VERSION_HEADER,
+    SIGNED_HEADERS_HEADER, SIGNED_PAYMENT_HEADER, SignatureVersion, SignedPayment, SigningHasher,
+    encode_response_head,
+};
 
 pub async fn proxy_request(
     State(state): State<Arc<AppState>>,
     req: Request<Body>,
 ) -> Result<Response, StatusCode> {
+    if is_websocket_upgrade(req.headers()) {
+        return proxy_websocket(state, req).await;
+    }
+
     let method = req.method().clone();
-    let path = req.uri().path().to_string();
     let request_path_and_query = req
         .uri()
         .path_and_query()
         .map(|pq| pq.as_str().to_string())
         .unwrap_or_else(|| "/".to_string());
-    let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
 
-    let target_url = format!("{}{}{}", state.config.target_api_url, path, query);
-    println!("Target {} URL: {}", method.as_str(), target_url);
+    let forward = Forward::for_request(&req)?;
+    let Some(lease) = forward.pick() else {
+        warn!(upstream = %forward.upstream(), "No healthy backend for the request");
+        state
+            .metrics
+            .upstream_error(forward.upstream(), UpstreamError::Unavailable);
+        return Ok(unrouted(StatusCode::SERVICE_UNAVAILABLE));
+    };
+    let target_url = forward.url(&lease, req.uri().path(), req.uri().query());
+    debug!(method = %method, url = %target_url, "Forwarding request upstream");
 
     let mut proxy_req = state.http_client.request(method.clone(), &target_url);
 
@@ -34,33 +59,121 @@ pub async fn proxy_request(
         }
     }
 
-    let body_bytes = axum::body::to_bytes(req.into_body(), usize::MAX)
-        .await
-        .map_err(|e| {
-            error!(error = %e, "Failed to read request body");
-            StatusCode::BAD_REQUEST
-        })?;
-
-    let request_body_bytes = body_bytes.clone();
-    proxy_req = proxy_req.body(body_bytes);
-
-    let response = proxy_req
-        .send()
-        .await
-        .map_err(|e| {
-            error!(error = %e, "Proxy request failed");
-            StatusCode::BAD_GATEWAY
-        })?;
+    // A streamed response is signed in a trailer for clients that accept
+    // them, and in a final chunk for everyone else
+    let sign_in_trailer = accepts_trailers(req.headers());
+    let wants_event_records = req
+        .headers()
+        .get(EVENT_SIGNATURES_HEADER)
+        .is_some_and(|value| value == EVENT_SIGNATURE_VERSION);
+    let requested =
+        SignatureVersion::requested(req.headers(), state.config.signing.default_version());
+    let payment = req.extensions().get::<SignedPayment>().cloned();
+    let mut request_length = content_length(req.headers()).or(req.body().size_hint().exact());
+    let mut body = req.into_body();
+
+    // v2 and v3 length-prefix the request body, so a chunked upload is read
+    // first to learn its length. One too large for that is signed in v4,
+    // which takes the length at the end.
+    if request_length.is_none() {
+        body = match buffer_body(body, MAX_BUFFERED_BODY_BYTES).await {
+            Ok(Ok(bytes)) => {
+                request_length = Some(bytes.len() as u64);
+                Body::from(bytes)
+            }
+            Ok(Err(body)) => {
+                warn!("Chunked upload is too large to buffer; signing in v4");
+                body
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to read the request body");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+        };
+    }
+    let version = match request_length {
+        Some(_) => requested,
+        None => SignatureVersion::V4,
+    };
+    let mut hasher = SigningHasher::with_version(version, &method, &request_path_and_query);
+    match request_length {
+        Some(len) => hasher.begin_body(len),
+        None => hasher.begin_streamed_body(),
+    }
+    .expect("no body has been announced yet");
+    let hasher = Arc::new(Mutex::new(hasher));
+    proxy_req = proxy_req.body(hash_request_body(body, hasher.clone()));
+
+    let response = match proxy_req.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            error!(error = %e, backend = %lease.backend_url(), "Proxy request failed");
+            lease.record_failure();
+            state
+                .metrics
+                .upstream_error(forward.upstream(), UpstreamError::Connect);
+            return Ok(unrouted(StatusCode::BAD_GATEWAY));
+        }
+    };
+    lease.record_response(response.status());
 
     let status = response.status();
     let resp_headers = response.headers().clone();
-    let body = response
-        .bytes()
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    let response_length = if method == Method::HEAD {
+        Some(0)
+    } else {
+        response.content_length()
+    };
 
     let mut response_builder = Response::builder().status(status.as_u16());
 
+    // An upstream that answers before reading the whole request body gets its
+    // response forwarded, but the gateway cannot sign a request it did not
+    // finish sending
+    let signed = {
+        let mut hasher = hasher.lock().expect("signing hasher poisoned");
+        let complete = hasher.body_complete();
+        if !complete {
+            warn!("Upstream responded before the request body was forwarded; not signing");
+        } else if response_length.is_none() {
+            hasher.switch_to_v4().expect("request body is complete");
+        }
+        complete
+    };
+    let version = hasher.lock().expect("signing hasher poisoned").version();
+
+    if signed && version.has_response_head() {
+        response_builder = response_builder.header(SIGNATURE_VERSION_HEADER, version.as_str());
+        let head = if version == SignatureVersion::V3 {
+            encode_response_head(status, &resp_headers, payment.as_ref())
+        } else {
+            let head = ResponseHead::new(
+                status,
+                &resp_headers,
+                &state.config.signing.signed_headers,
+                payment.as_ref(),
+            );
+            response_builder = response_builder
+                .header(SIGNATURE_TIMESTAMP_HEADER, head.timestamp)
+                .header(SIGNATURE_NONCE_HEADER, hex::encode(head.nonce))
+                .header(SIGNED_HEADERS_HEADER, head.signed_header_names());
+            head.encode()
+        };
+        hasher
+            .lock()
+            .expect("signing hasher poisoned")
+            .set_response_head(head);
+        if let Some(payment) = &payment {
+            let json = serde_json::to_string(payment).expect("payment serializes");
+            response_builder = response_builder.header(SIGNED_PAYMENT_HEADER, json);
+        }
+    }
+    // Lets a settled payment's receipt name the request it paid for
+    let request_digest = hasher.lock().expect("signing hasher poisoned").digest();
+    if let Ok(digest) = request_digest {
+        response_builder = response_builder.extension(RequestDigest(digest));
+    }
+
     for (key, value) in resp_headers.iter() {
         if key == "transfer-encoding" || key == "content-length" {
             continue;
@@ -68,79 +181,126 @@ pub async fn proxy_request(
         response_builder = response_builder.header(key, value);
     }
 
-    let signing_message = build_signing_message(
-        &method,
-        &request_path_and_query,
-        request_body_bytes.as_ref(),
-        body.as_ref(),
-    );
-    let signature = sign_message(&state.signing_key, &signing_message);
-    response_builder = response_builder.header("X-Signature", &signature);
+    // Metered by bytes, a response is charged for what the upstream sent
+    let upstream_bytes = UpstreamBytes::default();
+    response_builder = response_builder.extension(upstream_bytes.clone());
+
+    // SSE and NDJSON streams are forwarded event by event, each followed by its
+    // signature record, for clients that ask for the records
+    let event_stream = EventFormat::from_response_headers(&resp_headers);
+    let events = event_stream.filter(|_| signed && wants_event_records);
+
+    // A small body of known length is read whole and signed in the
+    // X-Signature header, which every client can read
+    let small = response_length.is_some_and(|len| len <= MAX_BUFFERED_BODY_BYTES);
+    if signed && small && events.is_none() {
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                error!(error = %e, backend = %lease.backend_url(), "Failed to read the upstream response");
+                state
+                    .metrics
+                    .upstream_error(forward.upstream(), UpstreamError::Body);
+                return Ok(unrouted(StatusCode::BAD_GATEWAY));
+            }
+        };
+        upstream_bytes.add(body.len());
+        let signature = {
+            let mut hasher = hasher.lock().expect("signing hasher poisoned");
+            hasher
+                .begin_body(body.len() as u64)
+                .and_then(|()| hasher.update(&body))
+                .and_then(|()| {
+                    state
+                        .metrics
+                        .time_signing(|| hasher.sign(&state.signing_key))
+                })
+                .expect("request body is complete")
+        };
+        return response_builder
+            .header(SIGNATURE_HEADER, signature)
+            .body(Body::from(body))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
+    if events.is_some() {
+        response_builder =
+            response_builder.header(EVENT_SIGNATURES_HEADER, EVENT_SIGNATURE_VERSION);
+    }
+    let signature = if !signed {
+        SignatureDelivery::Unsigned
+    } else if sign_in_trailer {
+        response_builder = response_builder.header(header::TRAILER, SIGNATURE_HEADER);
+        SignatureDelivery::Trailer
+    } else if event_stream.is_some() {
+        // A final chunk would reach event parsers as a stray event
+        SignatureDelivery::Unsigned
+    } else {
+        response_builder = response_builder.header(SIGNATURE_CHUNK_HEADER, SIGNATURE_HEX_LEN);
+        SignatureDelivery::FinalChunk
+    };
+
+    // The lease rides along with the stream so the backend counts as busy
+    // until the body is done
+    let metrics = state.metrics.clone();
+    let upstream = forward.upstream().to_string();
+    let chunks = response.bytes_stream().map(move |chunk| {
+        let _ = &lease;
+        match &chunk {
+            Ok(bytes) => upstream_bytes.add(bytes.len()),
+            Err(_) => metrics.upstream_error(&upstream, UpstreamError::Body),
+        }
+        chunk
+    });
+    let body = signed_stream_body(
+        chunks,
+        hasher,
+        response_length,
+        state.signing_key.clone(),
+        state.metrics.clone(),
+        StreamSigning { signature, events },
+    );
     response_builder
-        .body(Body::from(body))
+        .body(body)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-fn build_signing_message(
-    request_method: &Method,
-    request_path_and_query: &str,
-    request_body: &[u8],
-    response_body: &[u8],
-) -> Vec<u8> {
-    let method = request_method.as_str().as_bytes();
-    let path_and_query = request_path_and_query.as_bytes();
-
-    let mut message = Vec::with_capacity(
-        16 + method.len() + path_and_query.len() + request_body.len() + response_body.len(),
-    );
-    message.extend_from_slice(b"oyster-signature-v2\0");
-    message.extend_from_slice(&(method.len() as u32).to_be_bytes());
-    message.extend_from_slice(method);
-    message.extend_from_slice(&(path_and_query.len() as u32).to_be_bytes());
-    message.extend_from_slice(path_and_query);
-    message.extend_from_slice(&(request_body.len() as u64).to_be_bytes());
-    message.extend_from_slice(request_body);
-    message.extend_from_slice(&(response_body.len() as u64).to_be_bytes());
-    message.extend_from_slice(response_body);
-    message
-}
-
-fn sign_message(signing_key: &SigningKey, message: &[u8]) -> String {
-    let mut hasher = Keccak256::new();
-    hasher.update(message);
-    let (signature, recovery_id) = signing_key
-        .sign_digest_recoverable(hasher)
-        .expect("signing failed");
-
-    let mut sig_bytes = signature.to_vec();
-    sig_bytes.push(recovery_id.to_byte() + 27);
-    hex::encode(sig_bytes)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{Config, ProtectedRoute};
+    use crate::config::{Config, ProtectedRoute, UpstreamTarget};
+    use crate::upstream::{Unrouted, Upstreams};
     use axum::http::Request;
+    use http_body_util::BodyExt;
     use k256::ecdsa::SigningKey;
-    use wiremock::matchers::{method, path};
+    use wiremock::matchers::{body_string, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
+    use x402_gateway::signing::{
+        build_signing_message, build_signing_message_v3, build_signing_message_v4, sign_message,
+    };
+    use x402_gateway::verify::SignedResponse;
 
     fn make_state(target_url: &str) -> Arc<AppState> {
         Arc::new(AppState {
             config: Config {
                 gateway_port: 3000,
                 facilitator_url: "https://www.x402.org/facilitator".to_string(),
-                target_api_url: target_url.to_string(),
+                target_api_url: Some(target_url.to_string()),
                 networks: vec![],
                 protected_routes: vec![ProtectedRoute {
                     path: "/protected".to_string(),
-                    usdc_amount: 1000,
+                    usdc_amount: Some(1000),
+                    ..Default::default()
                 }],
+                ..Default::default()
             },
             http_client: reqwest::Client::new(),
             signing_key: test_signing_key(),
+            attestation_url: None,
+            metrics: Default::default(),
+            pools: Default::default(),
+            ledger: None,
+            credits: None,
         })
     }
 
@@ -149,6 +309,21 @@ mod tests {
         SigningKey::from_bytes(&key_bytes.into()).unwrap()
     }
 
+    /// Proxy `req` to the fallback upstream, as the router would.
+    async fn proxy(state: Arc<AppState>, mut req: Request<Body>) -> Response {
+        let upstreams = Upstreams::new(&state.config, &state.http_client, &state.pools);
+        let forward = upstreams.forward(&state.config, &UpstreamTarget::default());
+        req.extensions_mut().insert(forward);
+        proxy_request(State(state), req).await.unwrap()
+    }
+
+    fn signature_header(response: &Response) -> String {
+        response.headers()[SIGNATURE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
     #[tokio::test]
     async fn test_proxy_request_success() {
         let mock_server = MockServer::start().await;
@@ -167,7 +342,7 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let response = proxy_request(State(state), req).await.unwrap();
+        let response = proxy(state, req).await;
         assert_eq!(response.status(), StatusCode::OK);
 
         let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
@@ -186,9 +361,21 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let result = proxy_request(State(state), req).await;
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), StatusCode::BAD_GATEWAY);
+        let response = proxy(state, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(response.extensions().get::<Unrouted>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_without_route_is_internal_error() {
+        let state = make_state("http://127.0.0.1:1");
+        let req = Request::builder()
+            .uri("/anything")
+            .body(Body::empty())
+            .unwrap();
+
+        let status = proxy_request(State(state), req).await.unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     #[tokio::test]
@@ -207,7 +394,7 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let response = proxy_request(State(state), req).await.unwrap();
+        let response = proxy(state, req).await;
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
@@ -227,8 +414,444 @@ mod tests {
             .body(Body::empty())
             .unwrap();
 
-        let response = proxy_request(State(state), req).await.unwrap();
+        let response = proxy(state, req).await;
         let sig = response.headers().get("X-Signature").unwrap();
         assert_eq!(sig.as_bytes().len(), 130);
+        assert_eq!(
+            response.headers()["x-signature-version"],
+            "oyster-signature-v4"
+        );
+        assert!(response.headers().get(SIGNATURE_CHUNK_HEADER).is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "signed body");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_signs_large_body_in_final_chunk() {
+        let mock_server = MockServer::start().await;
+
+        let upstream_body = "a".repeat(MAX_BUFFERED_BODY_BYTES as usize + 1);
+        Mock::given(method("GET"))
+            .and(path("/large"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(upstream_body.clone()))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let req = Request::builder()
+            .uri("/large")
+            .header("x-signature-version", "oyster-signature-v2")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert!(response.headers().get(SIGNATURE_HEADER).is_none());
+        assert_eq!(response.headers()[SIGNATURE_CHUNK_HEADER], "130");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let (content, signature) = body.split_at(body.len() - SIGNATURE_HEX_LEN);
+        assert_eq!(content, upstream_body.as_bytes());
+
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message(&Method::GET, "/large", b"", upstream_body.as_bytes()),
+        );
+        assert_eq!(signature, expected.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_streams_request_body() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_string("{\"model\":\"qwen3:0.6b\"}"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let request_body = "{\"model\":\"qwen3:0.6b\"}";
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/chat?stream=false")
+            .header("x-signature-version", "oyster-signature-v2")
+            .header("content-length", request_body.len())
+            .body(Body::from(request_body))
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let sig = signature_header(&response);
+
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message(
+                &Method::POST,
+                "/api/chat?stream=false",
+                request_body.as_bytes(),
+                b"ok",
+            ),
+        );
+        assert_eq!(sig, expected);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_signs_v3_when_requested() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/paid"))
+            .respond_with(ResponseTemplate::new(201).set_body_raw("{\"id\":1}", "application/json"))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let payment = SignedPayment {
+            scheme: "exact".to_string(),
+            network: "eip155:84532".to_string(),
+            asset: "0x036CbD53842c5426634e7929541eC2318f3dCF7e".to_string(),
+            amount: "1000".to_string(),
+            pay_to: "0xd232A8b0F63a555d054134f67b298ffE955f3BAf".to_string(),
+            settlement_reference: format!("0x{}", "ab".repeat(32)),
+        };
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/paid")
+            .header("content-length", 2)
+            .header("x-signature-version", "oyster-signature-v3")
+            .body(Body::from("{}"))
+            .unwrap();
+        req.extensions_mut().insert(payment.clone());
+
+        let response = proxy(state, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let headers = response.headers().clone();
+        let signature = signature_header(&response);
+        assert_eq!(headers["x-signature-version"], "oyster-signature-v3");
+        // The timestamp, nonce and header allowlist are v4 only
+        assert!(!headers.contains_key("x-signature-timestamp"));
+        assert!(!headers.contains_key("x-signed-headers"));
+        let signed_payment: serde_json::Value =
+            serde_json::from_slice(headers["x-signed-payment"].as_bytes()).unwrap();
+        assert_eq!(signed_payment["pay_to"], payment.pay_to);
+
+        let head = encode_response_head(StatusCode::CREATED, &headers, Some(&payment));
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message_v3(&Method::POST, "/paid", b"{}", &head, b"{\"id\":1}"),
+        );
+        assert_eq!(signature, expected);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_signs_in_trailer_when_accepted() {
+        let mock_server = MockServer::start().await;
+
+        // Too large for the header, so it streams and signs in the trailer
+        let upstream_body = "s".repeat(MAX_BUFFERED_BODY_BYTES as usize + 1);
+        Mock::given(method("GET"))
+            .and(path("/stream"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(upstream_body.clone()))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let req = Request::builder()
+            .uri("/stream")
+            .header("x-signature-version", "oyster-signature-v2")
+            .header("te", "trailers")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert!(response.headers().get("X-Signature").is_none());
+        assert_eq!(response.headers().get("trailer").unwrap(), "X-Signature");
+
+        let collected = response.into_body().collect().await.unwrap();
+        let trailers = collected.trailers().cloned().unwrap();
+        let body = collected.to_bytes();
+        assert_eq!(body, upstream_body);
+
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message(&Method::GET, "/stream", b"", upstream_body.as_bytes()),
+        );
+        assert_eq!(trailers.get("X-Signature").unwrap(), expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_trailer_signature_without_upstream_content_length() {
+        let key = test_signing_key();
+        let headers = axum::http::HeaderMap::new();
+        let head = ResponseHead::new(StatusCode::OK, &headers, &[], None).encode();
+        let mut hasher = SigningHasher::new(&Method::GET, "/chunked");
+        hasher.begin_body(0).unwrap();
+        hasher.switch_to_v4().unwrap();
+        hasher.set_response_head(head.clone());
+        let hasher = Arc::new(Mutex::new(hasher));
+
+        let chunks = futures_util::stream::iter(vec![
+            Ok::<_, std::io::Error>(axum::body::Bytes::from_static(b"{\"a\":1}\n")),
+            Ok(axum::body::Bytes::from_static(b"{\"a\":2}\n")),
+        ]);
+        let signing = StreamSigning {
+            signature: SignatureDelivery::Trailer,
+            events: None,
+        };
+        let body = signed_stream_body(
+            chunks,
+            hasher,
+            None,
+            key.clone(),
+            Default::default(),
+            signing,
+        );
+
+        let collected = body.collect().await.unwrap();
+        let trailers = collected.trailers().cloned().unwrap();
+        assert_eq!(collected.to_bytes(), "{\"a\":1}\n{\"a\":2}\n");
+
+        let expected = sign_message(
+            &key,
+            &build_signing_message_v4(
+                &Method::GET,
+                "/chunked",
+                b"",
+                &head,
+                b"{\"a\":1}\n{\"a\":2}\n",
+            ),
+        );
+        assert_eq!(trailers.get("X-Signature").unwrap(), expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_buffers_small_chunked_upload() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/upload"))
+            .and(body_string("hello world"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("stored"))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let chunks = futures_util::stream::iter(vec![
+            Ok::<_, std::io::Error>(axum::body::Bytes::from_static(b"hello ")),
+            Ok(axum::body::Bytes::from_static(b"world")),
+        ]);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("x-signature-version", "oyster-signature-v2")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+
+        // Buffered, so it keeps the requested layout instead of switching to v4
+        let response = proxy(state, req).await;
+        assert!(response.headers().get("x-signature-version").is_none());
+        let signature = signature_header(&response);
+
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message(&Method::POST, "/upload", b"hello world", b"stored"),
+        );
+        assert_eq!(signature, expected);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_signs_large_chunked_upload_in_v4() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/upload"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("stored"))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let upload = vec![b'a'; MAX_BUFFERED_BODY_BYTES as usize + 1];
+        let chunks = futures_util::stream::iter(
+            upload
+                .chunks(64 * 1024)
+                .map(|chunk| Ok::<_, std::io::Error>(axum::body::Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        );
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        let headers = response.headers().clone();
+        assert_eq!(headers["x-signature-version"], "oyster-signature-v4");
+        let signature = signature_header(&response);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "stored");
+
+        let signed = SignedResponse {
+            method: &Method::POST,
+            path_and_query: "/upload",
+            request_body: &upload,
+            status: StatusCode::OK,
+            headers: &headers,
+            body: &body,
+        };
+        assert_eq!(
+            signed.recover(&signature).unwrap(),
+            *test_signing_key().verifying_key()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_forwards_early_response_unsigned() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers as soon as the request head is in, without reading the body
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 413 Payload Too Large\r\ncontent-length: 8\r\n\r\ntoo long")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let state = make_state(&format!("http://{}", address));
+        let chunks = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(
+            axum::body::Bytes::from_static(b"part"),
+        )])
+        .chain(futures_util::stream::pending());
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("content-length", 100)
+            .body(Body::from_stream(chunks))
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response.headers().get(SIGNATURE_HEADER).is_none());
+        assert!(response.headers().get(SIGNATURE_CHUNK_HEADER).is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "too long");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_signs_empty_body_in_header() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("DELETE"))
+            .and(path("/item"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let req = Request::builder()
+            .method("DELETE")
+            .uri("/item")
+            .header("x-signature-version", "oyster-signature-v2")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message(&Method::DELETE, "/item", b"", b""),
+        );
+        assert_eq!(response.headers()[SIGNATURE_HEADER], expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_signs_ndjson_events() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                "{\"done\":false}\n{\"done\":true}\n",
+                "application/x-ndjson",
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/chat")
+            .header("content-length", 2)
+            .header("te", "trailers")
+            .header("x-event-signatures", "oyster-event-v1")
+            .body(Body::from("{}"))
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert_eq!(
+            response.headers().get("x-event-signatures").unwrap(),
+            "oyster-event-v1"
+        );
+        assert!(response.headers().get("X-Signature").is_none());
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "{\"done\":false}");
+        assert!(lines[1].starts_with("{\"x_signature\":{\"index\":0,"));
+        assert_eq!(lines[2], "{\"done\":true}");
+        assert!(lines[3].starts_with("{\"x_signature\":{\"index\":1,"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request_leaves_events_alone_without_opt_in() {
+        let mock_server = MockServer::start().await;
+
+        let upstream_body = "{\"done\":false}\n{\"done\":true}\n";
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(upstream_body, "application/x-ndjson"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let state = make_state(&mock_server.uri());
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/chat")
+            .header("x-signature-version", "oyster-signature-v2")
+            .header("content-length", 2)
+            .body(Body::from("{}"))
+            .unwrap();
+
+        let response = proxy(state, req).await;
+        assert!(response.headers().get("x-event-signatures").is_none());
+        let signature = signature_header(&response);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, upstream_body);
+
+        let expected = sign_message(
+            &test_signing_key(),
+            &build_signing_message(&Method::POST, "/api/chat", b"{}", upstream_body.as_bytes()),
+        );
+        assert_eq!(signature, expected);
     }
 }
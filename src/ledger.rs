@@ -0,0 +1,437 @@
+use crate::config::read_config;
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use rusqlite::{Connection, OpenFlags, params_from_iter, types::Value};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+pub const LEDGER_PATH: &str = "/ledger";
+pub const LEDGER_CSV_PATH: &str = "/ledger.csv";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS payments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    payer TEXT NOT NULL,
+    network TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    route TEXT NOT NULL,
+    request_id TEXT NOT NULL,
+    transaction_hash TEXT NOT NULL,
+    upstream_status INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS payments_payer ON payments (payer COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS payments_timestamp ON payments (timestamp);
+";
+
+const COLUMNS: &str = "id, timestamp, payer, network, asset, amount, route, request_id, \
+                       transaction_hash, upstream_status";
+
+/// One settled payment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LedgerEntry {
+    /// Assigned by the ledger; ascending in settlement order.
+    pub id: i64,
+    /// Unix seconds at settlement.
+    pub timestamp: u64,
+    pub payer: String,
+    /// CAIP-2 network as reported by the facilitator, e.g. `eip155:84532`.
+    pub network: String,
+    pub asset: String,
+    /// Settled USDC microunits; the measured usage on metered routes.
+    pub amount: u64,
+    /// Pattern of the protected route that was paid for.
+    pub route: String,
+    /// The receipt's `request_hash`, or empty when no receipt was issued.
+    pub request_id: String,
+    pub transaction: String,
+    pub upstream_status: u16,
+}
+
+/// Which entries to return. Every filter is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct LedgerQuery {
+    /// Case-insensitive, as EVM addresses come in mixed case.
+    pub payer: Option<String>,
+    pub network: Option<String>,
+    pub route: Option<String>,
+    /// Unix seconds, inclusive.
+    pub since: Option<u64>,
+    /// Unix seconds, exclusive.
+    pub until: Option<u64>,
+    /// Most recent entries only.
+    pub limit: Option<u32>,
+}
+
+/// Settled payments in an SQLite database, so revenue can be reconciled
+/// against on-chain deposits after the requests are gone.
+pub struct Ledger {
+    connection: Mutex<Connection>,
+}
+
+impl Ledger {
+    /// Open the ledger at `path` for writing, creating it if needed.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let connection =
+            Connection::open(path).map_err(|e| format!("Failed to open ledger {}: {}", path, e))?;
+        // WAL lets the CLI read while the gateway writes
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .and_then(|()| connection.execute_batch(SCHEMA))
+            .map_err(|e| format!("Failed to initialize ledger {}: {}", path, e))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Open an existing ledger without writing to it.
+    pub fn open_read_only(path: &str) -> Result<Self, String> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format!("Failed to open ledger {}: {}", path, e))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Append `entry`, ignoring its `id`, and return the one it was given.
+    pub fn record(&self, entry: &LedgerEntry) -> Result<i64, String> {
+        let connection = self.connection.lock().expect("ledger lock poisoned");
+        connection
+            .execute(
+                "INSERT INTO payments (timestamp, payer, network, asset, amount, route, \
+                 request_id, transaction_hash, upstream_status) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                rusqlite::params![
+                    entry.timestamp as i64,
+                    entry.payer,
+                    entry.network,
+                    entry.asset,
+                    entry.amount as i64,
+                    entry.route,
+                    entry.request_id,
+                    entry.transaction,
+                    entry.upstream_status,
+                ],
+            )
+            .map_err(|e| format!("Failed to record payment: {}", e))?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    /// Matching entries, oldest first.
+    pub fn query(&self, query: &LedgerQuery) -> Result<Vec<LedgerEntry>, String> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(payer) = &query.payer {
+            conditions.push("payer = ? COLLATE NOCASE");
+            values.push(Value::Text(payer.clone()));
+        }
+        if let Some(network) = &query.network {
+            conditions.push("network = ?");
+            values.push(Value::Text(network.clone()));
+        }
+        if let Some(route) = &query.route {
+            conditions.push("route = ?");
+            values.push(Value::Text(route.clone()));
+        }
+        if let Some(since) = query.since {
+            conditions.push("timestamp >= ?");
+            values.push(Value::Integer(since as i64));
+        }
+        if let Some(until) = query.until {
+            conditions.push("timestamp < ?");
+            values.push(Value::Integer(until as i64));
+        }
+
+        let mut sql = format!("SELECT {} FROM payments", COLUMNS);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        // The newest `limit` entries, still returned oldest first
+        if let Some(limit) = query.limit {
+            sql = format!("SELECT * FROM ({} ORDER BY id DESC LIMIT {})", sql, limit);
+        }
+        sql.push_str(" ORDER BY id");
+
+        let connection = self.connection.lock().expect("ledger lock poisoned");
+        let mut statement = connection
+            .prepare(&sql)
+            .map_err(|e| format!("Failed to query ledger: {}", e))?;
+        let rows = statement
+            .query_map(params_from_iter(values), |row| {
+                Ok(LedgerEntry {
+                    id: row.get(0)?,
+                    timestamp: row.get::<_, i64>(1)? as u64,
+                    payer: row.get(2)?,
+                    network: row.get(3)?,
+                    asset: row.get(4)?,
+                    amount: row.get::<_, i64>(5)? as u64,
+                    route: row.get(6)?,
+                    request_id: row.get(7)?,
+                    transaction: row.get(8)?,
+                    upstream_status: row.get(9)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query ledger: {}", e))?;
+        rows.collect::<Result<_, _>>()
+            .map_err(|e| format!("Failed to read ledger: {}", e))
+    }
+}
+
+/// `x402-gateway ledger [--db <file>] [--payer <address>] [--network <network>]
+/// [--route <pattern>] [--since <unix>] [--until <unix>] [--limit <n>] [--csv]`:
+/// print ledger entries as JSON, or as CSV with `--csv`. Without `--db` the
+/// ledger is the `ledger_path` of the config at `config_path`.
+pub fn ledger_command(args: &[String], config_path: &str) -> Result<String, String> {
+    let mut query = LedgerQuery::default();
+    let mut db = None;
+    let mut csv = false;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--csv" {
+            csv = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?
+            .clone();
+        let number = |value: &str| {
+            value
+                .parse()
+                .map_err(|_| format!("{} expects a number, got {}", flag, value))
+        };
+        match flag.as_str() {
+            "--db" => db = Some(value),
+            "--payer" => query.payer = Some(value),
+            "--network" => query.network = Some(value),
+            "--route" => query.route = Some(value),
+            "--since" => query.since = Some(number(&value)?),
+            "--until" => query.until = Some(number(&value)?),
+            "--limit" => query.limit = Some(number(&value)? as u32),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+
+    let db = match db {
+        Some(db) => db,
+        None => read_config(config_path)?
+            .ledger_path
+            .ok_or_else(|| format!("{} sets no ledger_path; pass --db", config_path))?,
+    };
+    let entries = Ledger::open_read_only(&db)?.query(&query)?;
+    Ok(if csv {
+        to_csv(&entries)
+    } else {
+        serde_json::to_string_pretty(&entries).expect("entries serialize") + "\n"
+    })
+}
+
+/// `entries` as CSV with a header row.
+pub fn to_csv(entries: &[LedgerEntry]) -> String {
+    let mut csv = String::from(
+        "id,timestamp,payer,network,asset,amount,route,request_id,transaction,upstream_status\n",
+    );
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry.timestamp.to_string(),
+            csv_field(&entry.payer),
+            csv_field(&entry.network),
+            csv_field(&entry.asset),
+            entry.amount.to_string(),
+            csv_field(&entry.route),
+            csv_field(&entry.request_id),
+            csv_field(&entry.transaction),
+            entry.upstream_status.to_string(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote a field that contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Admin API over the ledger: `GET /ledger` as JSON and `GET /ledger.csv`,
+/// both filtered by [`LedgerQuery`] parameters.
+pub fn ledger_router(ledger: Arc<Ledger>) -> Router {
+    Router::new()
+        .route(LEDGER_PATH, get(ledger_json))
+        .route(LEDGER_CSV_PATH, get(ledger_csv))
+        .with_state(ledger)
+}
+
+async fn run_query(
+    ledger: Arc<Ledger>,
+    query: LedgerQuery,
+) -> Result<Vec<LedgerEntry>, StatusCode> {
+    tokio::task::spawn_blocking(move || ledger.query(&query))
+        .await
+        .expect("ledger query panicked")
+        .map_err(|e| {
+            error!(error = %e, "Ledger query failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn ledger_json(
+    State(ledger): State<Arc<Ledger>>,
+    Query(query): Query<LedgerQuery>,
+) -> Result<Json<Vec<LedgerEntry>>, StatusCode> {
+    Ok(Json(run_query(ledger, query).await?))
+}
+
+async fn ledger_csv(
+    State(ledger): State<Arc<Ledger>>,
+    Query(query): Query<LedgerQuery>,
+) -> Result<Response, StatusCode> {
+    let entries = run_query(ledger, query).await?;
+    Ok(([(header::CONTENT_TYPE, "text/csv")], to_csv(&entries)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn entry(timestamp: u64, payer: &str, route: &str, amount: u64) -> LedgerEntry {
+        LedgerEntry {
+            timestamp,
+            payer: payer.to_string(),
+            network: "eip155:84532".to_string(),
+            asset: "0x036CbD53842c5426634e7929541eC2318f3dCF7e".to_string(),
+            amount,
+            route: route.to_string(),
+            request_id: format!("0x{}", "ab".repeat(32)),
+            transaction: "0x01".to_string(),
+            upstream_status: 200,
+            ..Default::default()
+        }
+    }
+
+    fn sample_ledger(dir: &tempfile::TempDir) -> Ledger {
+        let path = dir.path().join("ledger.db");
+        let ledger = Ledger::open(path.to_str().unwrap()).unwrap();
+        ledger.record(&entry(100, "0xAbC", "/api/**", 10)).unwrap();
+        ledger.record(&entry(200, "0xdef", "/api/**", 20)).unwrap();
+        ledger.record(&entry(300, "0xabc", "/premium", 30)).unwrap();
+        ledger
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = sample_ledger(&dir);
+        let amounts = |query: LedgerQuery| -> Vec<u64> {
+            ledger
+                .query(&query)
+                .unwrap()
+                .iter()
+                .map(|e| e.amount)
+                .collect()
+        };
+
+        assert_eq!(amounts(LedgerQuery::default()), [10, 20, 30]);
+        let by_payer = LedgerQuery {
+            payer: Some("0xABC".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(amounts(by_payer), [10, 30]);
+        let by_route = LedgerQuery {
+            route: Some("/api/**".to_string()),
+            since: Some(200),
+            ..Default::default()
+        };
+        assert_eq!(amounts(by_route), [20]);
+        let window = LedgerQuery {
+            since: Some(100),
+            until: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(amounts(window), [10, 20]);
+        let latest = LedgerQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(amounts(latest), [20, 30]);
+    }
+
+    #[test]
+    fn test_reopened_ledger_keeps_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(sample_ledger(&dir));
+        let path = dir.path().join("ledger.db");
+        let ledger = Ledger::open_read_only(path.to_str().unwrap()).unwrap();
+        let entries = ledger.query(&LedgerQuery::default()).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].id, 1);
+        assert_eq!(entries[0].payer, "0xAbC");
+        assert!(ledger.record(&entry(400, "0x1", "/", 1)).is_err());
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut first = entry(100, "0xabc", "/a,b", 10);
+        first.id = 1;
+        let csv = to_csv(&[first]);
+        assert_eq!(
+            csv,
+            format!(
+                "id,timestamp,payer,network,asset,amount,route,request_id,transaction,upstream_status\n\
+                 1,100,0xabc,eip155:84532,0x036CbD53842c5426634e7929541eC2318f3dCF7e,10,\"/a,b\",0x{},0x01,200\n",
+                "ab".repeat(32)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ledger_router() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = ledger_router(Arc::new(sample_ledger(&dir)));
+
+        let response = router
+            .clone()
+            .oneshot(
+                Request::get("/ledger?payer=0xabc&limit=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let entries: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["amount"], 30);
+        assert_eq!(entries[0]["route"], "/premium");
+
+        let response = router
+            .oneshot(Request::get("/ledger.csv").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap().lines().count(), 4);
+    }
+}
@@ -0,0 +1,19 @@
+//! The gateway's signing protocol, shared by the `x402-gateway` server and
+//! the `verifier` binary so other services can check signed responses too.
+//!
+//! - [`signing`]: the `oyster-signature-v2` and `-v3` messages and how the
+//!   gateway signs them.
+//! - [`events`]: chained per-event signatures for SSE and NDJSON streams.
+//! - [`receipt`]: signed receipts for settled payments.
+//! - [`pass`]: signed passes for time-limited access after one payment.
+//! - [`credit`]: requests a payer signs to spend prepaid credit.
+//! - [`verify`]: recovering the signing key and address on the client side.
+//!
+//! `tests/vectors.json` holds golden vectors for all of them.
+
+pub mod credit;
+pub mod events;
+pub mod pass;
+pub mod receipt;
+pub mod signing;
+pub mod verify;
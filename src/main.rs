@@ -1,22 +1,91 @@
+mod body_pricing;
 mod config;
+mod credits;
 mod handlers;
+mod ledger;
+mod metering;
+mod metrics;
 mod pricing;
+mod reload;
+mod routes;
+mod settlement;
 mod state;
+mod streaming;
+mod upstream;
+mod websocket;
+mod well_known;
+
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    process::ExitCode,
+    sync::Arc,
+};
+
+use axum::Router;
+use tracing::{error, info, warn};
+
+use crate::config::{Config, NetworkConfig, config_path, read_config};
+use crate::credits::credits_router;
+use crate::ledger::{LEDGER_PATH, ledger_command, ledger_router};
+use crate::metrics::{METRICS_PATH, admin_router, require_bearer};
+use crate::reload::{ConfigReloader, app, build_router};
+use crate::state::AppState;
 
-use axum::{Router, routing::any};
-use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
-
-use tracing::info;
-use x402_axum::X402Middleware;
+/// `x402-gateway check-config [file]`: validate a config file and exit.
+fn check_config(config_path: &str) -> ExitCode {
+    let config = match read_config(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match config.validate() {
+        Ok(()) => {
+            println!("{}: OK", config_path);
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("{}: {}", config_path, e);
+            }
+            eprintln!("{} error(s) found", errors.len());
+            ExitCode::FAILURE
+        }
+    }
+}
 
-use crate::config::{NetworkConfig, load_config};
-use crate::handlers::proxy_request;
-use crate::pricing::build_price_layer;
-use crate::state::AppState;
+/// Reject an invalid config before any network or KMS work happens.
+fn ensure_valid(config: &Config) -> Result<(), ExitCode> {
+    config.validate().map_err(|errors| {
+        for e in &errors {
+            error!(path = %e.path, "Invalid configuration: {}", e.message);
+        }
+        ExitCode::FAILURE
+    })
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("check-config") {
+        let config_path = args.get(2).cloned().unwrap_or_else(config_path);
+        return Ok(check_config(&config_path));
+    }
+    if args.get(1).map(String::as_str) == Some("ledger") {
+        return Ok(match ledger_command(&args[2..], &config_path()) {
+            Ok(output) => {
+                print!("{}", output);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                ExitCode::FAILURE
+            }
+        });
+    }
+
     // Initialize tracing subscriber
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -25,7 +94,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .init();
 
-    let config = load_config();
+    let config = match read_config(&config_path()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    if let Err(code) = ensure_valid(&config) {
+        return Ok(code);
+    }
 
     // Log configured networks
     for net in &config.networks {
@@ -47,50 +125,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!(
         facilitator = %config.facilitator_url,
-        target_api = %config.target_api_url,
+        upstreams = ?config.upstreams.keys().collect::<Vec<_>>(),
+        fallback_upstream = %config.upstream(None).map_or("none", |(name, _)| name),
+        free_routes_count = config.free_routes.len(),
         network_count = config.networks.len(),
         protected_routes_count = config.protected_routes.len(),
         gateway_port = config.gateway_port,
         "Loaded configuration (all non-protected routes are free)"
     );
 
-    // Create x402 middleware
-    let x402 = X402Middleware::try_from(config.facilitator_url.as_str())?;
-
     let state = Arc::new(AppState::new(config.clone()).await);
 
-    // Build router dynamically from config
-    let mut app = Router::new();
-
-    // Add protected routes with V2 price tags (all configured networks)
-    for route_config in &config.protected_routes {
-        info!(route = %route_config.path, amount = route_config.usdc_amount, "Registering PROTECTED route");
-        let layer = build_price_layer(&x402, &config.networks, route_config.usdc_amount);
-        app = app.route(&route_config.path, any(proxy_request).layer(layer));
+    // Metrics, the ledger and balances go on their own port, bound to
+    // loopback unless `admin_bind_address` says otherwise. The ledger and
+    // balances also need the ADMIN_TOKEN bearer token.
+    if let Some(admin_port) = config.admin_port {
+        let token = env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        let mut admin = admin_router(state.metrics.clone());
+        let mut private = Router::new();
+        if let Some(ledger) = &state.ledger {
+            private = private.merge(ledger_router(ledger.clone()));
+        }
+        if let Some(credits) = &state.credits {
+            private = private.merge(credits_router(credits.clone()));
+        }
+        match &token {
+            Some(token) => admin = admin.merge(require_bearer(private, token)),
+            None if state.ledger.is_some() || state.credits.is_some() => {
+                warn!("ADMIN_TOKEN is not set, so the ledger and balances are not served")
+            }
+            None => {}
+        }
+        let address = SocketAddr::new(
+            config
+                .admin_bind_address
+                .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            admin_port,
+        );
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .unwrap_or_else(|_| panic!("Failed to bind to {}", address));
+        info!(address = %address, metrics = METRICS_PATH, ledger = token.as_ref().and(state.ledger.as_ref()).map(|_| LEDGER_PATH), "Admin server started");
+        tokio::spawn(axum::serve(listener, admin).into_future());
     }
 
-    // All other routes are free — use fallback to proxy without payment
-    app = app.fallback(proxy_request);
+    // Build the router for the current config; it is rebuilt and swapped in
+    // whenever CONFIG_PATH changes or the process receives SIGHUP.
+    let router = build_router(state.clone())?;
     info!("All non-protected routes will be proxied freely");
 
-    // Add CORS layer to allow frontend requests
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any)
-        .expose_headers(Any);
+    let reloader = Arc::new(ConfigReloader::new(config_path(), state, router));
+    reloader.clone().spawn_watchers();
 
-    // Add state and CORS to the router
-    let app = app.layer(cors).with_state(state);
+    let app = app(reloader);
 
     let address = format!("0.0.0.0:{}", config.gateway_port);
     let listener = tokio::net::TcpListener::bind(&address)
         .await
-        .expect(&format!("Failed to bind to {}", address));
+        .unwrap_or_else(|_| panic!("Failed to bind to {}", address));
 
     info!(address = %address, "x402 Gateway started");
 
     axum::serve(listener, app).await?;
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,256 @@
+use crate::config::{Metering, UsageSource};
+use crate::streaming::UpstreamBytes;
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue},
+    response::Response,
+};
+use futures_util::{StreamExt, stream};
+use http_body::Frame;
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use serde_json::Value;
+use std::convert::Infallible;
+use tracing::{info, warn};
+use x402_axum::paygate::PaygateError;
+use x402_types::proto;
+
+pub const METERED_UNITS_HEADER: &str = "X-Metered-Units";
+
+/// Largest metered response body buffered to measure its usage.
+pub const MAX_METERED_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Amount to settle for `units` of usage, capped at what was authorized.
+fn amount_for(metering: &Metering, units: u64, max_usdc_amount: u64) -> u64 {
+    units
+        .saturating_mul(metering.usdc_per_unit)
+        .min(max_usdc_amount)
+}
+
+/// Read a metered response and work out what to settle for it.
+///
+/// Usage is only known once the body has been read, so the response is
+/// buffered and handed back rebuilt, with its trailers and the
+/// [`METERED_UNITS_HEADER`]. Usage read from a header needs no buffering. A
+/// body larger than [`MAX_METERED_BODY_BYTES`] is not measured: what was
+/// buffered is sent on followed by the rest of the stream, and the maximum
+/// is settled.
+pub async fn meter_response(
+    metering: &Metering,
+    response: Response,
+    max_usdc_amount: u64,
+) -> Result<(Response, u64), String> {
+    let (mut parts, body) = response.into_parts();
+    let (body, units) = match &metering.usage {
+        UsageSource::Header { .. } => {
+            let units = measure_usage(&metering.usage, &parts.headers, &[]);
+            (body, units)
+        }
+        UsageSource::JsonPath { .. } | UsageSource::Bytes => {
+            match buffer(body, MAX_METERED_BODY_BYTES).await? {
+                Buffered::Complete(body, trailers) => {
+                    let units = match (&metering.usage, parts.extensions.get::<UpstreamBytes>()) {
+                        // Signature records and chunks added by the gateway are not usage
+                        (UsageSource::Bytes, Some(upstream)) => Some(upstream.get()),
+                        _ => measure_usage(&metering.usage, &parts.headers, &body),
+                    };
+                    (rebuild_body(body, trailers), units)
+                }
+                Buffered::Partial(buffered, rest) => {
+                    warn!(
+                        limit = MAX_METERED_BODY_BYTES,
+                        "Metered response is too large to measure"
+                    );
+                    let frames = stream::once(async { Ok(Frame::data(buffered)) })
+                        .chain(BodyStream::new(rest));
+                    (Body::new(StreamBody::new(frames)), None)
+                }
+            }
+        }
+    };
+
+    let amount = match units {
+        Some(units) => {
+            parts
+                .headers
+                .insert(METERED_UNITS_HEADER, HeaderValue::from(units));
+            amount_for(metering, units, max_usdc_amount)
+        }
+        None => {
+            warn!("Could not measure usage of a metered response; settling the maximum");
+            max_usdc_amount
+        }
+    };
+    info!(amount, max = max_usdc_amount, "Settling metered payment");
+    Ok((Response::from_parts(parts, body), amount))
+}
+
+/// A response body read up to a limit.
+enum Buffered {
+    /// The whole body and its trailers.
+    Complete(Bytes, Option<HeaderMap>),
+    /// The body went past the limit: what was read, and the unread rest.
+    Partial(Bytes, Body),
+}
+
+async fn buffer(mut body: Body, limit: usize) -> Result<Buffered, String> {
+    let mut buffered = Vec::new();
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| format!("upstream body failed: {}", e))?;
+        match frame.into_data() {
+            Ok(data) => buffered.extend_from_slice(&data),
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+        if buffered.len() > limit {
+            return Ok(Buffered::Partial(buffered.into(), body));
+        }
+    }
+    Ok(Buffered::Complete(buffered.into(), trailers))
+}
+
+/// The verify request with the settled amount in place of the authorized maximum.
+pub fn with_amount(
+    verify_request: &proto::VerifyRequest,
+    amount: u64,
+) -> Result<proto::SettleRequest, PaygateError> {
+    let mut json: Value = serde_json::from_str(verify_request.as_str())
+        .map_err(|e| PaygateError::Settlement(e.to_string()))?;
+    json["paymentRequirements"]["amount"] = Value::String(amount.to_string());
+    let raw = serde_json::value::to_raw_value(&json)
+        .map_err(|e| PaygateError::Settlement(e.to_string()))?;
+    Ok(proto::SettleRequest::from(raw))
+}
+
+fn rebuild_body(body: Bytes, trailers: Option<HeaderMap>) -> Body {
+    match trailers {
+        None => Body::from(body),
+        Some(trailers) => {
+            let frames = [Frame::data(body), Frame::trailers(trailers)]
+                .into_iter()
+                .map(Ok::<_, Infallible>);
+            Body::new(StreamBody::new(stream::iter(frames)))
+        }
+    }
+}
+
+/// Units of usage reported by a response.
+pub fn measure_usage(source: &UsageSource, headers: &HeaderMap, body: &[u8]) -> Option<u64> {
+    match source {
+        UsageSource::Bytes => Some(body.len() as u64),
+        UsageSource::Header { name } => headers.get(name)?.to_str().ok()?.trim().parse().ok(),
+        UsageSource::JsonPath { json_path } => {
+            if let Ok(json) = serde_json::from_slice::<Value>(body) {
+                return json_path.get(&json)?.as_u64();
+            }
+            // NDJSON lines or SSE `data:` lines; the last one with a value wins
+            body.split(|&b| b == b'\n').rev().find_map(|line| {
+                let line = line.strip_prefix(b"data:").unwrap_or(line);
+                let json: Value = serde_json::from_slice(line).ok()?;
+                json_path.get(&json)?.as_u64()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_path(path: &str) -> UsageSource {
+        UsageSource::JsonPath {
+            json_path: path.into(),
+        }
+    }
+
+    #[test]
+    fn test_measure_usage_from_json_body() {
+        let body = br#"{"model":"qwen3:0.6b","done":true,"eval_count":42}"#;
+        assert_eq!(
+            measure_usage(&json_path("$.eval_count"), &HeaderMap::new(), body),
+            Some(42)
+        );
+        assert_eq!(
+            measure_usage(&json_path("$.missing"), &HeaderMap::new(), body),
+            None
+        );
+    }
+
+    #[test]
+    fn test_measure_usage_from_streams() {
+        let ndjson =
+            b"{\"response\":\"a\"}\n{\"response\":\"b\"}\n{\"done\":true,\"eval_count\":7}\n";
+        assert_eq!(
+            measure_usage(&json_path("$.eval_count"), &HeaderMap::new(), ndjson),
+            Some(7)
+        );
+
+        let sse = b"data: {\"usage\":{\"completion_tokens\":3}}\n\ndata: [DONE]\n\n";
+        assert_eq!(
+            measure_usage(
+                &json_path("$.usage.completion_tokens"),
+                &HeaderMap::new(),
+                sse
+            ),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_measure_usage_from_header_and_bytes() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-usage-units", HeaderValue::from_static(" 12 "));
+        let header = UsageSource::Header {
+            name: "X-Usage-Units".to_string(),
+        };
+        assert_eq!(measure_usage(&header, &headers, b""), Some(12));
+        assert_eq!(
+            measure_usage(&UsageSource::Bytes, &headers, b"hello"),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn test_settled_amount_is_capped() {
+        let metering = Metering {
+            usdc_per_unit: 3,
+            usage: UsageSource::Bytes,
+        };
+        assert_eq!(amount_for(&metering, 10, 100), 30);
+        assert_eq!(amount_for(&metering, 50, 100), 100);
+    }
+
+    #[test]
+    fn test_settle_request_carries_used_amount() {
+        let verify = serde_json::json!({
+            "x402Version": 2,
+            "paymentPayload": {},
+            "paymentRequirements": { "scheme": "upto", "amount": "5000" }
+        });
+        let request = proto::VerifyRequest::from(serde_json::value::to_raw_value(&verify).unwrap());
+        let settle = with_amount(&request, 120).unwrap();
+        let json: Value = serde_json::from_str(settle.as_str()).unwrap();
+        assert_eq!(json["paymentRequirements"]["amount"], "120");
+        assert_eq!(json["paymentRequirements"]["scheme"], "upto");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_settles_the_maximum() {
+        let metering = Metering {
+            usdc_per_unit: 1,
+            usage: UsageSource::Bytes,
+        };
+        let chunks =
+            [MAX_METERED_BODY_BYTES, 10, 10].map(|len| Ok::<_, Infallible>(vec![b'x'; len]));
+        let response = Response::new(Body::from_stream(stream::iter(chunks)));
+        let (response, amount) = meter_response(&metering, response, 5000).await.unwrap();
+        assert_eq!(amount, 5000);
+        assert!(!response.headers().contains_key(METERED_UNITS_HEADER));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), MAX_METERED_BODY_BYTES + 20);
+
+        let response = Response::new(Body::from("hello"));
+        let (response, amount) = meter_response(&metering, response, 5000).await.unwrap();
+        assert_eq!(amount, 5);
+        assert_eq!(response.headers()[METERED_UNITS_HEADER], "5");
+    }
+}
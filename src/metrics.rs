@@ -0,0 +1,357 @@
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::{sync::Arc, time::Duration, time::Instant};
+use tracing::error;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Route label of requests that matched no configured route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Request latency buckets in seconds; model upstreams can take minutes.
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Signing latency buckets in seconds, from 25µs to about 50ms.
+const SIGNING_BUCKETS: &[f64] = &[
+    0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05,
+];
+
+/// What became of a payment the gateway was handed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentOutcome {
+    /// The facilitator accepted the payment; the upstream is called next.
+    Verified,
+    Settled,
+    /// Rejected by the facilitator, at verification or at settlement.
+    Failed,
+}
+
+impl PaymentOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Verified => "verified",
+            Self::Settled => "settled",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Why a request never got an answer from its upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamError {
+    /// Every backend of the upstream is ejected or failing its health check.
+    Unavailable,
+    /// The request or WebSocket handshake could not be sent.
+    Connect,
+    /// The response body broke off.
+    Body,
+}
+
+impl UpstreamError {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unavailable => "unavailable",
+            Self::Connect => "connect",
+            Self::Body => "body",
+        }
+    }
+}
+
+/// Prometheus metrics of the gateway. One set lives for the whole process,
+/// across config reloads, and is served on the admin port.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    challenges: IntCounterVec,
+    payments: IntCounterVec,
+    revenue: IntCounterVec,
+    credit: IntCounterVec,
+    passes: IntCounterVec,
+    upstream_errors: IntCounterVec,
+    signing_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels)
+                .expect("metric options are valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric names are unique");
+            counter
+        };
+
+        let requests = counter(
+            "gateway_requests_total",
+            "Requests answered, by route pattern, status and upstream.",
+            &["route", "status", "upstream"],
+        );
+        let challenges = counter(
+            "gateway_payment_challenges_total",
+            "402 Payment Required responses, by route pattern.",
+            &["route"],
+        );
+        let payments = counter(
+            "gateway_payments_total",
+            "Payments verified, settled or failed, by network.",
+            &["network", "outcome"],
+        );
+        let revenue = counter(
+            "gateway_revenue_usdc_micros_total",
+            "Settled USDC in microunits, by route pattern and network.",
+            &["route", "network"],
+        );
+        let credit = counter(
+            "gateway_credit_drawn_usdc_micros_total",
+            "Prepaid credit drawn in USDC microunits, by route pattern.",
+            &["route"],
+        );
+        let passes = counter(
+            "gateway_access_pass_requests_total",
+            "Requests admitted with an access pass instead of a payment, by route pattern.",
+            &["route"],
+        );
+        let upstream_errors = counter(
+            "gateway_upstream_errors_total",
+            "Requests that got no answer from their upstream, by upstream and kind.",
+            &["upstream", "kind"],
+        );
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "gateway_request_duration_seconds",
+                "Time to response headers, by route pattern, status and upstream.",
+            )
+            .buckets(REQUEST_BUCKETS.to_vec()),
+            &["route", "status", "upstream"],
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(request_duration.clone()))
+            .expect("metric names are unique");
+
+        let signing_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "gateway_signing_duration_seconds",
+                "Time to sign a whole response once its body is hashed.",
+            )
+            .buckets(SIGNING_BUCKETS.to_vec()),
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(signing_duration.clone()))
+            .expect("metric names are unique");
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            challenges,
+            payments,
+            revenue,
+            credit,
+            passes,
+            upstream_errors,
+            signing_duration,
+        }
+    }
+
+    /// A request answered with `status` after `elapsed`. Streamed responses
+    /// count up to their headers.
+    pub fn request(&self, route: &str, status: StatusCode, upstream: &str, elapsed: Duration) {
+        let labels = [route, status.as_str(), upstream];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn challenge(&self, route: &str) {
+        self.challenges.with_label_values(&[route]).inc();
+    }
+
+    pub fn payment(&self, network: &str, outcome: PaymentOutcome) {
+        self.payments
+            .with_label_values(&[network, outcome.as_str()])
+            .inc();
+    }
+
+    /// `amount` USDC microunits settled for a request to `route`.
+    pub fn revenue(&self, route: &str, network: &str, amount: u64) {
+        self.revenue
+            .with_label_values(&[route, network])
+            .inc_by(amount);
+    }
+
+    /// `amount` USDC microunits of prepaid credit drawn by a request to
+    /// `route`.
+    pub fn credit(&self, route: &str, amount: u64) {
+        self.credit.with_label_values(&[route]).inc_by(amount);
+    }
+
+    /// A request to `route` admitted with an access pass.
+    pub fn pass(&self, route: &str) {
+        self.passes.with_label_values(&[route]).inc();
+    }
+
+    pub fn upstream_error(&self, upstream: &str, error: UpstreamError) {
+        self.upstream_errors
+            .with_label_values(&[upstream, error.as_str()])
+            .inc();
+    }
+
+    /// Run `sign` and record how long it took.
+    pub fn time_signing<T>(&self, sign: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let signature = sign();
+        self.signing_duration
+            .observe(started.elapsed().as_secs_f64());
+        signature
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer).expect("the text format is UTF-8"))
+    }
+}
+
+/// The admin server: `GET /metrics` and nothing else.
+pub fn admin_router(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route(METRICS_PATH, get(serve_metrics))
+        .with_state(metrics)
+}
+
+/// Answer `401` to any request to `router` without
+/// `Authorization: Bearer <token>`.
+pub fn require_bearer(router: Router, token: &str) -> Router {
+    let expected: Arc<[u8]> = format!("Bearer {}", token).into_bytes().into();
+    router.layer(middleware::from_fn_with_state(expected, check_bearer))
+}
+
+async fn check_bearer(State(expected): State<Arc<[u8]>>, request: Request, next: Next) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map_or(&[][..], |value| value.as_bytes());
+    // Compare without an early exit so timing does not reveal the token
+    let matches = given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn serve_metrics(State(metrics): State<Arc<Metrics>>) -> Response {
+    match metrics.render() {
+        Ok(text) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], text).into_response(),
+        Err(e) => {
+            error!(error = %e, "Failed to encode metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_admin_router_serves_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.request(
+            "/api/**",
+            StatusCode::OK,
+            "ollama",
+            Duration::from_millis(20),
+        );
+        metrics.payment("eip155:84532", PaymentOutcome::Settled);
+        metrics.revenue("/api/**", "eip155:84532", 1000);
+        metrics.revenue("/api/**", "eip155:84532", 500);
+        metrics.upstream_error("ollama", UpstreamError::Connect);
+        metrics.time_signing(|| ());
+
+        let response = admin_router(metrics)
+            .oneshot(Request::get(METRICS_PATH).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            prometheus::TEXT_FORMAT
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = std::str::from_utf8(&body).unwrap();
+        assert!(text.contains(
+            r#"gateway_requests_total{route="/api/**",status="200",upstream="ollama"} 1"#
+        ));
+        assert!(text.contains(
+            r#"gateway_request_duration_seconds_bucket{route="/api/**",status="200",upstream="ollama",le="0.025"} 1"#
+        ));
+        assert!(
+            text.contains(r#"gateway_payments_total{network="eip155:84532",outcome="settled"} 1"#)
+        );
+        assert!(text.contains(
+            r#"gateway_revenue_usdc_micros_total{network="eip155:84532",route="/api/**"} 1500"#
+        ));
+        assert!(
+            text.contains(r#"gateway_upstream_errors_total{kind="connect",upstream="ollama"} 1"#)
+        );
+        assert!(text.contains("gateway_signing_duration_seconds_count 1"));
+    }
+
+    #[tokio::test]
+    async fn test_require_bearer() {
+        let router = require_bearer(admin_router(Arc::new(Metrics::new())), "s3cret");
+        let status = |authorization: Option<&str>| {
+            let mut request = Request::get(METRICS_PATH);
+            if let Some(value) = authorization {
+                request = request.header(header::AUTHORIZATION, value);
+            }
+            let router = router.clone();
+            async move {
+                router
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer wrong")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("s3cret")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer s3cret")).await, StatusCode::OK);
+    }
+}
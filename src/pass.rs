@@ -0,0 +1,174 @@
+use crate::receipt::settlement_field;
+use crate::signing::{sign_digest, unix_now};
+use crate::verify::{VerifyError, recover_digest};
+use http::HeaderValue;
+use k256::ecdsa::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use x402_types::{proto::SettleResponse, util::Base64Bytes};
+
+/// Response header with a newly issued pass, and the request header a pass
+/// is presented in.
+pub const ACCESS_PASS_HEADER: &str = "X-Access-Pass";
+pub const PASS_VERSION: &str = "oyster-pass-v1";
+
+const PASS_DOMAIN_V1: &[u8] = b"oyster-pass-v1\0";
+
+/// A bearer token for time-limited access to some routes, issued after one
+/// settled payment and signed with the gateway key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessPass {
+    pub version: String,
+    pub payer: String,
+    /// CAIP-2 network the payment settled on, e.g. `eip155:84532`.
+    pub network: String,
+    /// Settlement transaction hash.
+    pub transaction: String,
+    /// Route patterns the pass opens, as configured, e.g. `/api/**`.
+    pub scope: Vec<String>,
+    /// Unix seconds at settlement.
+    pub issued_at: u64,
+    /// Unix seconds from which the pass is no longer accepted.
+    pub expires_at: u64,
+    /// `r || s || v` as hex, like `X-Signature`.
+    pub signature: String,
+}
+
+impl AccessPass {
+    /// Sign a pass for `scope`, valid for `duration_secs` from now, after a
+    /// successful settlement.
+    pub fn issue(
+        settlement: &SettleResponse,
+        scope: Vec<String>,
+        duration_secs: u64,
+        signing_key: &SigningKey,
+    ) -> Result<Self, String> {
+        if settlement.0["success"] != true {
+            return Err("settlement did not succeed".to_string());
+        }
+        let field = |name| settlement_field(settlement, name);
+        let issued_at = unix_now();
+        let mut pass = Self {
+            version: PASS_VERSION.to_string(),
+            payer: field("payer")?,
+            network: field("network")?,
+            transaction: field("transaction")?,
+            scope,
+            issued_at,
+            expires_at: issued_at.saturating_add(duration_secs),
+            signature: String::new(),
+        };
+        pass.signature = sign_digest(signing_key, pass.digest());
+        Ok(pass)
+    }
+
+    /// Hash of the signed pass message:
+    ///
+    /// ```text
+    /// keccak256("oyster-pass-v1\0" ||
+    ///           u32be(len(payer)) || payer ||
+    ///           u32be(len(network)) || network ||
+    ///           u32be(len(transaction)) || transaction ||
+    ///           u32be(count(scope)) || (u32be(len(route)) || route)... ||
+    ///           u64be(issued_at) || u64be(expires_at))
+    /// ```
+    pub fn digest(&self) -> Keccak256 {
+        let mut hasher = Keccak256::new();
+        hasher.update(PASS_DOMAIN_V1);
+        for field in [&self.payer, &self.network, &self.transaction] {
+            hasher.update((field.len() as u32).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.update((self.scope.len() as u32).to_be_bytes());
+        for route in &self.scope {
+            hasher.update((route.len() as u32).to_be_bytes());
+            hasher.update(route.as_bytes());
+        }
+        hasher.update(self.issued_at.to_be_bytes());
+        hasher.update(self.expires_at.to_be_bytes());
+        hasher
+    }
+
+    /// Whether the pass opens the route with pattern `route` at `now`.
+    pub fn admits(&self, route: &str, now: u64) -> bool {
+        now < self.expires_at && self.scope.iter().any(|scope| scope == route)
+    }
+
+    /// Base64 of the pass JSON, for the [`ACCESS_PASS_HEADER`].
+    pub fn to_header(&self) -> HeaderValue {
+        let json = serde_json::to_vec(self).expect("pass serializes");
+        HeaderValue::from_bytes(Base64Bytes::encode(json).as_ref())
+            .expect("base64 is a valid header value")
+    }
+
+    /// Decode an [`ACCESS_PASS_HEADER`] value.
+    pub fn from_header(value: &[u8]) -> Result<Self, VerifyError> {
+        let json = Base64Bytes::from(value)
+            .decode()
+            .map_err(|e| VerifyError::Message(format!("pass is not base64: {}", e)))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| VerifyError::Message(format!("pass is not valid JSON: {}", e)))
+    }
+
+    /// The key that signed the pass.
+    pub fn recover(&self) -> Result<VerifyingKey, VerifyError> {
+        if self.version != PASS_VERSION {
+            return Err(VerifyError::Message(format!(
+                "unsupported pass version {}",
+                self.version
+            )));
+        }
+        recover_digest(self.digest(), &self.signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settlement() -> SettleResponse {
+        SettleResponse(serde_json::json!({
+            "success": true,
+            "payer": "0x857b06519E91e3A54538791bDbb0E22373e36b66",
+            "transaction": "0x01",
+            "network": "eip155:84532"
+        }))
+    }
+
+    #[test]
+    fn test_pass_recovers_gateway_key() {
+        let key = SigningKey::from_bytes(&[1u8; 32].into()).unwrap();
+        let scope = vec!["/api/**".to_string(), "/models".to_string()];
+        let pass = AccessPass::issue(&settlement(), scope, 3600, &key).unwrap();
+        assert_eq!(pass.payer, "0x857b06519E91e3A54538791bDbb0E22373e36b66");
+        assert_eq!(pass.expires_at, pass.issued_at + 3600);
+
+        let decoded = AccessPass::from_header(pass.to_header().as_bytes()).unwrap();
+        assert_eq!(decoded, pass);
+        assert_eq!(decoded.recover().unwrap(), *key.verifying_key());
+
+        assert!(decoded.admits("/api/**", pass.issued_at));
+        assert!(decoded.admits("/models", pass.expires_at - 1));
+        assert!(!decoded.admits("/models", pass.expires_at));
+        assert!(!decoded.admits("/api/chat", pass.issued_at));
+
+        // Widening the scope or the expiry breaks the signature
+        let mut tampered = decoded.clone();
+        tampered.expires_at += 3600;
+        assert_ne!(tampered.recover().unwrap(), *key.verifying_key());
+        let mut tampered = decoded.clone();
+        tampered.scope.push("/admin".to_string());
+        assert_ne!(tampered.recover().unwrap(), *key.verifying_key());
+    }
+
+    #[test]
+    fn test_pass_needs_settled_transaction() {
+        let key = SigningKey::from_bytes(&[1u8; 32].into()).unwrap();
+        let failed = SettleResponse(serde_json::json!({
+            "success": false,
+            "errorReason": "insufficient_funds",
+            "network": "eip155:84532"
+        }));
+        assert!(AccessPass::issue(&failed, vec!["/api/**".to_string()], 60, &key).is_err());
+    }
+}
@@ -1,17 +1,13 @@
 use crate::config::NetworkConfig;
 use alloy_primitives::Address;
-use std::{str::FromStr, sync::Arc};
-
-use x402_axum::{
-    StaticPriceTags, X402LayerBuilder, X402Middleware, facilitator_client::FacilitatorClient,
-};
-use x402_chain_eip155::{KnownNetworkEip155, V2Eip155Exact};
+use std::str::FromStr;
+use x402_chain_eip155::{KnownNetworkEip155, V2Eip155Exact, V2Eip155Upto};
 use x402_chain_solana::{KnownNetworkSolana, V2SolanaExact};
 use x402_types::{networks::USDC, proto::v2::PriceTag as V2PriceTag};
 
 /// Get USDC deployment for EVM networks
-fn get_evm_usdc(network: &str) -> x402_chain_eip155::chain::Eip155TokenDeployment {
-    match network {
+pub fn get_evm_usdc(network: &str) -> Option<x402_chain_eip155::chain::Eip155TokenDeployment> {
+    let usdc = match network {
         // Mainnets
         "base" => USDC::base(),
         "polygon" => USDC::polygon(),
@@ -28,68 +24,98 @@ fn get_evm_usdc(network: &str) -> x402_chain_eip155::chain::Eip155TokenDeploymen
         "avalanche-fuji" | "avalanche_fuji" => USDC::avalanche_fuji(),
         "sei-testnet" | "sei_testnet" => USDC::sei_testnet(),
         "celo-sepolia" | "celo_sepolia" => USDC::celo_sepolia(),
-        _ => panic!("Unsupported EVM network: {}", network),
-    }
+        _ => return None,
+    };
+    Some(usdc)
 }
 
 /// Get USDC deployment for Solana networks
-fn get_solana_usdc(network: &str) -> x402_chain_solana::chain::SolanaTokenDeployment {
+pub fn get_solana_usdc(network: &str) -> Option<x402_chain_solana::chain::SolanaTokenDeployment> {
     match network {
-        "solana" | "solana-mainnet" => USDC::solana(),
-        "solana-devnet" | "solana_devnet" => USDC::solana_devnet(),
-        _ => panic!("Unsupported Solana network: {}", network),
+        "solana" | "solana-mainnet" => Some(USDC::solana()),
+        "solana-devnet" | "solana_devnet" => Some(USDC::solana_devnet()),
+        _ => None,
+    }
+}
+
+/// Parse EVM address from string, enforcing the EIP-55 checksum on mixed-case input
+pub fn parse_evm_address(address: &str) -> Result<Address, String> {
+    let parsed: Address = address
+        .parse()
+        .map_err(|e| format!("Invalid EVM address: {}", e))?;
+
+    let hex_part = address.strip_prefix("0x").unwrap_or(address);
+    let mixed_case = hex_part.chars().any(|c| c.is_ascii_lowercase())
+        && hex_part.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case {
+        let checksummed = parsed.to_checksum(None);
+        if checksummed[2..] != *hex_part {
+            return Err(format!(
+                "Invalid EIP-55 checksum (expected {})",
+                checksummed
+            ));
+        }
     }
+    Ok(parsed)
 }
 
 /// Parse Solana address from string
-fn parse_solana_address(address: &str) -> x402_chain_solana::chain::Address {
-    x402_chain_solana::chain::Address::from_str(address).expect("Invalid Solana address")
+pub fn parse_solana_address(address: &str) -> Result<x402_chain_solana::chain::Address, String> {
+    x402_chain_solana::chain::Address::from_str(address)
+        .map_err(|e| format!("Invalid Solana address: {}", e))
 }
 
-/// Build price tags layer for a specific route
-pub fn build_price_layer(
-    x402: &X402Middleware<Arc<FacilitatorClient>>,
-    networks: &[NetworkConfig],
-    usdc_amount: u64,
-) -> X402LayerBuilder<StaticPriceTags<V2PriceTag>, Arc<FacilitatorClient>> {
-    // Collect all price tags first
-    let mut tags: Vec<V2PriceTag> = Vec::new();
-
-    for net_config in networks {
-        let tag = match net_config {
+/// "exact" price tags for every configured network.
+///
+/// The networks must have passed [`crate::config::Config::validate`].
+pub fn exact_price_tags(networks: &[NetworkConfig], usdc_amount: u64) -> Vec<V2PriceTag> {
+    networks
+        .iter()
+        .map(|net_config| match net_config {
             NetworkConfig::Evm {
                 network,
                 payment_address,
             } => {
-                let address: Address = payment_address.parse().expect("Invalid EVM address");
-                let usdc = get_evm_usdc(network);
+                let address =
+                    parse_evm_address(payment_address).expect("config validated EVM address");
+                let usdc = get_evm_usdc(network).expect("config validated EVM network");
                 V2Eip155Exact::price_tag(address, usdc.amount(usdc_amount))
             }
             NetworkConfig::Solana {
                 network,
                 payment_address,
             } => {
-                let solana_addr = parse_solana_address(payment_address);
-                let usdc = get_solana_usdc(network);
+                let solana_addr =
+                    parse_solana_address(payment_address).expect("config validated Solana address");
+                let usdc = get_solana_usdc(network).expect("config validated Solana network");
                 V2SolanaExact::price_tag(solana_addr, usdc.amount(usdc_amount))
             }
-        };
-        tags.push(tag);
-    }
-
-    if tags.is_empty() {
-        panic!("At least one network must be configured");
-    }
-
-    // Initialize builder with first tag
-    let mut builder = x402.with_price_tag(tags[0].clone());
-
-    // Add remaining tags
-    for tag in tags.into_iter().skip(1) {
-        builder = builder.with_price_tag(tag);
-    }
+        })
+        .collect()
+}
 
-    builder
+/// "upto" price tags authorizing at most `max_usdc_amount`.
+///
+/// Only EVM networks have an upto scheme; Solana networks are left out.
+pub fn upto_price_tags(networks: &[NetworkConfig], max_usdc_amount: u64) -> Vec<V2PriceTag> {
+    networks
+        .iter()
+        .filter_map(|net_config| match net_config {
+            NetworkConfig::Evm {
+                network,
+                payment_address,
+            } => {
+                let address =
+                    parse_evm_address(payment_address).expect("config validated EVM address");
+                let usdc = get_evm_usdc(network).expect("config validated EVM network");
+                Some(V2Eip155Upto::price_tag(
+                    address,
+                    usdc.amount(max_usdc_amount),
+                ))
+            }
+            NetworkConfig::Solana { .. } => None,
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -98,12 +124,12 @@ mod tests {
 
     #[test]
     fn test_get_evm_usdc_known_mainnets() {
-        // Should not panic for any supported mainnet
+        // Every supported mainnet has a deployment
         let networks = [
             "base", "polygon", "avalanche", "sei", "xdc", "xrpl-evm", "peaq", "iotex", "celo",
         ];
         for network in &networks {
-            let _usdc = get_evm_usdc(network);
+            assert!(get_evm_usdc(network).is_some(), "{}", network);
         }
     }
 
@@ -117,7 +143,7 @@ mod tests {
             "celo-sepolia",
         ];
         for network in &networks {
-            let _usdc = get_evm_usdc(network);
+            assert!(get_evm_usdc(network).is_some(), "{}", network);
         }
     }
 
@@ -132,41 +158,73 @@ mod tests {
             "celo_sepolia",
         ];
         for alias in &aliases {
-            let _usdc = get_evm_usdc(alias);
+            assert!(get_evm_usdc(alias).is_some(), "{}", alias);
         }
     }
 
     #[test]
-    #[should_panic(expected = "Unsupported EVM network")]
     fn test_get_evm_usdc_unsupported_network() {
-        get_evm_usdc("unknown-chain");
+        assert!(get_evm_usdc("unknown-chain").is_none());
     }
 
     #[test]
     fn test_get_solana_usdc_known_networks() {
         let networks = ["solana", "solana-mainnet", "solana-devnet", "solana_devnet"];
         for network in &networks {
-            let _usdc = get_solana_usdc(network);
+            assert!(get_solana_usdc(network).is_some(), "{}", network);
         }
     }
 
     #[test]
-    #[should_panic(expected = "Unsupported Solana network")]
     fn test_get_solana_usdc_unsupported_network() {
-        get_solana_usdc("solana-unknown");
+        assert!(get_solana_usdc("solana-unknown").is_none());
     }
 
     #[test]
     fn test_parse_solana_address_valid() {
         // A valid base58 Solana public key (32 bytes)
-        let addr = parse_solana_address("EGBQqKn968sVv5cQh5Cr72pSTHfxsuzq7o7asqYB5uEV");
+        let addr = parse_solana_address("EGBQqKn968sVv5cQh5Cr72pSTHfxsuzq7o7asqYB5uEV").unwrap();
         let addr_str = addr.to_string();
         assert_eq!(addr_str, "EGBQqKn968sVv5cQh5Cr72pSTHfxsuzq7o7asqYB5uEV");
     }
 
     #[test]
-    #[should_panic(expected = "Invalid Solana address")]
     fn test_parse_solana_address_invalid() {
-        parse_solana_address("not-a-valid-solana-address!!!");
+        let err = parse_solana_address("not-a-valid-solana-address!!!").unwrap_err();
+        assert!(err.contains("Invalid Solana address"));
+    }
+
+    #[test]
+    fn test_parse_evm_address_checksum() {
+        let checksummed = "0xd232A8b0F63a555d054134f67b298ffE955f3BAf";
+        assert!(parse_evm_address(checksummed).is_ok());
+        // Single-case addresses carry no checksum and are accepted as-is
+        assert!(parse_evm_address(&checksummed.to_lowercase()).is_ok());
+        assert!(parse_evm_address(&format!("0x{}", checksummed[2..].to_uppercase())).is_ok());
+
+        let err = parse_evm_address("0xD232a8b0F63a555d054134f67b298ffE955f3BAf").unwrap_err();
+        assert!(err.contains("checksum"));
+
+        let err = parse_evm_address("0x1234").unwrap_err();
+        assert!(err.contains("Invalid EVM address"));
+    }
+
+    #[test]
+    fn test_upto_price_tags_skip_solana() {
+        let networks = [
+            NetworkConfig::Evm {
+                network: "base-sepolia".to_string(),
+                payment_address: "0xd232A8b0F63a555d054134f67b298ffE955f3BAf".to_string(),
+            },
+            NetworkConfig::Solana {
+                network: "solana-devnet".to_string(),
+                payment_address: "EGBQqKn968sVv5cQh5Cr72pSTHfxsuzq7o7asqYB5uEV".to_string(),
+            },
+        ];
+        let tags = upto_price_tags(&networks, 5000);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].requirements.scheme, "upto");
+        assert_eq!(tags[0].requirements.amount, "5000");
+        assert_eq!(exact_price_tags(&networks, 5000).len(), 2);
     }
 }
@@ -0,0 +1,191 @@
+use crate::signing::{sign_digest, unix_now};
+use crate::verify::{VerifyError, recover_digest};
+use http::HeaderValue;
+use k256::ecdsa::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use x402_types::{proto::SettleResponse, util::Base64Bytes};
+
+pub const RECEIPT_HEADER: &str = "X-Payment-Receipt";
+pub const RECEIPT_VERSION: &str = "oyster-receipt-v1";
+
+const RECEIPT_DOMAIN_V1: &[u8] = b"oyster-receipt-v1\0";
+
+/// A string field of a facilitator settlement response, such as `payer`,
+/// `network` or `transaction`.
+pub fn settlement_field(settlement: &SettleResponse, name: &str) -> Result<String, String> {
+    settlement.0[name]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("settlement response has no {}", name))
+}
+
+/// Keccak256 of the signed message prefix up to and including the request
+/// body. The proxy handler attaches it to its response so a receipt can name
+/// the request it pays for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestDigest(pub [u8; 32]);
+
+/// What was paid for a request, signed with the gateway key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub version: String,
+    pub payer: String,
+    /// CAIP-2 network the payment settled on, e.g. `eip155:84532`.
+    pub network: String,
+    /// Settled amount in USDC microunits.
+    pub amount: u64,
+    /// Settlement transaction hash.
+    pub transaction: String,
+    /// `0x`-prefixed hex of the [`RequestDigest`].
+    pub request_hash: String,
+    /// Unix seconds at settlement.
+    pub timestamp: u64,
+    /// `r || s || v` as hex, like `X-Signature`.
+    pub signature: String,
+}
+
+impl Receipt {
+    /// Sign a receipt for a successful settlement of `amount`.
+    pub fn issue(
+        settlement: &SettleResponse,
+        amount: u64,
+        request: RequestDigest,
+        signing_key: &SigningKey,
+    ) -> Result<Self, String> {
+        if settlement.0["success"] != true {
+            return Err("settlement did not succeed".to_string());
+        }
+        let field = |name| settlement_field(settlement, name);
+        let mut receipt = Self {
+            version: RECEIPT_VERSION.to_string(),
+            payer: field("payer")?,
+            network: field("network")?,
+            amount,
+            transaction: field("transaction")?,
+            request_hash: format!("0x{}", hex::encode(request.0)),
+            timestamp: unix_now(),
+            signature: String::new(),
+        };
+        receipt.signature = sign_digest(signing_key, receipt.digest()?);
+        Ok(receipt)
+    }
+
+    /// Hash of the signed receipt message:
+    ///
+    /// ```text
+    /// keccak256("oyster-receipt-v1\0" ||
+    ///           u32be(len(payer)) || payer ||
+    ///           u32be(len(network)) || network ||
+    ///           u64be(amount) ||
+    ///           u32be(len(transaction)) || transaction ||
+    ///           request_hash || u64be(timestamp))
+    /// ```
+    pub fn digest(&self) -> Result<Keccak256, String> {
+        let request_hash = self
+            .request_hash
+            .strip_prefix("0x")
+            .and_then(|hex| hex::decode(hex).ok())
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or("request_hash must be 32 bytes of 0x-prefixed hex")?;
+
+        let mut hasher = Keccak256::new();
+        hasher.update(RECEIPT_DOMAIN_V1);
+        for field in [&self.payer, &self.network] {
+            hasher.update((field.len() as u32).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.update(self.amount.to_be_bytes());
+        hasher.update((self.transaction.len() as u32).to_be_bytes());
+        hasher.update(self.transaction.as_bytes());
+        hasher.update(request_hash);
+        hasher.update(self.timestamp.to_be_bytes());
+        Ok(hasher)
+    }
+
+    /// Base64 of the receipt JSON, for the [`RECEIPT_HEADER`].
+    pub fn to_header(&self) -> HeaderValue {
+        let json = serde_json::to_vec(self).expect("receipt serializes");
+        HeaderValue::from_bytes(Base64Bytes::encode(json).as_ref())
+            .expect("base64 is a valid header value")
+    }
+
+    /// Decode a [`RECEIPT_HEADER`] value.
+    pub fn from_header(value: &[u8]) -> Result<Self, VerifyError> {
+        let json = Base64Bytes::from(value)
+            .decode()
+            .map_err(|e| VerifyError::Message(format!("receipt is not base64: {}", e)))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| VerifyError::Message(format!("receipt is not valid JSON: {}", e)))
+    }
+
+    /// The key that signed the receipt.
+    pub fn recover(&self) -> Result<VerifyingKey, VerifyError> {
+        if self.version != RECEIPT_VERSION {
+            return Err(VerifyError::Message(format!(
+                "unsupported receipt version {}",
+                self.version
+            )));
+        }
+        recover_digest(
+            self.digest().map_err(VerifyError::Message)?,
+            &self.signature,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+
+    fn settlement() -> SettleResponse {
+        SettleResponse(serde_json::json!({
+            "success": true,
+            "payer": "0x857b06519E91e3A54538791bDbb0E22373e36b66",
+            "transaction": "0x01",
+            "network": "eip155:84532"
+        }))
+    }
+
+    #[test]
+    fn test_receipt_recovers_gateway_key() {
+        let key = SigningKey::from_bytes(&[1u8; 32].into()).unwrap();
+        let receipt = Receipt::issue(&settlement(), 120, RequestDigest([7u8; 32]), &key).unwrap();
+        assert_eq!(receipt.payer, "0x857b06519E91e3A54538791bDbb0E22373e36b66");
+        assert_eq!(receipt.network, "eip155:84532");
+        assert_eq!(receipt.transaction, "0x01");
+        assert_eq!(receipt.request_hash, format!("0x{}", "07".repeat(32)));
+
+        let decoded = Receipt::from_header(receipt.to_header().as_bytes()).unwrap();
+        assert_eq!(decoded, receipt);
+        assert_eq!(decoded.recover().unwrap(), *key.verifying_key());
+
+        let bytes = hex::decode(&decoded.signature).unwrap();
+        let signature = Signature::from_slice(&bytes[..64]).unwrap();
+        let recovery_id = RecoveryId::from_byte(bytes[64] - 27).unwrap();
+        let recovered =
+            VerifyingKey::recover_from_digest(decoded.digest().unwrap(), &signature, recovery_id)
+                .unwrap();
+        assert_eq!(&recovered, key.verifying_key());
+
+        // Any change to a signed field changes the digest
+        let mut tampered = decoded.clone();
+        tampered.amount = 121;
+        assert_ne!(
+            tampered.digest().unwrap().finalize(),
+            decoded.digest().unwrap().finalize()
+        );
+    }
+
+    #[test]
+    fn test_receipt_needs_settled_transaction() {
+        let key = SigningKey::from_bytes(&[1u8; 32].into()).unwrap();
+        let failed = SettleResponse(serde_json::json!({
+            "success": false,
+            "errorReason": "insufficient_funds",
+            "network": "eip155:84532"
+        }));
+        assert!(Receipt::issue(&failed, 10, RequestDigest([0u8; 32]), &key).is_err());
+    }
+}
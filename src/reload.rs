@@ -0,0 +1,1935 @@
+use crate::body_pricing::{MAX_PRICED_BODY_BYTES, PRICE_BREAKDOWN_HEADER, price_request};
+use crate::config::{
+    Balance, BodyPriceRule, Config, Credits, NetworkConfig, ProtectedRoute, UnlistedMethods,
+    Upstream, UpstreamTarget, read_config,
+};
+use crate::credits::account;
+use crate::handlers::proxy_request;
+use crate::metrics::UNMATCHED_ROUTE;
+use crate::routes::{RoutePattern, RouteTable, has_encoded_separator, normalize_path};
+use crate::settlement::{Settler, top_up_request};
+use crate::state::AppState;
+use crate::upstream::{Forward, Upstreams};
+use crate::websocket::WebSocketSession;
+use crate::well_known::{WELL_KNOWN_PATH, oyster_info};
+use axum::{
+    Extension, Router,
+    body::{Body, to_bytes},
+    extract::State,
+    handler::Handler,
+    http::{HeaderValue, Method, Request, StatusCode, Uri, header},
+    response::{IntoResponse, Response},
+    routing::{MethodRouter, any, get},
+};
+use http_body_util::LengthLimitError;
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tower::ServiceExt;
+use tower_http::cors::{Any, CorsLayer};
+use tracing::{error, info, warn};
+use x402_axum::facilitator_client::FacilitatorClient;
+
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Build the proxy router for one config generation.
+pub fn build_router(state: Arc<AppState>) -> Result<Router, Box<dyn std::error::Error>> {
+    let config = &state.config;
+
+    let upstreams = Upstreams::new(config, &state.http_client, &state.pools);
+    let facilitator = Arc::new(FacilitatorClient::try_from(
+        config.facilitator_url.as_str(),
+    )?);
+
+    let charge = |route_config: &ProtectedRoute, amount: u64| -> Arc<Charge> {
+        Arc::new(Charge {
+            base: amount,
+            rules: route_config.body_pricing.clone(),
+            settler: Settler::new(facilitator.clone(), route_config, &state),
+            session: WebSocketSession::from(&route_config.websocket),
+            state: state.clone(),
+        })
+    };
+
+    // Add protected routes with V2 price tags (all configured networks)
+    let mut routes = Vec::new();
+    for route_config in &config.protected_routes {
+        let pattern = RoutePattern::parse(&route_config.path)?;
+
+        let mut methods = HashMap::new();
+        for (name, &amount) in &route_config.methods {
+            let method = Method::from_bytes(name.to_ascii_uppercase().as_bytes())?;
+            info!(route = %route_config.path, method = %method, amount, "Registering PROTECTED route");
+            methods.insert(method, charge(route_config, amount));
+        }
+
+        let unlisted = match (route_config.usdc_amount, route_config.unlisted_methods) {
+            (Some(amount), _) => {
+                info!(route = %route_config.path, amount, "Registering PROTECTED route");
+                Unlisted::Paid(charge(route_config, amount))
+            }
+            (None, Some(UnlistedMethods::Reject)) => {
+                let mut allowed: Vec<&str> = methods.keys().map(Method::as_str).collect();
+                allowed.sort_unstable();
+                let allow = allowed.join(", ");
+                Unlisted::Reject(HeaderValue::from_str(&allow)?)
+            }
+            (None, Some(UnlistedMethods::Free) | None) => Unlisted::Free,
+        };
+        if !route_config.body_pricing.is_empty() {
+            info!(route = %route_config.path, rules = route_config.body_pricing.len(), "Route price depends on the request body");
+        }
+        if let Some(metering) = &route_config.metering {
+            info!(route = %route_config.path, usdc_per_unit = metering.usdc_per_unit, usage = ?metering.usage, "Route settles metered usage with the upto scheme (EVM networks only)");
+        }
+        let forward = upstreams.forward(config, &route_config.target);
+        routes.push((
+            pattern,
+            Route::Priced(PricedRoute {
+                methods,
+                unlisted,
+                forward,
+            }),
+        ));
+    }
+    for route_config in &config.free_routes {
+        info!(route = %route_config.path, target = %describe_target(&route_config.target), "Registering FREE route");
+        let forward = upstreams.forward(config, &route_config.target);
+        routes.push((
+            RoutePattern::parse(&route_config.path)?,
+            Route::Free(forward),
+        ));
+    }
+
+    let table = RouteTable::new(routes);
+    for (winner, other) in table.overlaps() {
+        info!(pattern = %winner, overlaps = %other, "Overlapping routes; the first pattern wins where both match");
+    }
+    let table = Arc::new(table);
+    let fallback = upstreams.forward(config, &UpstreamTarget::default());
+
+    // All other routes are free — proxy without payment
+    let free: MethodRouter = any(proxy_request).with_state(state.clone());
+
+    let mut router =
+        Router::new().route(WELL_KNOWN_PATH, get(oyster_info).with_state(state.clone()));
+
+    // The gateway answers the credit routes itself; they take precedence
+    // over configured routes
+    if let (Some(credits), Some(store)) = (&config.credits, &state.credits) {
+        info!(route = %credits.top_up_path, amount = credits.top_up_usdc_amount, account = %credits.account_path, "Registering credit TOP-UP route");
+        let settler = Arc::new(Settler::top_up(
+            facilitator.clone(),
+            credits,
+            &state,
+            store.clone(),
+        ));
+        let amount = credits.top_up_usdc_amount;
+        let top_up = any(move |req: Request<Body>| async move {
+            let description = "Prepaid credit".to_string();
+            settler
+                .call(amount, description, any(top_up_request), req)
+                .await
+        });
+        router = router.route(&credits.top_up_path, top_up).route(
+            &credits.account_path,
+            get(account).with_state(store.clone()),
+        );
+    } else if config.credits.is_some() {
+        warn!("Credits take effect after a restart, which opens the credit store");
+    }
+    let metrics = state.metrics.clone();
+
+    Ok(router.fallback(move |mut req: Request<Body>| {
+        let table = table.clone();
+        let free = free.clone();
+        let fallback = fallback.clone();
+        let metrics = metrics.clone();
+        async move {
+            let started = Instant::now();
+            // Match and forward the canonical path, so `..` or escapes cannot
+            // reach a protected route through a pattern that misses it
+            if has_encoded_separator(req.uri().path()) {
+                warn!(path = %req.uri().path(), "Rejecting request with an encoded separator in its path");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            if let Err(e) = normalize_uri(&mut req) {
+                warn!(error = %e, path = %req.uri().path(), "Rejecting request with unusable path");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+            let (pattern, route) = match table.find(req.uri().path()) {
+                Some((pattern, route)) => (pattern, Some(route)),
+                None => (UNMATCHED_ROUTE, None),
+            };
+            let forward = match route {
+                Some(Route::Priced(route)) => route.forward.clone(),
+                Some(Route::Free(forward)) => forward.clone(),
+                None => fallback,
+            };
+            let upstream = forward.upstream().to_string();
+            req.extensions_mut().insert(forward);
+
+            let response = match route {
+                Some(Route::Priced(route)) => match route.methods.get(req.method()) {
+                    Some(charge) => charge.call(req).await,
+                    None => match &route.unlisted {
+                        Unlisted::Paid(charge) => charge.call(req).await,
+                        Unlisted::Free => call(free, req).await,
+                        Unlisted::Reject(allow) => (
+                            StatusCode::METHOD_NOT_ALLOWED,
+                            [(header::ALLOW, allow.clone())],
+                        )
+                            .into_response(),
+                    },
+                },
+                Some(Route::Free(_)) | None => call(free, req).await,
+            };
+            metrics.request(pattern, response.status(), &upstream, started.elapsed());
+            response
+        }
+    }))
+}
+
+/// What the routing table holds for a configured path pattern.
+enum Route {
+    Priced(PricedRoute),
+    /// A free route sent to its own upstream.
+    Free(Forward),
+}
+
+/// Paid services of one protected route, picked by request method.
+struct PricedRoute {
+    methods: HashMap<Method, Arc<Charge>>,
+    unlisted: Unlisted,
+    forward: Forward,
+}
+
+/// What a protected route does with methods that have no price of their own.
+enum Unlisted {
+    Paid(Arc<Charge>),
+    Free,
+    /// Answer `405` with this `Allow` header.
+    Reject(HeaderValue),
+}
+
+/// How a request to a protected route is charged.
+struct Charge {
+    base: u64,
+    rules: Vec<BodyPriceRule>,
+    settler: Settler,
+    session: WebSocketSession,
+    state: Arc<AppState>,
+}
+
+impl Charge {
+    /// Price the request, reading its body if there are body rules, and
+    /// charge that price. With metering the price is only the maximum the
+    /// client authorizes.
+    async fn call(&self, req: Request<Body>) -> Response {
+        let (req, breakdown) = if self.rules.is_empty() {
+            (req, None)
+        } else {
+            let (parts, body) = req.into_parts();
+            let body = match to_bytes(body, MAX_PRICED_BODY_BYTES).await {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!(error = %e, "Could not read request body for pricing");
+                    let too_large = std::error::Error::source(&e)
+                        .is_some_and(|source| source.is::<LengthLimitError>());
+                    return if too_large {
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    }
+                    .into_response();
+                }
+            };
+            let breakdown = match price_request(self.base, &self.rules, &body) {
+                Ok(breakdown) => breakdown,
+                Err(e) => {
+                    warn!(error = %e, "Could not price request body");
+                    return (StatusCode::BAD_REQUEST, e).into_response();
+                }
+            };
+            (
+                Request::from_parts(parts, Body::from(body)),
+                Some(breakdown),
+            )
+        };
+        let total = breakdown.as_ref().map_or(self.base, |b| b.total);
+        let description = breakdown
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+
+        let mut response = if total == 0 {
+            call(any(proxy_request).with_state(self.state.clone()), req).await
+        } else {
+            let inner =
+                any(proxy_request.layer(Extension(self.session))).with_state(self.state.clone());
+            self.settler.call(total, description, inner, req).await
+        };
+
+        if let Some(breakdown) = breakdown {
+            let json = serde_json::to_string(&breakdown).expect("breakdown serializes");
+            if let Ok(value) = HeaderValue::from_str(&json) {
+                response.headers_mut().insert(PRICE_BREAKDOWN_HEADER, value);
+            }
+        }
+        response
+    }
+}
+
+async fn call(service: MethodRouter, req: Request<Body>) -> Response {
+    match service.oneshot(req).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+/// Replace the request path with its [`normalize_path`] form, keeping the query.
+fn normalize_uri<B>(req: &mut Request<B>) -> Result<(), axum::http::Error> {
+    let path = normalize_path(req.uri().path());
+    if path == req.uri().path() {
+        return Ok(());
+    }
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    *req.uri_mut() = Uri::from_parts(parts)?;
+    Ok(())
+}
+
+/// The state and router built from one config, swapped together.
+struct Generation {
+    state: Arc<AppState>,
+    router: Router,
+}
+
+/// Holds the live router and replaces it when `CONFIG_PATH` changes or the
+/// process receives SIGHUP. Requests already in flight keep the router they
+/// started with.
+pub struct ConfigReloader {
+    config_path: String,
+    current: RwLock<Generation>,
+}
+
+impl ConfigReloader {
+    pub fn new(config_path: String, state: Arc<AppState>, router: Router) -> Self {
+        Self {
+            config_path,
+            current: RwLock::new(Generation { state, router }),
+        }
+    }
+
+    pub fn router(&self) -> Router {
+        self.current
+            .read()
+            .expect("config generation poisoned")
+            .router
+            .clone()
+    }
+
+    /// Re-read the config file and swap in a new router if it changed.
+    ///
+    /// Returns `Ok(false)` when the file parses to the running config. An
+    /// invalid file is rejected and the running config stays in place.
+    pub fn reload(&self) -> Result<bool, String> {
+        let new_config = read_config(&self.config_path)?;
+        new_config.validate().map_err(|errors| {
+            errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        })?;
+
+        let current_state = self
+            .current
+            .read()
+            .expect("config generation poisoned")
+            .state
+            .clone();
+        let changes = config_changes(&current_state.config, &new_config);
+        if changes.is_empty() {
+            return Ok(false);
+        }
+
+        let state = Arc::new(current_state.with_config(new_config));
+        let router = build_router(state.clone()).map_err(|e| e.to_string())?;
+        *self.current.write().expect("config generation poisoned") = Generation { state, router };
+
+        for change in changes {
+            info!(change = %change, "Config changed");
+        }
+        Ok(true)
+    }
+
+    /// Watch the config file and SIGHUP in the background.
+    pub fn spawn_watchers(self: Arc<Self>) {
+        tokio::spawn(self.clone().watch_file());
+        #[cfg(unix)]
+        tokio::spawn(self.watch_sighup());
+    }
+
+    async fn watch_file(self: Arc<Self>) {
+        let mut last_modified = modified(&self.config_path);
+        let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let modified = modified(&self.config_path);
+            if modified != last_modified {
+                last_modified = modified;
+                self.reload_and_log("file change");
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn watch_sighup(self: Arc<Self>) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!(error = %e, "Failed to install SIGHUP handler; reload on SIGHUP disabled");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            self.reload_and_log("SIGHUP");
+        }
+    }
+
+    fn reload_and_log(&self, trigger: &str) {
+        match self.reload() {
+            Ok(true) => info!(trigger, path = %self.config_path, "Reloaded configuration"),
+            Ok(false) => info!(trigger, path = %self.config_path, "Configuration unchanged"),
+            Err(e) => {
+                error!(trigger, error = %e, "Rejected config reload; keeping the running config")
+            }
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The gateway service: CORS on top of the router of the current config
+/// generation. Preflights are answered here and never reach a route, so
+/// they are not charged.
+pub fn app(reloader: Arc<ConfigReloader>) -> Router {
+    // Allow frontend requests from any origin
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers(Any);
+
+    Router::new()
+        .fallback(dispatch)
+        .layer(cors)
+        .with_state(reloader)
+}
+
+/// Route every request through the router of the current config generation.
+async fn dispatch(State(reloader): State<Arc<ConfigReloader>>, req: Request<Body>) -> Response {
+    match reloader.router().oneshot(req).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+fn describe_network(net: &NetworkConfig) -> String {
+    match net {
+        NetworkConfig::Evm {
+            network,
+            payment_address,
+        } => format!("{} (EVM, {})", network, payment_address),
+        NetworkConfig::Solana {
+            network,
+            payment_address,
+        } => format!("{} (Solana, {})", network, payment_address),
+    }
+}
+
+fn describe_prices(route: &ProtectedRoute) -> String {
+    let mut prices: Vec<String> = route
+        .methods
+        .iter()
+        .map(|(method, amount)| format!("{} {}", method, amount))
+        .collect();
+    match (route.usdc_amount, route.unlisted_methods) {
+        (Some(amount), _) => prices.insert(0, format!("usdc_amount {}", amount)),
+        (None, Some(UnlistedMethods::Reject)) => prices.push("other methods rejected".to_string()),
+        (None, _) => prices.push("other methods free".to_string()),
+    }
+    prices.join(", ")
+}
+
+/// Human-readable list of what differs between two configs.
+pub fn config_changes(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    if old.gateway_port != new.gateway_port {
+        changes.push(format!(
+            "gateway_port: {} -> {} (takes effect after a restart)",
+            old.gateway_port, new.gateway_port
+        ));
+    }
+    if old.admin_port != new.admin_port {
+        let describe = |port: Option<u16>| port.map_or("none".to_string(), |p| p.to_string());
+        changes.push(format!(
+            "admin_port: {} -> {} (takes effect after a restart)",
+            describe(old.admin_port),
+            describe(new.admin_port)
+        ));
+    }
+    if old.ledger_path != new.ledger_path {
+        changes.push(format!(
+            "ledger_path: {} -> {} (takes effect after a restart)",
+            old.ledger_path.as_deref().unwrap_or("none"),
+            new.ledger_path.as_deref().unwrap_or("none")
+        ));
+    }
+    if old.credits != new.credits {
+        let describe = |credits: &Option<Credits>| match credits {
+            Some(c) => format!(
+                "top-up {} at {}, account {}, store {}",
+                c.top_up_usdc_amount, c.top_up_path, c.account_path, c.db_path
+            ),
+            None => "off".to_string(),
+        };
+        let db_path = |credits: &Option<Credits>| credits.as_ref().map(|c| c.db_path.clone());
+        let restart = if db_path(&old.credits) != db_path(&new.credits) {
+            " (the credit store changes after a restart)"
+        } else {
+            ""
+        };
+        changes.push(format!(
+            "credits: {} -> {}{}",
+            describe(&old.credits),
+            describe(&new.credits),
+            restart
+        ));
+    }
+    if old.facilitator_url != new.facilitator_url {
+        changes.push(format!(
+            "facilitator_url: {} -> {}",
+            old.facilitator_url, new.facilitator_url
+        ));
+    }
+    if old.target_api_url != new.target_api_url {
+        changes.push(format!(
+            "target_api_url: {} -> {}",
+            old.target_api_url.as_deref().unwrap_or("none"),
+            new.target_api_url.as_deref().unwrap_or("none")
+        ));
+    }
+    for (name, upstream) in &old.upstreams {
+        match new.upstreams.get(name) {
+            None => changes.push(format!("upstream removed: {}", name)),
+            Some(updated) => {
+                let (before, after) = (describe_upstream(upstream), describe_upstream(updated));
+                if before != after {
+                    changes.push(format!("upstream {}: {} -> {}", name, before, after));
+                }
+                if updated.health_check != upstream.health_check
+                    || updated.passive_health != upstream.passive_health
+                {
+                    changes.push(format!("upstream {}: health checking changed", name));
+                }
+            }
+        }
+    }
+    for (name, upstream) in &new.upstreams {
+        if !old.upstreams.contains_key(name) {
+            changes.push(format!(
+                "upstream added: {} ({})",
+                name,
+                describe_upstream(upstream)
+            ));
+        }
+    }
+    if old.fallback_upstream != new.fallback_upstream {
+        changes.push(format!(
+            "fallback_upstream: {} -> {}",
+            old.fallback_upstream.as_deref().unwrap_or("none"),
+            new.fallback_upstream.as_deref().unwrap_or("none")
+        ));
+    }
+
+    for net in old.networks.iter().filter(|n| !new.networks.contains(n)) {
+        changes.push(format!("network removed: {}", describe_network(net)));
+    }
+    for net in new.networks.iter().filter(|n| !old.networks.contains(n)) {
+        changes.push(format!("network added: {}", describe_network(net)));
+    }
+
+    if old.signing.version != new.signing.version {
+        changes.push(format!(
+            "signature version: {} -> {}",
+            old.signing.version, new.signing.version
+        ));
+    }
+    if old.signing.signed_headers != new.signing.signed_headers {
+        changes.push(format!(
+            "signed_headers: {} -> {}",
+            old.signing.signed_headers.join(", "),
+            new.signing.signed_headers.join(", ")
+        ));
+    }
+
+    for route in &old.protected_routes {
+        match new.protected_routes.iter().find(|r| r.path == route.path) {
+            None => changes.push(format!("route removed: {}", route.path)),
+            Some(updated) => {
+                let (before, after) = (describe_prices(route), describe_prices(updated));
+                if before != after {
+                    changes.push(format!("route {}: {} -> {}", route.path, before, after));
+                }
+                if updated.target != route.target {
+                    changes.push(format!(
+                        "route {}: {} -> {}",
+                        route.path,
+                        describe_target(&route.target),
+                        describe_target(&updated.target)
+                    ));
+                }
+                if updated.body_pricing != route.body_pricing {
+                    changes.push(format!("route {}: body pricing rules changed", route.path));
+                }
+                if updated.metering != route.metering {
+                    changes.push(format!("route {}: metering changed", route.path));
+                }
+                if updated.access_pass != route.access_pass {
+                    changes.push(format!("route {}: access pass changed", route.path));
+                }
+                if updated.settlement(new) != route.settlement(old) {
+                    changes.push(format!("route {}: settlement policy changed", route.path));
+                }
+                if updated.websocket != route.websocket {
+                    changes.push(format!(
+                        "route {}: websocket billing {:?} -> {:?}",
+                        route.path, route.websocket, updated.websocket
+                    ));
+                }
+            }
+        }
+    }
+    for route in &new.protected_routes {
+        if !old.protected_routes.iter().any(|r| r.path == route.path) {
+            changes.push(format!(
+                "route added: {} ({})",
+                route.path,
+                describe_prices(route)
+            ));
+        }
+    }
+
+    for route in &old.free_routes {
+        match new.free_routes.iter().find(|r| r.path == route.path) {
+            None => changes.push(format!("free route removed: {}", route.path)),
+            Some(updated) if updated.target != route.target => changes.push(format!(
+                "free route {}: {} -> {}",
+                route.path,
+                describe_target(&route.target),
+                describe_target(&updated.target)
+            )),
+            Some(_) => {}
+        }
+    }
+    for route in &new.free_routes {
+        if !old.free_routes.iter().any(|r| r.path == route.path) {
+            changes.push(format!(
+                "free route added: {} ({})",
+                route.path,
+                describe_target(&route.target)
+            ));
+        }
+    }
+
+    changes
+}
+
+fn describe_upstream(upstream: &Upstream) -> String {
+    let urls: Vec<&str> = upstream.urls().collect();
+    match upstream.balance {
+        Balance::RoundRobin if urls.len() < 2 => urls.join(", "),
+        balance => format!("{} ({:?})", urls.join(", "), balance),
+    }
+}
+
+fn describe_target(target: &UpstreamTarget) -> String {
+    let mut parts = vec![match &target.upstream {
+        Some(name) => format!("upstream {}", name),
+        None => "fallback upstream".to_string(),
+    }];
+    if let Some(prefix) = &target.strip_prefix {
+        parts.push(format!("strip {}", prefix));
+    }
+    if let Some(prefix) = &target.rewrite_prefix {
+        parts.push(format!("rewrite {}", prefix));
+    }
+    parts.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        AccessPassPolicy, FreeRoute, Metering, SettlementPolicy, StatusPattern, UsageSource,
+    };
+    use crate::credits::CreditStore;
+    use crate::ledger::{Ledger, LedgerEntry};
+    use crate::settlement::PAYMENT_RESPONSE_HEADER;
+    use axum::http::StatusCode;
+    use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+    use sha3::{Digest, Keccak256};
+    use wiremock::matchers::{any as any_request, body_string, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use x402_gateway::credit::{CREDIT_BALANCE_HEADER, CreditAuthorization};
+    use x402_gateway::pass::{ACCESS_PASS_HEADER, AccessPass};
+    use x402_gateway::receipt::{RECEIPT_HEADER, Receipt};
+    use x402_gateway::signing::{SignatureVersion, SigningHasher, sign_digest, unix_now};
+    use x402_gateway::verify::address;
+    use x402_types::util::Base64Bytes;
+
+    fn make_config(target_url: &str, routes: &[(&str, u64)]) -> Config {
+        Config {
+            gateway_port: 3000,
+            // Unreachable; the 402 challenge is still produced without it.
+            facilitator_url: "http://127.0.0.1:1".to_string(),
+            target_api_url: Some(target_url.to_string()),
+            networks: vec![NetworkConfig::Evm {
+                network: "base-sepolia".to_string(),
+                payment_address: "0xd232A8b0F63a555d054134f67b298ffE955f3BAf".to_string(),
+            }],
+            protected_routes: routes
+                .iter()
+                .map(|(path, usdc_amount)| ProtectedRoute {
+                    path: path.to_string(),
+                    usdc_amount: Some(*usdc_amount),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn config_json(config: &Config) -> String {
+        let routes: Vec<String> = config
+            .protected_routes
+            .iter()
+            .map(|r| {
+                format!(
+                    r#"{{ "path": "{}", "usdc_amount": {} }}"#,
+                    r.path,
+                    r.usdc_amount.unwrap()
+                )
+            })
+            .collect();
+        format!(
+            r#"{{
+                "gateway_port": {},
+                "facilitator_url": "{}",
+                "target_api_url": "{}",
+                "networks": [
+                    {{ "type": "evm", "network": "base-sepolia",
+                       "payment_address": "0xd232A8b0F63a555d054134f67b298ffE955f3BAf" }}
+                ],
+                "protected_routes": [{}]
+            }}"#,
+            config.gateway_port,
+            config.facilitator_url,
+            config.target_api_url.as_deref().unwrap(),
+            routes.join(",")
+        )
+    }
+
+    fn make_reloader(config_path: &str, config: Config) -> ConfigReloader {
+        let state = Arc::new(AppState {
+            config,
+            http_client: reqwest::Client::new(),
+            signing_key: SigningKey::from_bytes(&[1u8; 32].into()).unwrap(),
+            attestation_url: None,
+            metrics: Default::default(),
+            pools: Default::default(),
+            ledger: None,
+            credits: None,
+        });
+        let router = build_router(state.clone()).unwrap();
+        ConfigReloader::new(config_path.to_string(), state, router)
+    }
+
+    async fn get_status(reloader: &ConfigReloader, path: &str) -> StatusCode {
+        let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+        reloader.router().oneshot(req).await.unwrap().status()
+    }
+
+    async fn send(reloader: &ConfigReloader, req: Request<Body>) -> Response {
+        reloader.router().oneshot(req).await.unwrap()
+    }
+
+    /// Amount asked for in the 402 challenge, if the request was challenged.
+    async fn required_amount(
+        reloader: &ConfigReloader,
+        method: Method,
+        path: &str,
+    ) -> Option<String> {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        challenged_amount(&send(reloader, req).await)
+    }
+
+    fn challenged_amount(response: &Response) -> Option<String> {
+        let header = response.headers().get("payment-required")?;
+        let json = Base64Bytes::from(header.as_bytes()).decode().unwrap();
+        let challenge: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        Some(
+            challenge["accepts"][0]["amount"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        )
+    }
+
+    /// A `Payment-Signature` header accepting the first offer of a 402
+    /// challenge. The signed part is empty; the mock facilitator approves it.
+    fn payment_for(challenge: &Response) -> String {
+        let header = challenge.headers()["payment-required"].as_bytes();
+        let json = Base64Bytes::from(header).decode().unwrap();
+        let challenge: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let payload = serde_json::json!({
+            "x402Version": 2,
+            "accepted": challenge["accepts"][0],
+            "payload": {},
+        });
+        let encoded = Base64Bytes::encode(serde_json::to_vec(&payload).unwrap());
+        String::from_utf8(encoded.as_ref().to_vec()).unwrap()
+    }
+
+    /// A facilitator that approves every payment and expects `settlements`
+    /// settle calls.
+    async fn mock_facilitator(settlements: u64) -> MockServer {
+        mock_facilitator_for("0x857b06519E91e3A54538791bDbb0E22373e36b66", settlements).await
+    }
+
+    /// [`mock_facilitator`] with payments from `payer`.
+    async fn mock_facilitator_for(payer: &str, settlements: u64) -> MockServer {
+        let facilitator = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/verify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "isValid": true,
+                "payer": payer
+            })))
+            .mount(&facilitator)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/settle"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": true,
+                "payer": payer,
+                "transaction": "0x01",
+                "network": "eip155:84532"
+            })))
+            .expect(settlements)
+            .mount(&facilitator)
+            .await;
+        facilitator
+    }
+
+    #[test]
+    fn test_config_changes() {
+        let old = make_config("http://a", &[("/keep", 10), ("/gone", 20)]);
+        let mut new = make_config("http://b", &[("/keep", 15), ("/new", 30)]);
+        new.gateway_port = 4000;
+        new.signing.version = "oyster-signature-v2".to_string();
+        new.signing.signed_headers.push("x-request-id".to_string());
+
+        let changes = config_changes(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                "gateway_port: 3000 -> 4000 (takes effect after a restart)",
+                "target_api_url: http://a -> http://b",
+                "signature version: oyster-signature-v4 -> oyster-signature-v2",
+                "signed_headers: content-type, content-encoding -> content-type, content-encoding, x-request-id",
+                "route /keep: usdc_amount 10 -> usdc_amount 15",
+                "route removed: /gone",
+                "route added: /new (usdc_amount 30)",
+            ]
+        );
+        assert!(config_changes(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_config_changes_upstreams() {
+        let old = Config {
+            upstreams: [(
+                "ollama".to_string(),
+                Upstream {
+                    url: Some("http://a".to_string()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            fallback_upstream: Some("ollama".to_string()),
+            ..Default::default()
+        };
+        let mut new = old.clone();
+        new.upstreams.get_mut("ollama").unwrap().url = Some("http://b".to_string());
+        new.upstreams.insert(
+            "search".to_string(),
+            Upstream {
+                url: Some("http://c".to_string()),
+                ..Default::default()
+            },
+        );
+        new.free_routes.push(FreeRoute {
+            path: "/search/**".to_string(),
+            target: UpstreamTarget {
+                upstream: Some("search".to_string()),
+                strip_prefix: Some("/search".to_string()),
+                rewrite_prefix: None,
+            },
+        });
+
+        assert_eq!(
+            config_changes(&old, &new),
+            vec![
+                "upstream ollama: http://a -> http://b",
+                "upstream added: search (http://c)",
+                "free route added: /search/** (upstream search, strip /search)",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_routes_are_protected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let config = make_config(
+            &mock_server.uri(),
+            &[("/api/**", 1000), ("/v1/models/{id}/generate", 500)],
+        );
+        let reloader = make_reloader("unused.json", config);
+        for path in ["/api", "/api/a/b", "/v1/models/llama/generate"] {
+            assert_eq!(
+                get_status(&reloader, path).await,
+                StatusCode::PAYMENT_REQUIRED,
+                "{}",
+                path
+            );
+        }
+        for path in ["/apix", "/v1/models", "/v1/models/llama/generate/x"] {
+            assert_eq!(
+                get_status(&reloader, path).await,
+                StatusCode::OK,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_canonical_paths_are_protected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/free/chat"))
+            .and(query_param("q", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let config = make_config(&mock_server.uri(), &[("/api/**", 1000), ("/exact", 500)]);
+        let reloader = make_reloader("unused.json", config);
+        for path in [
+            "/x/../api/chat",
+            "/x/%2e%2e/api/chat",
+            "/%61pi/chat",
+            "//api/chat",
+            "/exact/",
+            "/exact/.",
+        ] {
+            assert_eq!(
+                get_status(&reloader, path).await,
+                StatusCode::PAYMENT_REQUIRED,
+                "{}",
+                path
+            );
+        }
+
+        // Free requests are forwarded with the canonical path
+        assert_eq!(
+            get_status(&reloader, "/x/../%66ree/chat/?q=1").await,
+            StatusCode::OK
+        );
+
+        // An upstream could decode these into a protected path
+        for path in [
+            "/api%2Fchat",
+            "/exact%2f",
+            "/x%5C..%5Capi/chat",
+            "/exact%00",
+        ] {
+            assert_eq!(
+                get_status(&reloader, path).await,
+                StatusCode::BAD_REQUEST,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_well_known_is_not_proxied() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        // Served by the gateway even when a catch-all route is paid
+        let config = make_config(&mock_server.uri(), &[("/**", 1000)]);
+        let reloader = make_reloader("unused.json", config);
+        let response = send(
+            &reloader,
+            Request::builder()
+                .uri("/.well-known/oyster")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            info["address"],
+            "0x1a642f0E3c3aF545E7AcBD38b07251B3990914F1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_per_method_pricing() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any_request())
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let mut config = make_config(&mock_server.uri(), &[("/items", 0), ("/free-rest", 0)]);
+        for route in &mut config.protected_routes {
+            route.usdc_amount = None;
+            route.methods = [("GET".to_string(), 100), ("post".to_string(), 500)].into();
+        }
+        config.protected_routes[0].unlisted_methods = Some(UnlistedMethods::Reject);
+        let reloader = make_reloader("unused.json", config);
+
+        assert_eq!(
+            required_amount(&reloader, Method::GET, "/items")
+                .await
+                .as_deref(),
+            Some("100")
+        );
+        assert_eq!(
+            required_amount(&reloader, Method::POST, "/items")
+                .await
+                .as_deref(),
+            Some("500")
+        );
+
+        let delete = Request::builder()
+            .method(Method::DELETE)
+            .uri("/items")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&reloader, delete).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, POST");
+
+        let delete = Request::builder()
+            .method(Method::DELETE)
+            .uri("/free-rest")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&reloader, delete).await.status(), StatusCode::OK);
+
+        let preflight = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/items")
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        // Preflights are answered by the CORS layer in front of the routes
+        let response = app(Arc::new(reloader)).oneshot(preflight).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "*"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_pricing() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_string(r#"{"model":"tiny"}"#))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let mut config = make_config(&mock_server.uri(), &[("/api/chat", 0)]);
+        config.protected_routes[0].body_pricing = vec![
+            BodyPriceRule::Match {
+                json_path: "$.model".into(),
+                prices: [("qwen3:32b".to_string(), 500)].into(),
+                default: 0,
+            },
+            BodyPriceRule::PerUnit {
+                json_path: "$.options.num_predict".into(),
+                usdc_per_unit: 2,
+                default_units: 0,
+                max_units: None,
+            },
+        ];
+        let reloader = make_reloader("unused.json", config);
+
+        let chat = |body: &'static str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/chat")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = send(
+            &reloader,
+            chat(r#"{"model":"qwen3:32b","options":{"num_predict":100}}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(challenged_amount(&response).as_deref(), Some("700"));
+        let breakdown: serde_json::Value =
+            serde_json::from_slice(response.headers()[PRICE_BREAKDOWN_HEADER].as_bytes()).unwrap();
+        assert_eq!(breakdown["total"], 700);
+        assert_eq!(breakdown["rules"][0]["value"], "qwen3:32b");
+        assert_eq!(breakdown["rules"][1]["amount"], 200);
+
+        // Nothing to charge: the body still reaches the upstream intact
+        let response = send(&reloader, chat(r#"{"model":"tiny"}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(PRICE_BREAKDOWN_HEADER));
+
+        // A negative count has no price without max_units
+        let response = send(&reloader, chat(r#"{"options":{"num_predict":-1}}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let large = Request::builder()
+            .method(Method::POST)
+            .uri("/api/chat")
+            .body(Body::from(vec![b' '; MAX_PRICED_BODY_BYTES + 1]))
+            .unwrap();
+        assert_eq!(
+            send(&reloader, large).await.status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let chunks: [Result<&'static str, std::io::Error>; 2] =
+            [Ok("{"), Err(std::io::Error::other("connection reset"))];
+        let broken = Request::builder()
+            .method(Method::POST)
+            .uri("/api/chat")
+            .body(Body::from_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+        assert_eq!(
+            send(&reloader, broken).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metered_route_challenges_upto() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any_request())
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut config = make_config(&mock_server.uri(), &[("/api/generate", 5000)]);
+        config.protected_routes[0].metering = Some(Metering {
+            usdc_per_unit: 2,
+            usage: UsageSource::JsonPath {
+                json_path: "$.eval_count".into(),
+            },
+        });
+        let reloader = make_reloader("unused.json", config);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/api/generate")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&reloader, req).await;
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(challenged_amount(&response).as_deref(), Some("5000"));
+
+        let header = response.headers()["payment-required"].as_bytes();
+        let json = Base64Bytes::from(header).decode().unwrap();
+        let challenge: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(challenge["accepts"][0]["scheme"], "upto");
+    }
+
+    #[tokio::test]
+    async fn test_metered_bytes_exclude_event_records() {
+        let mock_server = MockServer::start().await;
+        let upstream_body = "{\"response\":\"a\"}\n{\"done\":true}\n";
+        Mock::given(path("/api/chat"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(upstream_body, "application/x-ndjson"),
+            )
+            .mount(&mock_server)
+            .await;
+        let facilitator = mock_facilitator(1).await;
+
+        let mut config = make_config(&mock_server.uri(), &[("/api/chat", 5000)]);
+        config.facilitator_url = facilitator.uri();
+        config.protected_routes[0].metering = Some(Metering {
+            usdc_per_unit: 1,
+            usage: UsageSource::Bytes,
+        });
+        let reloader = make_reloader("unused.json", config);
+
+        let challenge = send(
+            &reloader,
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/chat")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/api/chat")
+            .header("payment-signature", payment_for(&challenge))
+            .header("x-event-signatures", "oyster-event-v1")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&reloader, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["x-metered-units"],
+            upstream_body.len().to_string()
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.len() > upstream_body.len());
+
+        let settle = facilitator
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|request| request.url.path() == "/settle")
+            .unwrap();
+        let settle: serde_json::Value = serde_json::from_slice(&settle.body).unwrap();
+        assert_eq!(
+            settle["paymentRequirements"]["amount"],
+            upstream_body.len().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_settles_only_success_statuses() {
+        let mock_server = MockServer::start().await;
+        for (route, status) in [("/ok", 200), ("/fail", 500), ("/missing", 404)] {
+            Mock::given(path(route))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&mock_server)
+                .await;
+        }
+        let facilitator = mock_facilitator(2).await;
+
+        let mut config = make_config(
+            &mock_server.uri(),
+            &[("/ok", 10), ("/fail", 10), ("/missing", 10)],
+        );
+        config.facilitator_url = facilitator.uri();
+        config.protected_routes[2].settlement = Some(SettlementPolicy {
+            success_statuses: vec![
+                StatusPattern::Class("2xx".to_string()),
+                StatusPattern::Code(404),
+            ],
+        });
+        let reloader = make_reloader("unused.json", config);
+
+        let challenge = send(
+            &reloader,
+            Request::builder().uri("/ok").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(challenge.status(), StatusCode::PAYMENT_REQUIRED);
+        let payment = payment_for(&challenge);
+        let paid = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("payment-signature", &payment)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Upstream error: returned as is, nothing settled
+        let response = send(&reloader, paid("/fail")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!response.headers().contains_key("x-payment-response"));
+
+        let response = send(&reloader, paid("/ok")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key("x-payment-response"));
+
+        // 404 is in this route's success set
+        let response = send(&reloader, paid("/missing")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().contains_key("x-payment-response"));
+    }
+
+    #[tokio::test]
+    async fn test_paid_upgrade_is_settled_under_any_policy() {
+        use axum::extract::ws::WebSocketUpgrade;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let upstream = Router::new().route(
+            "/ws",
+            axum::routing::any(|upgrade: WebSocketUpgrade| async move {
+                upgrade.on_upgrade(|_socket| async {})
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+        let facilitator = mock_facilitator(1).await;
+
+        let mut config = make_config(&upstream_url, &[("/ws", 1000)]);
+        config.facilitator_url = facilitator.uri();
+        config.settlement = SettlementPolicy {
+            success_statuses: vec![StatusPattern::Class("2xx".to_string())],
+        };
+        let reloader = make_reloader("unused.json", config);
+        let challenge = send(
+            &reloader,
+            Request::builder().uri("/ws").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(challenge.status(), StatusCode::PAYMENT_REQUIRED);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway = listener.local_addr().unwrap();
+        let router = reloader.router();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let mut req = format!("ws://{}/ws", gateway)
+            .into_client_request()
+            .unwrap();
+        req.headers_mut().insert(
+            "payment-signature",
+            payment_for(&challenge).parse().unwrap(),
+        );
+        let (_socket, response) = tokio_tungstenite::connect_async(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert!(response.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_paid_requests_are_counted() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/paid/1"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let facilitator = mock_facilitator(1).await;
+        let mut config = make_config(&mock_server.uri(), &[("/paid/*", 10)]);
+        config.facilitator_url = facilitator.uri();
+        let reloader = make_reloader("unused.json", config);
+
+        let challenge = send(
+            &reloader,
+            Request::builder()
+                .uri("/paid/1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let payment = payment_for(&challenge);
+        let response = send(
+            &reloader,
+            Request::builder()
+                .uri("/paid/1")
+                .header("payment-signature", &payment)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        get_status(&reloader, "/free").await;
+
+        let metrics = reloader
+            .current
+            .read()
+            .unwrap()
+            .state
+            .metrics
+            .render()
+            .unwrap();
+        for line in [
+            r#"gateway_requests_total{route="/paid/*",status="402",upstream="default"} 1"#,
+            r#"gateway_requests_total{route="/paid/*",status="200",upstream="default"} 1"#,
+            r#"gateway_requests_total{route="unmatched",status="404",upstream="default"} 1"#,
+            r#"gateway_payment_challenges_total{route="/paid/*"} 1"#,
+            r#"gateway_payments_total{network="eip155:84532",outcome="verified"} 1"#,
+            r#"gateway_payments_total{network="eip155:84532",outcome="settled"} 1"#,
+            r#"gateway_revenue_usdc_micros_total{network="eip155:84532",route="/paid/*"} 10"#,
+            "gateway_signing_duration_seconds_count 2",
+        ] {
+            assert!(metrics.contains(line), "missing {}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prepaid_credit() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/api/chat"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hi"))
+            .mount(&mock_server)
+            .await;
+        let key = SigningKey::from_bytes(&[2u8; 32].into()).unwrap();
+        let payer = address(key.verifying_key()).to_checksum(None);
+        let facilitator = mock_facilitator_for(&payer, 1).await;
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("credits.db").to_str().unwrap().to_string();
+        let mut config = make_config(&mock_server.uri(), &[("/api/chat", 10)]);
+        config.facilitator_url = facilitator.uri();
+        config.credits = Some(Credits {
+            db_path: db_path.clone(),
+            top_up_path: "/credits/top-up".to_string(),
+            top_up_usdc_amount: 25,
+            account_path: "/credits".to_string(),
+        });
+        let state = Arc::new(AppState {
+            config,
+            http_client: reqwest::Client::new(),
+            signing_key: SigningKey::from_bytes(&[1u8; 32].into()).unwrap(),
+            attestation_url: None,
+            metrics: Default::default(),
+            pools: Default::default(),
+            ledger: None,
+            credits: Some(Arc::new(CreditStore::open(&db_path).unwrap())),
+        });
+        let router = build_router(state.clone()).unwrap();
+        let reloader = ConfigReloader::new("unused.json".to_string(), state, router);
+
+        // Top up with an x402 payment
+        let challenge = send(
+            &reloader,
+            Request::post("/credits/top-up")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(challenged_amount(&challenge).as_deref(), Some("25"));
+        let response = send(
+            &reloader,
+            Request::post("/credits/top-up")
+                .header("payment-signature", payment_for(&challenge))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(RECEIPT_HEADER));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let top_up: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            top_up,
+            serde_json::json!({ "payer": payer, "credited": 25, "balance": 25 })
+        );
+
+        let signed = |nonce: u8, method: Method, path: &str, body: &'static str| {
+            let authorization = CreditAuthorization::sign(
+                &key,
+                unix_now(),
+                [nonce; 16],
+                &method,
+                path,
+                body.as_bytes(),
+            );
+            let mut req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::from(body))
+                .unwrap();
+            authorization.insert_headers(req.headers_mut());
+            req
+        };
+
+        // Signed requests draw on the balance without an x402 payment
+        for (nonce, status, balance) in [
+            (1, StatusCode::OK, "15"),
+            (2, StatusCode::OK, "5"),
+            (3, StatusCode::PAYMENT_REQUIRED, "5"),
+        ] {
+            let response = send(&reloader, signed(nonce, Method::POST, "/api/chat", "{}")).await;
+            assert_eq!(response.status(), status);
+            assert_eq!(response.headers()[CREDIT_BALANCE_HEADER], balance);
+        }
+        let replayed = send(&reloader, signed(1, Method::POST, "/api/chat", "{}")).await;
+        assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send(&reloader, signed(4, Method::GET, "/credits", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let account: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(account["payer"], payer);
+        assert_eq!(account["balance"], 5);
+        let usage: Vec<(&str, u64)> = account["usage"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| (u["kind"].as_str().unwrap(), u["amount"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(usage, [("draw", 10), ("draw", 10), ("top_up", 25)]);
+
+        let unsigned = Request::get("/credits").body(Body::empty()).unwrap();
+        assert_eq!(
+            send(&reloader, unsigned).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_pass() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any_request())
+            .respond_with(ResponseTemplate::new(200).set_body_string("hi"))
+            .mount(&mock_server)
+            .await;
+        let facilitator = mock_facilitator(1).await;
+        let mut config = make_config(
+            &mock_server.uri(),
+            &[("/api/chat", 10), ("/models", 5), ("/other", 7)],
+        );
+        config.facilitator_url = facilitator.uri();
+        config.protected_routes[0].access_pass = Some(AccessPassPolicy {
+            duration_secs: 3600,
+            scope: vec!["/api/chat".to_string(), "/models".to_string()],
+        });
+        let reloader = make_reloader("unused.json", config);
+
+        // A settled payment buys a pass for the whole scope
+        let challenge = send(
+            &reloader,
+            Request::post("/api/chat").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let response = send(
+            &reloader,
+            Request::post("/api/chat")
+                .header("payment-signature", payment_for(&challenge))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = response.headers()[ACCESS_PASS_HEADER].clone();
+        let pass = AccessPass::from_header(header.as_bytes()).unwrap();
+        let gateway_key = SigningKey::from_bytes(&[1u8; 32].into()).unwrap();
+        assert_eq!(pass.recover().unwrap(), *gateway_key.verifying_key());
+        assert_eq!(pass.payer, "0x857b06519E91e3A54538791bDbb0E22373e36b66");
+        assert_eq!(pass.scope, ["/api/chat", "/models"]);
+        assert_eq!(pass.expires_at, pass.issued_at + 3600);
+
+        let with_pass = |uri: &str, pass: HeaderValue| {
+            Request::post(uri)
+                .header(ACCESS_PASS_HEADER, pass)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for uri in ["/api/chat", "/models"] {
+            let response = send(&reloader, with_pass(uri, header.clone())).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(!response.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+        }
+
+        // Out of scope, expired, and forged passes get the usual challenge
+        let response = send(&reloader, with_pass("/other", header.clone())).await;
+        assert_eq!(challenged_amount(&response).as_deref(), Some("7"));
+        let mut expired = pass.clone();
+        expired.issued_at -= 7200;
+        expired.expires_at -= 7200;
+        expired.signature = sign_digest(&gateway_key, expired.digest());
+        let response = send(&reloader, with_pass("/models", expired.to_header())).await;
+        assert_eq!(challenged_amount(&response).as_deref(), Some("5"));
+        let mut forged = pass.clone();
+        forged.signature = sign_digest(
+            &SigningKey::from_bytes(&[2u8; 32].into()).unwrap(),
+            forged.digest(),
+        );
+        let response = send(&reloader, with_pass("/models", forged.to_header())).await;
+        assert_eq!(challenged_amount(&response).as_deref(), Some("5"));
+    }
+
+    #[tokio::test]
+    async fn test_settled_payment_is_recorded() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/paid"))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&mock_server)
+            .await;
+        let facilitator = mock_facilitator(1).await;
+        let mut config = make_config(&mock_server.uri(), &[("/paid", 10)]);
+        config.facilitator_url = facilitator.uri();
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("ledger.db");
+        let ledger = Arc::new(Ledger::open(db.to_str().unwrap()).unwrap());
+        let state = Arc::new(AppState {
+            config,
+            http_client: reqwest::Client::new(),
+            signing_key: SigningKey::from_bytes(&[1u8; 32].into()).unwrap(),
+            attestation_url: None,
+            metrics: Default::default(),
+            pools: Default::default(),
+            ledger: Some(ledger.clone()),
+            credits: None,
+        });
+        let router = build_router(state.clone()).unwrap();
+        let reloader = ConfigReloader::new("unused.json".to_string(), state, router);
+
+        let challenge = send(
+            &reloader,
+            Request::builder().uri("/paid").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let payment = payment_for(&challenge);
+        let response = send(
+            &reloader,
+            Request::builder()
+                .uri("/paid")
+                .header("payment-signature", &payment)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let header = response.headers().get(RECEIPT_HEADER).unwrap();
+        let receipt = Receipt::from_header(header.as_bytes()).unwrap();
+
+        let entries = ledger.query(&Default::default()).unwrap();
+        assert_eq!(
+            entries,
+            [LedgerEntry {
+                id: 1,
+                timestamp: receipt.timestamp,
+                payer: "0x857b06519E91e3A54538791bDbb0E22373e36b66".to_string(),
+                network: "eip155:84532".to_string(),
+                asset: "0x036CbD53842c5426634e7929541eC2318f3dCF7e".to_string(),
+                amount: 10,
+                route: "/paid".to_string(),
+                request_id: receipt.request_hash,
+                transaction: "0x01".to_string(),
+                upstream_status: 201,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_settled_response_carries_receipt() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/paid"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("answer"))
+            .mount(&mock_server)
+            .await;
+        let facilitator = mock_facilitator(1).await;
+        let mut config = make_config(&mock_server.uri(), &[("/paid", 10)]);
+        config.facilitator_url = facilitator.uri();
+        let reloader = make_reloader("unused.json", config);
+
+        let challenge = send(
+            &reloader,
+            Request::builder().uri("/paid").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let payment = payment_for(&challenge);
+        let response = send(
+            &reloader,
+            Request::builder()
+                .method("POST")
+                .uri("/paid?x=1")
+                .header("payment-signature", &payment)
+                .header("content-length", "5")
+                .body(Body::from("hello"))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let header = response.headers().get(RECEIPT_HEADER).unwrap();
+        let json = Base64Bytes::from(header.as_bytes()).decode().unwrap();
+        let receipt: Receipt = serde_json::from_slice(&json).unwrap();
+        assert_eq!(receipt.payer, "0x857b06519E91e3A54538791bDbb0E22373e36b66");
+        assert_eq!(receipt.network, "eip155:84532");
+        assert_eq!(receipt.amount, 10);
+        assert_eq!(receipt.transaction, "0x01");
+
+        let mut request =
+            SigningHasher::with_version(SignatureVersion::V4, &Method::POST, "/paid?x=1");
+        request.begin_body(5).unwrap();
+        request.update(b"hello").unwrap();
+        assert_eq!(
+            receipt.request_hash,
+            format!("0x{}", hex::encode(request.digest().unwrap()))
+        );
+
+        let signature = hex::decode(&receipt.signature).unwrap();
+        let recovered = VerifyingKey::recover_from_digest(
+            receipt.digest().unwrap(),
+            &Signature::from_slice(&signature[..64]).unwrap(),
+            RecoveryId::from_byte(signature[64] - 27).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            &recovered,
+            SigningKey::from_bytes(&[1u8; 32].into())
+                .unwrap()
+                .verifying_key()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v3_signature_binds_payment() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/paid"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("answer"))
+            .mount(&mock_server)
+            .await;
+        let facilitator = mock_facilitator(1).await;
+        let mut config = make_config(&mock_server.uri(), &[("/paid", 10)]);
+        config.facilitator_url = facilitator.uri();
+        let reloader = make_reloader("unused.json", config);
+
+        let challenge = send(
+            &reloader,
+            Request::builder().uri("/paid").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let payment = payment_for(&challenge);
+        let response = send(
+            &reloader,
+            Request::builder()
+                .uri("/paid")
+                .header("payment-signature", &payment)
+                .header("x-signature-version", "oyster-signature-v3")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let signed: serde_json::Value =
+            serde_json::from_slice(response.headers()["x-signed-payment"].as_bytes()).unwrap();
+        let payload = Base64Bytes::from(payment.as_bytes()).decode().unwrap();
+        let accepted = &serde_json::from_slice::<serde_json::Value>(&payload).unwrap()["accepted"];
+        assert_eq!(signed["scheme"], accepted["scheme"]);
+        assert_eq!(signed["network"], accepted["network"]);
+        assert_eq!(signed["amount"], "10");
+        assert_eq!(signed["pay_to"], accepted["payTo"]);
+        assert_eq!(
+            signed["settlement_reference"],
+            format!("0x{}", hex::encode(Keccak256::digest(&payload)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_backend_is_not_settled() {
+        let facilitator = mock_facilitator(0).await;
+        let mut config = make_config("http://127.0.0.1:1", &[("/paid", 10)]);
+        config.facilitator_url = facilitator.uri();
+        // Even a policy that accepts upstream errors never settles a request
+        // that no backend saw
+        config.settlement = SettlementPolicy {
+            success_statuses: vec![StatusPattern::Class("5xx".to_string())],
+        };
+        let reloader = make_reloader("unused.json", config);
+
+        let challenge = send(
+            &reloader,
+            Request::builder().uri("/paid").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let payment = payment_for(&challenge);
+        let response = send(
+            &reloader,
+            Request::builder()
+                .uri("/paid")
+                .header("payment-signature", &payment)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(!response.headers().contains_key("x-payment-response"));
+    }
+
+    #[tokio::test]
+    async fn test_routes_to_named_upstreams() {
+        let ollama = MockServer::start().await;
+        Mock::given(path("/api/tags"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ollama"))
+            .mount(&ollama)
+            .await;
+        let search = MockServer::start().await;
+        Mock::given(path("/v1/web"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("search"))
+            .mount(&search)
+            .await;
+        Mock::given(path("/admin"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("search admin"))
+            .expect(0)
+            .mount(&search)
+            .await;
+
+        let json = format!(
+            r#"{{
+                "gateway_port": 3000,
+                "facilitator_url": "http://127.0.0.1:1",
+                "upstreams": {{
+                    "ollama": {{ "url": "{}" }},
+                    "search": {{ "url": "{}" }}
+                }},
+                "fallback_upstream": "ollama",
+                "networks": [
+                    {{ "type": "evm", "network": "base-sepolia",
+                       "payment_address": "0xd232A8b0F63a555d054134f67b298ffE955f3BAf" }}
+                ],
+                "protected_routes": [
+                    {{ "path": "/search/deep/**", "usdc_amount": 10, "upstream": "search" }}
+                ],
+                "free_routes": [
+                    {{ "path": "/search/**", "upstream": "search",
+                       "strip_prefix": "/search", "rewrite_prefix": "/v1" }}
+                ]
+            }}"#,
+            ollama.uri(),
+            search.uri()
+        );
+        let config: Config = serde_json::from_str(&json).unwrap();
+        config.validate().unwrap();
+        let reloader = make_reloader("unused.json", config);
+
+        let body = |response: Response| async {
+            to_bytes(response.into_body(), usize::MAX).await.unwrap()
+        };
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = send(&reloader, get("/search/web?q=rust")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "search");
+
+        let response = send(&reloader, get("/api/tags")).await;
+        assert_eq!(body(response).await, "ollama");
+
+        // The more specific protected route wins over the free one
+        assert_eq!(
+            get_status(&reloader, "/search/deep/web").await,
+            StatusCode::PAYMENT_REQUIRED
+        );
+
+        // Dot segments cannot climb out of the rewrite prefix
+        for uri in ["/search/../admin", "/search/%2e%2e/%2E%2E/admin"] {
+            let response = send(&reloader, get(uri)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_swaps_router() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        let config = make_config(&mock_server.uri(), &[("/protected", 1000)]);
+        fs::write(&config_path, config_json(&config)).unwrap();
+
+        let reloader = make_reloader(config_path.to_str().unwrap(), config);
+        assert_eq!(get_status(&reloader, "/premium").await, StatusCode::OK);
+        assert!(!reloader.reload().unwrap());
+
+        let updated = make_config(
+            &mock_server.uri(),
+            &[("/protected", 1000), ("/premium", 5000)],
+        );
+        fs::write(&config_path, config_json(&updated)).unwrap();
+
+        assert!(reloader.reload().unwrap());
+        assert_eq!(
+            get_status(&reloader, "/premium").await,
+            StatusCode::PAYMENT_REQUIRED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_rejects_invalid_config() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        let config = make_config(&mock_server.uri(), &[("/protected", 1000)]);
+        let reloader = make_reloader(config_path.to_str().unwrap(), config);
+
+        fs::write(&config_path, "{ not json").unwrap();
+        assert!(reloader.reload().is_err());
+
+        let broken = config_json(&make_config(&mock_server.uri(), &[])).replace(
+            "0xd232A8b0F63a555d054134f67b298ffE955f3BAf",
+            "not-an-address",
+        );
+        fs::write(&config_path, broken).unwrap();
+        assert!(
+            reloader
+                .reload()
+                .unwrap_err()
+                .contains("Invalid EVM address")
+        );
+
+        assert_eq!(
+            get_status(&reloader, "/protected").await,
+            StatusCode::PAYMENT_REQUIRED
+        );
+    }
+}
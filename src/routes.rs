@@ -0,0 +1,381 @@
+use std::cmp::Ordering;
+
+/// One `/`-separated piece of a route pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `*` or `{name}`: exactly one path segment.
+    Param,
+    /// `**` or `{*name}`: the rest of the path, zero or more segments.
+    CatchAll,
+}
+
+impl Segment {
+    /// Rank used for precedence; more specific segments rank higher.
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Literal(_) => 3,
+            Self::Param => 2,
+            Self::CatchAll => 1,
+        }
+    }
+
+    fn matches(&self, segment: &str) -> bool {
+        match self {
+            Self::Literal(literal) => literal == segment,
+            Self::Param | Self::CatchAll => true,
+        }
+    }
+}
+
+/// A protected route path with optional glob or axum-style parameter segments.
+///
+/// - `/v1/models` matches that path only.
+/// - `/v1/models/{id}/generate` and `/v1/models/*/generate` match any single
+///   segment in place of `{id}` or `*`.
+/// - `/api/**` and `/api/{*rest}` match `/api` and everything below it. They
+///   are only allowed as the last segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePattern {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let Some(rest) = pattern.strip_prefix('/') else {
+            return Err(format!("route path must start with '/': {}", pattern));
+        };
+
+        let parts: Vec<&str> = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split('/').collect()
+        };
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match *part {
+                "*" => Segment::Param,
+                "**" => Segment::CatchAll,
+                _ => match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                    Some(name) => {
+                        let (catch_all, name) = match name.strip_prefix('*') {
+                            Some(name) => (true, name),
+                            None => (false, name),
+                        };
+                        if name.is_empty()
+                            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                        {
+                            return Err(format!("invalid parameter name in {}: {}", pattern, part));
+                        }
+                        if catch_all {
+                            Segment::CatchAll
+                        } else {
+                            Segment::Param
+                        }
+                    }
+                    None if part.contains(['*', '{', '}']) => {
+                        return Err(format!(
+                            "wildcards and parameters must span a whole segment: {}",
+                            pattern
+                        ));
+                    }
+                    None => Segment::Literal(part.to_string()),
+                },
+            };
+            if segment == Segment::CatchAll && i + 1 != parts.len() {
+                return Err(format!(
+                    "catch-all is only allowed as the last segment: {}",
+                    pattern
+                ));
+            }
+            segments.push(segment);
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether both patterns match exactly the same paths, e.g. `/a/{id}` and `/a/*`.
+    pub fn is_equivalent(&self, other: &Self) -> bool {
+        self.segments == other.segments
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let rest = path.strip_prefix('/').unwrap_or(path);
+        let parts: Vec<&str> = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split('/').collect()
+        };
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if *segment == Segment::CatchAll {
+                return true;
+            }
+            match parts.get(i) {
+                Some(part) if segment.matches(part) => {}
+                _ => return false,
+            }
+        }
+        parts.len() == self.segments.len()
+    }
+
+    /// Whether some path is matched by both patterns.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        fn overlap(a: &[Segment], b: &[Segment]) -> bool {
+            match (a.first(), b.first()) {
+                (None, None) => true,
+                (Some(Segment::CatchAll), _) | (_, Some(Segment::CatchAll)) => true,
+                (None, Some(_)) | (Some(_), None) => false,
+                (Some(Segment::Literal(x)), Some(Segment::Literal(y))) if x != y => false,
+                (Some(_), Some(_)) => overlap(&a[1..], &b[1..]),
+            }
+        }
+        overlap(&self.segments, &other.segments)
+    }
+
+    /// Order patterns so the most specific one comes first.
+    ///
+    /// Segments are compared left to right: a literal beats a parameter, which
+    /// beats a catch-all, and a pattern that ends beats a catch-all in the same
+    /// position. For any path matched by two patterns, the one that sorts
+    /// first is the longest match.
+    pub fn precedence(&self, other: &Self) -> Ordering {
+        const END: u8 = 4;
+        let ranks = |pattern: &Self| {
+            pattern
+                .segments
+                .iter()
+                .map(Segment::rank)
+                .chain(std::iter::once(END))
+                .collect::<Vec<_>>()
+        };
+        ranks(other).cmp(&ranks(self))
+    }
+}
+
+/// The canonical form of a request path, which is what routes are matched
+/// against and what is forwarded upstream.
+///
+/// Percent-encoded unreserved characters (`A-Z a-z 0-9 - . _ ~`) are decoded,
+/// `.` and `..` segments are resolved, and empty segments are dropped, so
+/// `/x/../api/%63hat/` becomes `/api/chat`. Other escapes, such as `%2F`, are
+/// kept as they are.
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<String> = Vec::new();
+    for segment in path.split('/').map(decode_unreserved) {
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+fn decode_unreserved(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|&c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~'));
+        match escaped {
+            Some(c) => {
+                decoded.push(c);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).expect("only ASCII escapes are decoded")
+}
+
+/// Whether a path holds an escaped `/`, `\` or NUL (`%2F`, `%5C`, `%00`).
+///
+/// [`normalize_path`] keeps these escaped, but an upstream may decode them
+/// and see a different path from the one routes were matched against, so
+/// such requests are refused instead.
+pub fn has_encoded_separator(path: &str) -> bool {
+    path.as_bytes().windows(3).any(|escape| {
+        escape[0] == b'%'
+            && [b"2f", b"5c", b"00"]
+                .iter()
+                .any(|hex| escape[1..].eq_ignore_ascii_case(*hex))
+    })
+}
+
+/// Protected routes ordered by precedence, so lookups return the longest match.
+pub struct RouteTable<T> {
+    routes: Vec<(RoutePattern, T)>,
+}
+
+impl<T> RouteTable<T> {
+    pub fn new(mut routes: Vec<(RoutePattern, T)>) -> Self {
+        routes.sort_by(|(a, _), (b, _)| a.precedence(b));
+        Self { routes }
+    }
+
+    /// The longest match for `path` and the pattern it was registered under.
+    pub fn find(&self, path: &str) -> Option<(&str, &T)> {
+        self.routes
+            .iter()
+            .find(|(pattern, _)| pattern.matches(path))
+            .map(|(pattern, value)| (pattern.as_str(), value))
+    }
+
+    /// Pairs of patterns that both match some path. The first of each pair
+    /// takes precedence on the paths they share.
+    pub fn overlaps(&self) -> Vec<(&str, &str)> {
+        let mut pairs = Vec::new();
+        for (i, (a, _)) in self.routes.iter().enumerate() {
+            for (b, _) in &self.routes[i + 1..] {
+                if a.overlaps(b) {
+                    pairs.push((a.as_str(), b.as_str()));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> RoutePattern {
+        RoutePattern::parse(pattern).unwrap()
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_patterns() {
+        assert!(RoutePattern::parse("api").is_err());
+        assert!(RoutePattern::parse("/api/**/x").is_err());
+        assert!(RoutePattern::parse("/api/{*rest}/x").is_err());
+        assert!(RoutePattern::parse("/api/v*").is_err());
+        assert!(RoutePattern::parse("/api/{}").is_err());
+        assert!(RoutePattern::parse("/api/{a-b}").is_err());
+    }
+
+    #[test]
+    fn test_exact_and_parameter_matches() {
+        assert!(pattern("/").matches("/"));
+        assert!(!pattern("/").matches("/x"));
+        assert!(pattern("/protected").matches("/protected"));
+        assert!(!pattern("/protected").matches("/protected/x"));
+
+        let generate = pattern("/v1/models/{id}/generate");
+        assert!(generate.matches("/v1/models/llama/generate"));
+        assert!(!generate.matches("/v1/models/generate"));
+        assert!(!generate.matches("/v1/models/a/b/generate"));
+        assert!(generate.is_equivalent(&pattern("/v1/models/*/generate")));
+    }
+
+    #[test]
+    fn test_catch_all_matches() {
+        for p in ["/api/**", "/api/{*rest}"] {
+            let api = pattern(p);
+            assert!(api.matches("/api"));
+            assert!(api.matches("/api/"));
+            assert!(api.matches("/api/a/b/c"));
+            assert!(!api.matches("/apix"));
+            assert!(!api.matches("/other"));
+        }
+    }
+
+    #[test]
+    fn test_longest_match_wins() {
+        let table = RouteTable::new(vec![
+            (pattern("/**"), "root"),
+            (pattern("/api/**"), "api"),
+            (pattern("/api/{id}"), "api-item"),
+            (pattern("/api/premium"), "premium"),
+            (pattern("/api/premium/**"), "premium-tree"),
+        ]);
+        assert_eq!(table.find("/"), Some(("/**", &"root")));
+        assert_eq!(table.find("/api"), Some(("/api/**", &"api")));
+        assert_eq!(table.find("/api/a/b"), Some(("/api/**", &"api")));
+        assert_eq!(table.find("/api/free"), Some(("/api/{id}", &"api-item")));
+        assert_eq!(
+            table.find("/api/premium"),
+            Some(("/api/premium", &"premium"))
+        );
+        assert_eq!(
+            table.find("/api/premium/x"),
+            Some(("/api/premium/**", &"premium-tree"))
+        );
+
+        let empty: RouteTable<()> = RouteTable::new(vec![(pattern("/a"), ())]);
+        assert_eq!(empty.find("/b"), None);
+    }
+
+    #[test]
+    fn test_overlap_report() {
+        let table = RouteTable::new(vec![
+            (pattern("/api/**"), ()),
+            (pattern("/api/{id}/generate"), ()),
+            (pattern("/api/models/generate"), ()),
+            (pattern("/static/{file}"), ()),
+            (pattern("/other"), ()),
+        ]);
+        assert_eq!(
+            table.overlaps(),
+            [
+                ("/api/models/generate", "/api/{id}/generate"),
+                ("/api/models/generate", "/api/**"),
+                ("/api/{id}/generate", "/api/**"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/api/chat"), "/api/chat");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/x/../api/chat"), "/api/chat");
+        assert_eq!(normalize_path("/x/%2e%2e/api/chat"), "/api/chat");
+        assert_eq!(normalize_path("/x/%2E./api/./chat"), "/api/chat");
+        assert_eq!(normalize_path("/../../api/chat"), "/api/chat");
+        assert_eq!(normalize_path("/%61pi/chat"), "/api/chat");
+        assert_eq!(normalize_path("/api/chat/"), "/api/chat");
+        assert_eq!(normalize_path("//api//chat"), "/api/chat");
+        // Reserved and malformed escapes stay encoded
+        assert_eq!(normalize_path("/api/a%2Fb"), "/api/a%2Fb");
+        assert_eq!(normalize_path("/api/%zz%4"), "/api/%zz%4");
+        assert!(has_encoded_separator("/api%2Fchat"));
+        assert!(has_encoded_separator("/api%5cchat"));
+        assert!(has_encoded_separator("/api/chat%00"));
+        assert!(!has_encoded_separator("/api/%2e%2e/chat"));
+        assert!(!has_encoded_separator("/api/chat%2"));
+
+        let api = pattern("/api/**");
+        let chat = pattern("/api/chat");
+        for path in [
+            "/x/../api/chat",
+            "/x/%2e%2e/api/chat",
+            "/%61pi/chat",
+            "/api/chat/",
+        ] {
+            assert!(!chat.matches(path));
+            assert!(chat.matches(&normalize_path(path)));
+            assert!(api.matches(&normalize_path(path)));
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use crate::body_pricing::MAX_PRICED_BODY_BYTES;
+use crate::config::{
+    AccessPassPolicy, Credits, Metering, NetworkConfig, ProtectedRoute, SettlementPolicy,
+};
+use crate::credits::{CreditStore, DrawError, authorize};
+use crate::ledger::{Ledger, LedgerEntry};
+use crate::metering::{meter_response, with_amount};
+use crate::metrics::{Metrics, PaymentOutcome};
+use crate::pricing::{exact_price_tags, upto_price_tags};
+use crate::state::AppState;
+use crate::upstream::Unrouted;
+use axum::{
+    Json,
+    body::Body,
+    http::{HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use k256::ecdsa::SigningKey;
+use sha3::{Digest, Keccak256};
+use std::sync::Arc;
+use tower::ServiceExt;
+use tracing::{error, info, warn};
+use x402_axum::{
+    PaygateProtocol,
+    facilitator_client::FacilitatorClient,
+    paygate::{Paygate, PaygateError, ResourceInfoBuilder, VerificationError},
+};
+use x402_gateway::credit::{CREDIT_BALANCE_HEADER, CREDIT_SIGNATURE_HEADER};
+use x402_gateway::pass::{ACCESS_PASS_HEADER, AccessPass};
+use x402_gateway::receipt::{RECEIPT_HEADER, Receipt, RequestDigest, settlement_field};
+use x402_gateway::signing::{SignatureVersion, SignedPayment, build_request_prefix, unix_now};
+use x402_gateway::verify::VerifyError;
+use x402_types::{
+    proto::{SettleResponse, v2::PriceTag as V2PriceTag},
+    util::Base64Bytes,
+};
+
+pub const PAYMENT_RESPONSE_HEADER: &str = "X-Payment-Response";
+
+type PaymentPayload = <V2PriceTag as PaygateProtocol>::PaymentPayload;
+
+/// The accepted requirements of a payment, with the hash of the payload JSON
+/// as its settlement reference.
+fn signed_payment(payload: &PaymentPayload, json: &[u8]) -> SignedPayment {
+    let accepted = &payload.accepted;
+    SignedPayment {
+        scheme: accepted.scheme.clone(),
+        network: accepted.network.to_string(),
+        asset: accepted.asset.clone(),
+        amount: accepted.amount.clone(),
+        pay_to: accepted.pay_to.clone(),
+        settlement_reference: format!("0x{}", hex::encode(Keccak256::digest(json))),
+    }
+}
+
+/// What a route's settler does with prepaid credit.
+#[derive(Clone)]
+enum CreditUse {
+    Off,
+    /// Requests signed by a payer draw on their balance instead of paying.
+    Draw(Arc<CreditStore>),
+    /// The route sells credit: settled payments are added to the payer's
+    /// balance.
+    TopUp(Arc<CreditStore>),
+}
+
+/// Charges paid requests to one route: the payment is verified, the upstream
+/// is called, and the payment is settled only when the response status is in
+/// the route's success set. Metered routes use the `upto` scheme and settle
+/// the measured usage instead of the full price. Settled responses carry a
+/// receipt signed with the gateway key, and settled payments are written to
+/// the ledger when there is one. With prepaid credit, requests that carry a
+/// credit authorization are charged to the payer's balance the same way.
+pub struct Settler {
+    facilitator: Arc<FacilitatorClient>,
+    networks: Vec<NetworkConfig>,
+    policy: SettlementPolicy,
+    metering: Option<Metering>,
+    signing_key: SigningKey,
+    /// Pattern of the route, as metrics and the ledger name it.
+    route: String,
+    metrics: Arc<Metrics>,
+    ledger: Option<Arc<Ledger>>,
+    credit: CreditUse,
+    /// Pass issued for settled payments, with its scope resolved.
+    access_pass: Option<AccessPassPolicy>,
+}
+
+impl Settler {
+    pub fn new(
+        facilitator: Arc<FacilitatorClient>,
+        route_config: &ProtectedRoute,
+        state: &AppState,
+    ) -> Self {
+        Self {
+            facilitator,
+            networks: state.config.networks.clone(),
+            policy: route_config.settlement(&state.config).clone(),
+            metering: route_config.metering.clone(),
+            signing_key: state.signing_key.clone(),
+            route: route_config.path.clone(),
+            metrics: state.metrics.clone(),
+            ledger: state.ledger.clone(),
+            credit: match (&state.config.credits, &state.credits) {
+                (Some(_), Some(store)) => CreditUse::Draw(store.clone()),
+                _ => CreditUse::Off,
+            },
+            access_pass: route_config
+                .access_pass
+                .as_ref()
+                .map(|policy| AccessPassPolicy {
+                    duration_secs: policy.duration_secs,
+                    scope: route_config.pass_scope(),
+                }),
+        }
+    }
+
+    /// Settler of the route that sells credit. Credit is drawn with EVM
+    /// signatures, so it is only sold on EVM networks.
+    pub fn top_up(
+        facilitator: Arc<FacilitatorClient>,
+        credits: &Credits,
+        state: &AppState,
+        store: Arc<CreditStore>,
+    ) -> Self {
+        Self {
+            facilitator,
+            networks: state
+                .config
+                .networks
+                .iter()
+                .filter(|net| matches!(net, NetworkConfig::Evm { .. }))
+                .cloned()
+                .collect(),
+            policy: SettlementPolicy::default(),
+            metering: None,
+            signing_key: state.signing_key.clone(),
+            route: credits.top_up_path.clone(),
+            metrics: state.metrics.clone(),
+            ledger: state.ledger.clone(),
+            credit: CreditUse::TopUp(store),
+            access_pass: None,
+        }
+    }
+
+    /// Run `req` through `inner` for `usdc_amount`, the most the request can
+    /// cost when it is metered.
+    pub async fn call(
+        &self,
+        usdc_amount: u64,
+        description: String,
+        inner: MethodRouter,
+        req: Request<Body>,
+    ) -> Response {
+        if let Some(pass) = self.valid_pass(&req) {
+            info!(payer = %pass.payer, expires_at = pass.expires_at, "Request admitted with an access pass");
+            self.metrics.pass(&self.route);
+            return match inner.oneshot(req).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+        }
+        if let CreditUse::Draw(store) = &self.credit
+            && req.headers().contains_key(CREDIT_SIGNATURE_HEADER)
+        {
+            return self.draw(store, usdc_amount, inner, req).await;
+        }
+
+        let accepts = match self.metering {
+            Some(_) => upto_price_tags(&self.networks, usdc_amount),
+            None => exact_price_tags(&self.networks, usdc_amount),
+        };
+        let resource = ResourceInfoBuilder {
+            description,
+            ..Default::default()
+        }
+        .as_resource_info(None, &req);
+        let mut paygate = Paygate {
+            facilitator: self.facilitator.clone(),
+            settle_before_execution: false,
+            accepts: Arc::new(accepts),
+            resource,
+        };
+        paygate.enrich_accepts().await;
+
+        let response = match self.handle(&paygate, usdc_amount, inner, req).await {
+            Ok(response) => response,
+            Err(err) => V2PriceTag::error_into_response(err, &paygate.accepts, &paygate.resource),
+        };
+        if response.status() == StatusCode::PAYMENT_REQUIRED {
+            self.metrics.challenge(&self.route);
+        }
+        response
+    }
+
+    /// The access pass the request carries, if this gateway issued it, it
+    /// covers the route, and it has not expired. Anything else falls through
+    /// to payment, so x402 clients pay again when their pass runs out.
+    fn valid_pass(&self, req: &Request<Body>) -> Option<AccessPass> {
+        let header = req.headers().get(ACCESS_PASS_HEADER)?;
+        let checked = AccessPass::from_header(header.as_bytes()).and_then(|pass| {
+            if pass.recover()? != *self.signing_key.verifying_key() {
+                return Err(VerifyError::Message(
+                    "not issued by this gateway".to_string(),
+                ));
+            }
+            if !pass.admits(&self.route, unix_now()) {
+                return Err(VerifyError::Message(
+                    "expired or not valid for this route".to_string(),
+                ));
+            }
+            Ok(pass)
+        });
+        checked
+            .inspect_err(|e| info!(error = %e, "Access pass not accepted; payment required"))
+            .ok()
+    }
+
+    async fn handle(
+        &self,
+        paygate: &Paygate<V2PriceTag, Arc<FacilitatorClient>>,
+        usdc_amount: u64,
+        inner: MethodRouter,
+        mut req: Request<Body>,
+    ) -> Result<Response, PaygateError> {
+        let header = req.headers().get(V2PriceTag::PAYMENT_HEADER_NAME).ok_or(
+            VerificationError::PaymentHeaderRequired(V2PriceTag::PAYMENT_HEADER_NAME),
+        )?;
+        let json = Base64Bytes::from(header.as_bytes())
+            .decode()
+            .map_err(|_| VerificationError::InvalidPaymentHeader)?;
+        let payload: PaymentPayload =
+            serde_json::from_slice(&json).map_err(|_| VerificationError::InvalidPaymentHeader)?;
+        let payment = signed_payment(&payload, &json);
+
+        let verify_request =
+            V2PriceTag::make_verify_request(payload, &paygate.accepts, &paygate.resource)?;
+        let network = payment.network.clone();
+        let asset = payment.asset.clone();
+        let failed = || self.metrics.payment(&network, PaymentOutcome::Failed);
+        let verify_response = paygate
+            .verify_payment(&verify_request)
+            .await
+            .inspect_err(|_| failed())?;
+        V2PriceTag::validate_verify_response(verify_response).inspect_err(|_| failed())?;
+        self.metrics.payment(&network, PaymentOutcome::Verified);
+
+        // v3 and v4 response signatures cover what was paid
+        req.extensions_mut().insert(payment);
+
+        let response = match inner.oneshot(req).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+        if response.extensions().get::<Unrouted>().is_some() {
+            info!(status = %response.status(), "No backend reached; payment not settled");
+            return Ok(response);
+        }
+        if !self.policy.is_success(response.status()) {
+            info!(status = %response.status(), "Upstream response is not a success; payment not settled");
+            return Ok(response);
+        }
+
+        let (mut response, settle_request, amount) = match &self.metering {
+            Some(metering) => {
+                let (response, amount) = meter_response(metering, response, usdc_amount)
+                    .await
+                    .map_err(PaygateError::Settlement)
+                    .inspect_err(|_| failed())?;
+                (response, with_amount(&verify_request, amount)?, amount)
+            }
+            None => (response, verify_request, usdc_amount),
+        };
+
+        let settlement = paygate
+            .settle_payment(&settle_request)
+            .await
+            .inspect_err(|_| failed())?;
+        self.metrics.payment(&network, PaymentOutcome::Settled);
+        self.metrics.revenue(&self.route, &network, amount);
+        if let CreditUse::TopUp(store) = &self.credit {
+            response = self
+                .credit_top_up(store, &settlement, amount, response)
+                .await;
+        }
+        if let Some(policy) = &self.access_pass {
+            match AccessPass::issue(
+                &settlement,
+                policy.scope.clone(),
+                policy.duration_secs,
+                &self.signing_key,
+            ) {
+                Ok(pass) => {
+                    response
+                        .headers_mut()
+                        .insert(ACCESS_PASS_HEADER, pass.to_header());
+                }
+                Err(e) => warn!(error = %e, "Could not issue an access pass"),
+            }
+        }
+        let receipt = match response.extensions().get::<RequestDigest>() {
+            Some(&request) => {
+                match Receipt::issue(&settlement, amount, request, &self.signing_key) {
+                    Ok(receipt) => {
+                        response
+                            .headers_mut()
+                            .insert(RECEIPT_HEADER, receipt.to_header());
+                        Some(receipt)
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Could not issue a payment receipt");
+                        None
+                    }
+                }
+            }
+            None => {
+                warn!("Request was not fully forwarded; no payment receipt issued");
+                None
+            }
+        };
+
+        if let Some(ledger) = &self.ledger {
+            let field = |name| settlement_field(&settlement, name).unwrap_or_default();
+            let entry = LedgerEntry {
+                timestamp: receipt.as_ref().map_or_else(unix_now, |r| r.timestamp),
+                payer: field("payer"),
+                network,
+                asset,
+                amount,
+                route: self.route.clone(),
+                request_id: receipt.map(|r| r.request_hash).unwrap_or_default(),
+                transaction: field("transaction"),
+                upstream_status: response.status().as_u16(),
+                ..Default::default()
+            };
+            let ledger = ledger.clone();
+            let recorded = tokio::task::spawn_blocking(move || ledger.record(&entry))
+                .await
+                .expect("ledger write panicked");
+            if let Err(e) = recorded {
+                error!(error = %e, "Settled payment is missing from the ledger");
+            }
+        }
+
+        let settlement =
+            serde_json::to_vec(&settlement).map_err(|e| PaygateError::Settlement(e.to_string()))?;
+        let header = HeaderValue::from_bytes(Base64Bytes::encode(settlement).as_ref())
+            .map_err(|e| PaygateError::Settlement(e.to_string()))?;
+        response
+            .headers_mut()
+            .insert(PAYMENT_RESPONSE_HEADER, header);
+        Ok(response)
+    }
+
+    /// Charge an authorized request to the payer's prepaid balance. Its price
+    /// is reserved before the upstream is called, and only what settlement
+    /// would have taken is kept.
+    async fn draw(
+        &self,
+        store: &Arc<CreditStore>,
+        usdc_amount: u64,
+        inner: MethodRouter,
+        req: Request<Body>,
+    ) -> Response {
+        let (authorization, req) = match authorize(req).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+        let payer = authorization.payer;
+        let reserved = {
+            let store = store.clone();
+            tokio::task::spawn_blocking(move || {
+                store.reserve(&authorization, usdc_amount, unix_now())
+            })
+            .await
+            .expect("credit reservation panicked")
+        };
+        match reserved {
+            Ok(()) => {}
+            Err(DrawError::Insufficient { balance }) => {
+                let body = serde_json::json!({
+                    "error": "insufficient credit",
+                    "balance": balance,
+                    "price": usdc_amount,
+                });
+                return (
+                    StatusCode::PAYMENT_REQUIRED,
+                    [(CREDIT_BALANCE_HEADER, balance.to_string())],
+                    Json(body),
+                )
+                    .into_response();
+            }
+            Err(e @ DrawError::Replayed) => {
+                return (StatusCode::UNAUTHORIZED, e.to_string()).into_response();
+            }
+            Err(DrawError::Store(e)) => {
+                error!(error = %e, "Could not draw credit");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+
+        let response = match inner.oneshot(req).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+        let (mut response, charged) = if response.extensions().get::<Unrouted>().is_some() {
+            info!(status = %response.status(), "No backend reached; credit not drawn");
+            (response, 0)
+        } else if !self.policy.is_success(response.status()) {
+            info!(status = %response.status(), "Upstream response is not a success; credit not drawn");
+            (response, 0)
+        } else {
+            match &self.metering {
+                Some(metering) => match meter_response(metering, response, usdc_amount).await {
+                    Ok(metered) => metered,
+                    Err(e) => {
+                        warn!(error = %e, "Could not meter response; credit not drawn");
+                        (StatusCode::BAD_GATEWAY.into_response(), 0)
+                    }
+                },
+                None => (response, usdc_amount),
+            }
+        };
+
+        let request_id = response
+            .extensions()
+            .get::<RequestDigest>()
+            .map(|request| format!("0x{}", hex::encode(request.0)))
+            .unwrap_or_default();
+        let settled = {
+            let store = store.clone();
+            let route = self.route.clone();
+            tokio::task::spawn_blocking(move || {
+                store.settle(payer, usdc_amount, charged, &route, &request_id, unix_now())
+            })
+            .await
+            .expect("credit settlement panicked")
+        };
+        match settled {
+            Ok(balance) => {
+                response
+                    .headers_mut()
+                    .insert(CREDIT_BALANCE_HEADER, HeaderValue::from(balance));
+            }
+            Err(e) => error!(error = %e, "Credit reservation was not settled"),
+        }
+        self.metrics.credit(&self.route, charged);
+        response
+    }
+
+    /// Add a settled top-up to the payer's balance and answer with the new
+    /// balance.
+    async fn credit_top_up(
+        &self,
+        store: &Arc<CreditStore>,
+        settlement: &SettleResponse,
+        amount: u64,
+        response: Response,
+    ) -> Response {
+        let field = |name| settlement_field(settlement, name).unwrap_or_default();
+        let (payer, transaction) = (field("payer"), field("transaction"));
+        let credited = match payer.parse() {
+            Ok(address) => {
+                let store = store.clone();
+                let route = self.route.clone();
+                tokio::task::spawn_blocking(move || {
+                    store.top_up(address, amount, &route, &transaction, unix_now())
+                })
+                .await
+                .expect("top-up panicked")
+            }
+            Err(_) => Err(format!("payer {:?} is not an EVM address", payer)),
+        };
+
+        let mut top_up = match credited {
+            Ok(balance) => {
+                info!(payer = %payer, amount, balance, "Credit topped up");
+                let body = serde_json::json!({
+                    "payer": payer,
+                    "credited": amount,
+                    "balance": balance,
+                });
+                ([(CREDIT_BALANCE_HEADER, balance.to_string())], Json(body)).into_response()
+            }
+            Err(e) => {
+                // The payment is settled; the receipt still proves it
+                error!(error = %e, payer = %payer, amount, "Settled top-up was not credited");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        };
+        if let Some(&request) = response.extensions().get::<RequestDigest>() {
+            top_up.extensions_mut().insert(request);
+        }
+        top_up
+    }
+}
+
+/// Inner service of the top-up route: answers with nothing but the request
+/// digest a receipt names, computed as for an unsigned response.
+pub async fn top_up_request(req: Request<Body>) -> Response {
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |pq| pq.as_str())
+        .to_string();
+    let (parts, body) = req.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_PRICED_BODY_BYTES).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let prefix = build_request_prefix(SignatureVersion::V2, &parts.method, &path_and_query, &body);
+    let mut response = StatusCode::OK.into_response();
+    response
+        .extensions_mut()
+        .insert(RequestDigest(Keccak256::digest(prefix).into()));
+    response
+}
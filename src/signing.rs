@@ -0,0 +1,851 @@
+use http::{HeaderMap, Method, StatusCode};
+use k256::ecdsa::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Response header (or trailer) holding the `r || s || v` signature as hex.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Request header selecting the signed message layout, echoed on responses
+/// signed with anything but the default.
+pub const SIGNATURE_VERSION_HEADER: &str = "X-Signature-Version";
+/// Response header listing the response headers a v4 signature covers.
+pub const SIGNED_HEADERS_HEADER: &str = "X-Signed-Headers";
+/// Response header holding the [`SignedPayment`] a v3 or v4 signature covers.
+pub const SIGNED_PAYMENT_HEADER: &str = "X-Signed-Payment";
+
+/// Response headers covered by a v3 signature, in signing order.
+pub const SIGNED_RESPONSE_HEADERS: [&str; 2] = ["content-type", "content-encoding"];
+
+/// Response headers with the timestamp and nonce a v4 signature covers.
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+pub const SIGNATURE_NONCE_HEADER: &str = "X-Signature-Nonce";
+
+/// Response header announcing that a streamed body ends with a final chunk
+/// holding the hex signature, for clients that did not accept trailers. Its
+/// value is the length of that chunk, [`SIGNATURE_HEX_LEN`].
+pub const SIGNATURE_CHUNK_HEADER: &str = "X-Signature-Chunk";
+/// Length of an `r || s || v` signature as hex.
+pub const SIGNATURE_HEX_LEN: usize = 130;
+
+const SIGNATURE_DOMAIN_V2: &[u8] = b"oyster-signature-v2\0";
+const SIGNATURE_DOMAIN_V3: &[u8] = b"oyster-signature-v3\0";
+const SIGNATURE_DOMAIN_V4: &[u8] = b"oyster-signature-v4\0";
+
+/// Layout of the message behind `X-Signature`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureVersion {
+    /// Request and response bodies only.
+    V2,
+    /// Also the response status, selected response headers and the payment.
+    V3,
+    /// Also a timestamp, a nonce and the configured response headers, with
+    /// each body replaced by its Keccak256 and length so bodies of unknown
+    /// length can be signed as they stream.
+    V4,
+}
+
+impl SignatureVersion {
+    /// Every version the gateway can sign, oldest first.
+    pub const ALL: [Self; 3] = [Self::V2, Self::V3, Self::V4];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::V2 => "oyster-signature-v2",
+            Self::V3 => "oyster-signature-v3",
+            Self::V4 => "oyster-signature-v4",
+        }
+    }
+
+    /// The version named `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|version| name.trim().eq_ignore_ascii_case(version.as_str()))
+    }
+
+    /// The version the client asked for with [`SIGNATURE_VERSION_HEADER`];
+    /// `default` when it asked for none or for one the gateway does not know.
+    pub fn requested(headers: &HeaderMap, default: Self) -> Self {
+        headers
+            .get(SIGNATURE_VERSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::from_name)
+            .unwrap_or(default)
+    }
+
+    fn domain(self) -> &'static [u8] {
+        match self {
+            Self::V2 => SIGNATURE_DOMAIN_V2,
+            Self::V3 => SIGNATURE_DOMAIN_V3,
+            Self::V4 => SIGNATURE_DOMAIN_V4,
+        }
+    }
+
+    /// Whether the message covers a response head: [`encode_response_head`]
+    /// for v3, [`ResponseHead`] for v4.
+    pub fn has_response_head(self) -> bool {
+        self != Self::V2
+    }
+}
+
+/// The payment a paid request was verified with, as bound into v3 and v4
+/// signatures.
+/// The settler attaches it to the request before proxying.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedPayment {
+    pub scheme: String,
+    /// CAIP-2 network, e.g. `eip155:84532`.
+    pub network: String,
+    pub asset: String,
+    /// Accepted amount in token units; the maximum for `upto` payments.
+    pub amount: String,
+    pub pay_to: String,
+    /// `0x`-prefixed Keccak256 of the payment payload the client sent, which
+    /// is what the facilitator settles.
+    pub settlement_reference: String,
+}
+
+/// Encode what a v3 signature covers between the request and response bodies:
+///
+/// ```text
+/// u16be(status) ||
+/// u32be(n) || n × (u32be(len(name)) || name || u32be(len(value)) || value) ||
+/// 6 × (u32be(len(field)) || field)
+/// ```
+///
+/// The headers are [`SIGNED_RESPONSE_HEADERS`], with repeated values joined by
+/// `, ` and missing ones empty. The six fields are those of [`SignedPayment`]
+/// in declaration order, all empty for a request that was not paid.
+pub fn encode_response_head(
+    status: StatusCode,
+    headers: &HeaderMap,
+    payment: Option<&SignedPayment>,
+) -> Vec<u8> {
+    let mut head = Vec::new();
+    head.extend_from_slice(&status.as_u16().to_be_bytes());
+    push_headers(&mut head, headers, SIGNED_RESPONSE_HEADERS);
+    push_payment(&mut head, payment);
+    head
+}
+
+/// What a v4 signature covers between the request and response bodies.
+pub struct ResponseHead<'a> {
+    pub status: StatusCode,
+    /// Unix seconds when the gateway answered.
+    pub timestamp: u64,
+    /// Random per response, so no two signed messages are the same.
+    pub nonce: [u8; 16],
+    pub headers: &'a HeaderMap,
+    /// The `signed_headers` allowlist, picking out `headers` to sign.
+    pub signed_headers: &'a [String],
+    pub payment: Option<&'a SignedPayment>,
+}
+
+impl<'a> ResponseHead<'a> {
+    /// A head stamped with the current time and a fresh nonce.
+    pub fn new(
+        status: StatusCode,
+        headers: &'a HeaderMap,
+        signed_headers: &'a [String],
+        payment: Option<&'a SignedPayment>,
+    ) -> Self {
+        let mut nonce = [0u8; 16];
+        getrandom::fill(&mut nonce).expect("system random number generator failed");
+        Self {
+            status,
+            timestamp: unix_now(),
+            nonce,
+            headers,
+            signed_headers,
+            payment,
+        }
+    }
+
+    /// Lowercase names of the signed headers, as listed in [`SIGNED_HEADERS_HEADER`].
+    pub fn signed_header_names(&self) -> String {
+        self.signed_headers
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Encode the head:
+    ///
+    /// ```text
+    /// u16be(status) || u64be(timestamp) || nonce ||
+    /// u32be(n) || n × (u32be(len(name)) || name || u32be(len(value)) || value) ||
+    /// 6 × (u32be(len(field)) || field)
+    /// ```
+    ///
+    /// Header names are lowercase, repeated values are joined by `, ` and
+    /// missing ones are empty. The six fields are those of [`SignedPayment`]
+    /// in declaration order, all empty for a request that was not paid.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut head = Vec::new();
+        head.extend_from_slice(&self.status.as_u16().to_be_bytes());
+        head.extend_from_slice(&self.timestamp.to_be_bytes());
+        head.extend_from_slice(&self.nonce);
+        let names = self
+            .signed_headers
+            .iter()
+            .map(|name| name.to_ascii_lowercase());
+        push_headers(&mut head, self.headers, names);
+        push_payment(&mut head, self.payment);
+        head
+    }
+}
+
+fn push_headers<I>(head: &mut Vec<u8>, headers: &HeaderMap, names: I)
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let names = names.into_iter();
+    head.extend_from_slice(&(names.len() as u32).to_be_bytes());
+    for name in names {
+        let name = name.as_ref();
+        let value = headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.as_bytes())
+            .collect::<Vec<_>>()
+            .join(&b", "[..]);
+        push_field(head, name.as_bytes());
+        push_field(head, &value);
+    }
+}
+
+fn push_payment(head: &mut Vec<u8>, payment: Option<&SignedPayment>) {
+    let payment = payment.cloned().unwrap_or_default();
+    for field in [
+        &payment.scheme,
+        &payment.network,
+        &payment.asset,
+        &payment.amount,
+        &payment.pay_to,
+        &payment.settlement_reference,
+    ] {
+        push_field(head, field.as_bytes());
+    }
+}
+
+fn push_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+/// The message up to and including the request body:
+///
+/// ```text
+/// version || 0x00 || u32be(len(method)) || method ||
+/// u32be(len(path_and_query)) || path_and_query ||
+/// u64be(len(request_body)) || request_body
+/// ```
+///
+/// v4 has `keccak256(request_body) || u64be(len(request_body))` in place of
+/// the last line. Its Keccak256 is the [`crate::receipt::RequestDigest`]
+/// that receipts name.
+pub fn build_request_prefix(
+    version: SignatureVersion,
+    request_method: &Method,
+    request_path_and_query: &str,
+    request_body: &[u8],
+) -> Vec<u8> {
+    let method = request_method.as_str().as_bytes();
+    let path_and_query = request_path_and_query.as_bytes();
+
+    let mut message = Vec::with_capacity(
+        version.domain().len() + 16 + method.len() + path_and_query.len() + request_body.len(),
+    );
+    message.extend_from_slice(version.domain());
+    message.extend_from_slice(&(method.len() as u32).to_be_bytes());
+    message.extend_from_slice(method);
+    message.extend_from_slice(&(path_and_query.len() as u32).to_be_bytes());
+    message.extend_from_slice(path_and_query);
+    push_body(&mut message, version, request_body);
+    message
+}
+
+fn push_body(message: &mut Vec<u8>, version: SignatureVersion, body: &[u8]) {
+    if version == SignatureVersion::V4 {
+        message.extend_from_slice(&Keccak256::digest(body));
+        message.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        message.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        message.extend_from_slice(body);
+    }
+}
+
+/// Buffered encoding of a whole v2 message. The gateway itself hashes
+/// incrementally with [`SigningHasher`]; tests check both agree.
+pub fn build_signing_message(
+    request_method: &Method,
+    request_path_and_query: &str,
+    request_body: &[u8],
+    response_body: &[u8],
+) -> Vec<u8> {
+    let mut message = build_request_prefix(
+        SignatureVersion::V2,
+        request_method,
+        request_path_and_query,
+        request_body,
+    );
+    push_body(&mut message, SignatureVersion::V2, response_body);
+    message
+}
+
+/// Buffered encoding of a whole v3 message, `response_head` coming from
+/// [`encode_response_head`].
+pub fn build_signing_message_v3(
+    request_method: &Method,
+    request_path_and_query: &str,
+    request_body: &[u8],
+    response_head: &[u8],
+    response_body: &[u8],
+) -> Vec<u8> {
+    build_message_with_head(
+        SignatureVersion::V3,
+        request_method,
+        request_path_and_query,
+        request_body,
+        response_head,
+        response_body,
+    )
+}
+
+/// Buffered encoding of a whole v4 message:
+///
+/// ```text
+/// "oyster-signature-v4" || 0x00 || u32be(len(method)) || method ||
+/// u32be(len(path_and_query)) || path_and_query ||
+/// keccak256(request_body) || u64be(len(request_body)) ||
+/// response_head ||
+/// keccak256(response_body) || u64be(len(response_body))
+/// ```
+///
+/// `response_head` comes from [`ResponseHead::encode`].
+pub fn build_signing_message_v4(
+    request_method: &Method,
+    request_path_and_query: &str,
+    request_body: &[u8],
+    response_head: &[u8],
+    response_body: &[u8],
+) -> Vec<u8> {
+    build_message_with_head(
+        SignatureVersion::V4,
+        request_method,
+        request_path_and_query,
+        request_body,
+        response_head,
+        response_body,
+    )
+}
+
+fn build_message_with_head(
+    version: SignatureVersion,
+    request_method: &Method,
+    request_path_and_query: &str,
+    request_body: &[u8],
+    response_head: &[u8],
+    response_body: &[u8],
+) -> Vec<u8> {
+    let mut message = build_request_prefix(
+        version,
+        request_method,
+        request_path_and_query,
+        request_body,
+    );
+    message.extend_from_slice(response_head);
+    push_body(&mut message, version, response_body);
+    message
+}
+
+/// Sign the Keccak256 of `message`, returning `r || s || v` as hex.
+pub fn sign_message(signing_key: &SigningKey, message: &[u8]) -> String {
+    let mut hasher = Keccak256::new();
+    hasher.update(message);
+    sign_digest(signing_key, hasher)
+}
+
+/// Sign an already-populated Keccak256 state, returning `r || s || v` as hex.
+pub fn sign_digest(signing_key: &SigningKey, hasher: Keccak256) -> String {
+    let (signature, recovery_id) = signing_key
+        .sign_digest_recoverable(hasher)
+        .expect("signing failed");
+
+    let mut sig_bytes = signature.to_vec();
+    sig_bytes.push(recovery_id.to_byte() + 27);
+    hex::encode(sig_bytes)
+}
+
+/// Seconds since the Unix epoch, as signed timestamps carry them.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Error returned when a body does not match the length announced for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyLengthMismatch {
+    pub expected: u64,
+    pub received: u64,
+}
+
+impl fmt::Display for BodyLengthMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "body length mismatch: announced {} bytes, received {}",
+            self.expected, self.received
+        )
+    }
+}
+
+impl std::error::Error for BodyLengthMismatch {}
+
+/// Incremental Keccak256 over the `oyster-signature-v2`, `-v3` or `-v4`
+/// message layout.
+///
+/// v2 and v3 length-prefix each body, so a body has to be announced with
+/// [`SigningHasher::begin_body`] before its bytes are fed in. Hashing the
+/// request body and then the response body this way produces the same digest
+/// as hashing the output of [`build_signing_message`]. v4 also takes bodies
+/// whose length is only known at the end, through
+/// [`SigningHasher::begin_streamed_body`].
+pub struct SigningHasher {
+    hasher: Keccak256,
+    version: SignatureVersion,
+    /// For v2 and v3, the same request in the v4 layout, so the response can
+    /// still be signed with [`SigningHasher::switch_to_v4`] if its length
+    /// turns out to be unknown.
+    fallback: Option<Keccak256>,
+    /// Keccak256 of the body being fed in, for the v4 layout.
+    body: Option<Keccak256>,
+    /// `None` while a streamed body has not been finished.
+    expected: Option<u64>,
+    received: u64,
+    bodies: u8,
+    response_head: Option<Vec<u8>>,
+}
+
+impl SigningHasher {
+    pub fn new(request_method: &Method, request_path_and_query: &str) -> Self {
+        Self::with_version(SignatureVersion::V2, request_method, request_path_and_query)
+    }
+
+    pub fn with_version(
+        version: SignatureVersion,
+        request_method: &Method,
+        request_path_and_query: &str,
+    ) -> Self {
+        let prefix = |version: SignatureVersion| {
+            let method = request_method.as_str().as_bytes();
+            let path_and_query = request_path_and_query.as_bytes();
+
+            let mut hasher = Keccak256::new();
+            hasher.update(version.domain());
+            hasher.update((method.len() as u32).to_be_bytes());
+            hasher.update(method);
+            hasher.update((path_and_query.len() as u32).to_be_bytes());
+            hasher.update(path_and_query);
+            hasher
+        };
+
+        Self {
+            hasher: prefix(version),
+            version,
+            fallback: (version != SignatureVersion::V4).then(|| prefix(SignatureVersion::V4)),
+            body: None,
+            expected: Some(0),
+            received: 0,
+            bodies: 0,
+            response_head: None,
+        }
+    }
+
+    pub fn version(&self) -> SignatureVersion {
+        self.version
+    }
+
+    /// Set what a v3 or v4 message covers ahead of the response body, from
+    /// [`encode_response_head`] or [`ResponseHead::encode`]. Must be called before the response body is
+    /// announced; v2 messages ignore it.
+    pub fn set_response_head(&mut self, head: Vec<u8>) {
+        self.response_head = Some(head);
+    }
+
+    /// Carry on in the v4 layout after a v2 or v3 request body, for a
+    /// response whose length is not known up front. The request body must
+    /// be complete and the response body not yet announced.
+    pub fn switch_to_v4(&mut self) -> Result<(), BodyLengthMismatch> {
+        self.check_complete()?;
+        assert_eq!(self.bodies, 1, "switch to v4 between request and response");
+        self.end_body();
+        if let Some(fallback) = self.fallback.take() {
+            self.hasher = fallback;
+            self.version = SignatureVersion::V4;
+        }
+        Ok(())
+    }
+
+    /// Announce the next body (request first, then response) and its length.
+    pub fn begin_body(&mut self, len: u64) -> Result<(), BodyLengthMismatch> {
+        self.begin(Some(len))
+    }
+
+    /// Announce the next body without its length, which is taken when
+    /// [`SigningHasher::finish_body`] is called. v4 only.
+    pub fn begin_streamed_body(&mut self) -> Result<(), BodyLengthMismatch> {
+        assert_eq!(
+            self.version,
+            SignatureVersion::V4,
+            "only v4 signs bodies of unknown length"
+        );
+        self.begin(None)
+    }
+
+    /// Mark a streamed body as complete at the length fed in so far. Bodies
+    /// announced with their length are left alone.
+    pub fn finish_body(&mut self) {
+        self.expected.get_or_insert(self.received);
+    }
+
+    fn begin(&mut self, len: Option<u64>) -> Result<(), BodyLengthMismatch> {
+        self.check_complete()?;
+        self.end_body();
+        if self.bodies == 1 {
+            // Only the request body is kept for a switch to v4
+            self.fallback = None;
+            if self.version.has_response_head() {
+                let head = self
+                    .response_head
+                    .take()
+                    .expect("response head is set before the response body");
+                self.hasher.update(head);
+            }
+        }
+        self.bodies += 1;
+        match len {
+            Some(len) if self.version != SignatureVersion::V4 => {
+                self.hasher.update(len.to_be_bytes())
+            }
+            _ => {}
+        }
+        if self.version == SignatureVersion::V4 || self.fallback.is_some() {
+            self.body = Some(Keccak256::new());
+        }
+        self.expected = len;
+        self.received = 0;
+        Ok(())
+    }
+
+    /// Hash the digest and length of a finished body into the v4 message.
+    fn end_body(&mut self) {
+        let Some(body) = self.body.take() else {
+            return;
+        };
+        let hasher = match self.version {
+            SignatureVersion::V4 => &mut self.hasher,
+            _ => match self.fallback.as_mut() {
+                Some(fallback) => fallback,
+                None => return,
+            },
+        };
+        hasher.update(body.finalize());
+        hasher.update(self.received.to_be_bytes());
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), BodyLengthMismatch> {
+        let received = self.received + chunk.len() as u64;
+        if let Some(expected) = self.expected
+            && received > expected
+        {
+            return Err(BodyLengthMismatch { expected, received });
+        }
+        self.received = received;
+        if let Some(body) = self.body.as_mut() {
+            body.update(chunk);
+        }
+        if self.version != SignatureVersion::V4 {
+            self.hasher.update(chunk);
+        }
+        Ok(())
+    }
+
+    /// Whether the body announced last has been fed in completely.
+    pub fn body_complete(&self) -> bool {
+        self.expected == Some(self.received)
+    }
+
+    /// Digest of everything hashed so far. Taken after the request body, it
+    /// anchors per-event signatures to the request.
+    pub fn digest(&self) -> Result<[u8; 32], BodyLengthMismatch> {
+        Ok(self.message()?.finalize().into())
+    }
+
+    pub fn sign(&self, signing_key: &SigningKey) -> Result<String, BodyLengthMismatch> {
+        Ok(sign_digest(signing_key, self.message()?))
+    }
+
+    /// The hash state of the message so far, with the last body closed.
+    fn message(&self) -> Result<Keccak256, BodyLengthMismatch> {
+        self.check_complete()?;
+        let mut hasher = self.hasher.clone();
+        if let (SignatureVersion::V4, Some(body)) = (self.version, &self.body) {
+            hasher.update(body.clone().finalize());
+            hasher.update(self.received.to_be_bytes());
+        }
+        Ok(hasher)
+    }
+
+    fn check_complete(&self) -> Result<(), BodyLengthMismatch> {
+        if self.body_complete() {
+            Ok(())
+        } else {
+            Err(BodyLengthMismatch {
+                expected: self.expected.unwrap_or(self.received),
+                received: self.received,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[1u8; 32].into()).unwrap()
+    }
+
+    #[test]
+    fn test_build_signing_message_layout() {
+        let message = build_signing_message(&Method::POST, "/api/chat?x=1", b"req", b"resp");
+        let mut expected = b"oyster-signature-v2\0".to_vec();
+        expected.extend_from_slice(&4u32.to_be_bytes());
+        expected.extend_from_slice(b"POST");
+        expected.extend_from_slice(&13u32.to_be_bytes());
+        expected.extend_from_slice(b"/api/chat?x=1");
+        expected.extend_from_slice(&3u64.to_be_bytes());
+        expected.extend_from_slice(b"req");
+        expected.extend_from_slice(&4u64.to_be_bytes());
+        expected.extend_from_slice(b"resp");
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn test_signing_hasher_matches_buffered_message() {
+        let key = test_signing_key();
+        let message = build_signing_message(&Method::POST, "/api/chat", b"hello", b"world!");
+
+        let mut hasher = SigningHasher::new(&Method::POST, "/api/chat");
+        hasher.begin_body(5).unwrap();
+        hasher.update(b"hel").unwrap();
+        hasher.update(b"lo").unwrap();
+        hasher.begin_body(6).unwrap();
+        hasher.update(b"wor").unwrap();
+        hasher.update(b"ld!").unwrap();
+
+        assert_eq!(hasher.sign(&key).unwrap(), sign_message(&key, &message));
+    }
+
+    #[test]
+    fn test_v3_response_head_layout() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("x-request-id", "1".parse().unwrap());
+        let payment = SignedPayment {
+            scheme: "exact".to_string(),
+            network: "eip155:84532".to_string(),
+            amount: "10".to_string(),
+            ..Default::default()
+        };
+        let head = encode_response_head(StatusCode::CREATED, &headers, Some(&payment));
+
+        let mut expected = 201u16.to_be_bytes().to_vec();
+        expected.extend_from_slice(&2u32.to_be_bytes());
+        expected.extend_from_slice(&12u32.to_be_bytes());
+        expected.extend_from_slice(b"content-type");
+        expected.extend_from_slice(&16u32.to_be_bytes());
+        expected.extend_from_slice(b"application/json");
+        expected.extend_from_slice(&16u32.to_be_bytes());
+        expected.extend_from_slice(b"content-encoding");
+        expected.extend_from_slice(&0u32.to_be_bytes());
+        for field in ["exact", "eip155:84532", "", "10", "", ""] {
+            expected.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            expected.extend_from_slice(field.as_bytes());
+        }
+        assert_eq!(head, expected);
+    }
+
+    #[test]
+    fn test_v4_response_head_layout() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        let payment = SignedPayment {
+            scheme: "exact".to_string(),
+            network: "eip155:84532".to_string(),
+            amount: "10".to_string(),
+            ..Default::default()
+        };
+        let head = ResponseHead {
+            status: StatusCode::CREATED,
+            timestamp: 1_700_000_000,
+            nonce: [9u8; 16],
+            headers: &headers,
+            signed_headers: &["Content-Type".to_string(), "x-request-id".to_string()],
+            payment: Some(&payment),
+        };
+        assert_eq!(head.signed_header_names(), "content-type, x-request-id");
+
+        let mut expected = 201u16.to_be_bytes().to_vec();
+        expected.extend_from_slice(&1_700_000_000u64.to_be_bytes());
+        expected.extend_from_slice(&[9u8; 16]);
+        expected.extend_from_slice(&2u32.to_be_bytes());
+        expected.extend_from_slice(&12u32.to_be_bytes());
+        expected.extend_from_slice(b"content-type");
+        expected.extend_from_slice(&16u32.to_be_bytes());
+        expected.extend_from_slice(b"application/json");
+        expected.extend_from_slice(&12u32.to_be_bytes());
+        expected.extend_from_slice(b"x-request-id");
+        expected.extend_from_slice(&0u32.to_be_bytes());
+        for field in ["exact", "eip155:84532", "", "10", "", ""] {
+            expected.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            expected.extend_from_slice(field.as_bytes());
+        }
+        assert_eq!(head.encode(), expected);
+    }
+
+    #[test]
+    fn test_response_heads_are_unique() {
+        let headers = HeaderMap::new();
+        let first = ResponseHead::new(StatusCode::OK, &headers, &[], None);
+        let second = ResponseHead::new(StatusCode::OK, &headers, &[], None);
+        assert_ne!(first.nonce, second.nonce);
+        assert_ne!(first.encode(), second.encode());
+    }
+
+    #[test]
+    fn test_v3_hasher_matches_buffered_message() {
+        let key = test_signing_key();
+        let head = encode_response_head(StatusCode::OK, &HeaderMap::new(), None);
+        let message =
+            build_signing_message_v3(&Method::POST, "/api/chat", b"hello", &head, b"world!");
+        assert!(message.starts_with(b"oyster-signature-v3\0"));
+
+        let mut hasher =
+            SigningHasher::with_version(SignatureVersion::V3, &Method::POST, "/api/chat");
+        hasher.begin_body(5).unwrap();
+        hasher.update(b"hello").unwrap();
+        hasher.set_response_head(head);
+        hasher.begin_body(6).unwrap();
+        hasher.update(b"world!").unwrap();
+
+        assert_eq!(hasher.sign(&key).unwrap(), sign_message(&key, &message));
+    }
+
+    #[test]
+    fn test_v4_hasher_streams_bodies_of_unknown_length() {
+        let key = test_signing_key();
+        let headers = HeaderMap::new();
+        let head = ResponseHead::new(StatusCode::OK, &headers, &[], None).encode();
+        let message =
+            build_signing_message_v4(&Method::POST, "/api/chat", b"hello", &head, b"world!");
+
+        let mut hasher =
+            SigningHasher::with_version(SignatureVersion::V4, &Method::POST, "/api/chat");
+        hasher.begin_streamed_body().unwrap();
+        hasher.update(b"hel").unwrap();
+        hasher.update(b"lo").unwrap();
+        assert!(hasher.digest().is_err());
+        hasher.finish_body();
+        let prefix =
+            build_request_prefix(SignatureVersion::V4, &Method::POST, "/api/chat", b"hello");
+        assert_eq!(
+            hasher.digest().unwrap(),
+            <[u8; 32]>::from(Keccak256::digest(prefix))
+        );
+
+        hasher.set_response_head(head);
+        hasher.begin_streamed_body().unwrap();
+        hasher.update(b"world!").unwrap();
+        hasher.finish_body();
+
+        assert_eq!(hasher.sign(&key).unwrap(), sign_message(&key, &message));
+    }
+
+    #[test]
+    fn test_switch_to_v4_after_request_body() {
+        let key = test_signing_key();
+        let headers = HeaderMap::new();
+        let head = ResponseHead::new(StatusCode::OK, &headers, &[], None).encode();
+        let message =
+            build_signing_message_v4(&Method::POST, "/api/chat", b"hello", &head, b"world!");
+
+        let mut hasher = SigningHasher::new(&Method::POST, "/api/chat");
+        hasher.begin_body(5).unwrap();
+        hasher.update(b"hello").unwrap();
+        hasher.switch_to_v4().unwrap();
+        assert_eq!(hasher.version(), SignatureVersion::V4);
+        hasher.set_response_head(head);
+        hasher.begin_streamed_body().unwrap();
+        hasher.update(b"world!").unwrap();
+        hasher.finish_body();
+
+        assert_eq!(hasher.sign(&key).unwrap(), sign_message(&key, &message));
+    }
+
+    #[test]
+    fn test_requested_version() {
+        let mut headers = HeaderMap::new();
+        let requested =
+            |headers: &HeaderMap| SignatureVersion::requested(headers, SignatureVersion::V4);
+        assert_eq!(requested(&headers), SignatureVersion::V4);
+        headers.insert(
+            SIGNATURE_VERSION_HEADER,
+            "oyster-signature-v2".parse().unwrap(),
+        );
+        assert_eq!(requested(&headers), SignatureVersion::V2);
+        headers.insert(
+            SIGNATURE_VERSION_HEADER,
+            "oyster-signature-v3".parse().unwrap(),
+        );
+        assert_eq!(requested(&headers), SignatureVersion::V3);
+        headers.insert(
+            SIGNATURE_VERSION_HEADER,
+            "oyster-signature-v9".parse().unwrap(),
+        );
+        assert_eq!(requested(&headers), SignatureVersion::V4);
+    }
+
+    #[test]
+    fn test_signing_hasher_rejects_overlong_body() {
+        let mut hasher = SigningHasher::new(&Method::GET, "/");
+        hasher.begin_body(2).unwrap();
+        let err = hasher.update(b"abc").unwrap_err();
+        assert_eq!(err.expected, 2);
+        assert_eq!(err.received, 3);
+    }
+
+    #[test]
+    fn test_signing_hasher_rejects_short_body() {
+        let mut hasher = SigningHasher::new(&Method::GET, "/");
+        hasher.begin_body(4).unwrap();
+        hasher.update(b"ab").unwrap();
+        assert!(hasher.begin_body(0).is_err());
+        assert!(hasher.sign(&test_signing_key()).is_err());
+    }
+
+    #[test]
+    fn test_sign_message_format() {
+        let sig = sign_message(&test_signing_key(), b"message");
+        let bytes = hex::decode(&sig).unwrap();
+        assert_eq!(bytes.len(), 65);
+        assert!(bytes[64] == 27 || bytes[64] == 28);
+    }
+}
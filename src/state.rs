@@ -1,6 +1,11 @@
 use crate::config::Config;
+use crate::credits::CreditStore;
+use crate::ledger::Ledger;
+use crate::metrics::Metrics;
+use crate::upstream::Pools;
 use k256::ecdsa::SigningKey;
 use std::env;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Clone)]
@@ -8,6 +13,17 @@ pub struct AppState {
     pub config: Config,
     pub http_client: reqwest::Client,
     pub signing_key: SigningKey,
+    /// Where `/.well-known/oyster` fetches the enclave attestation document.
+    pub attestation_url: Option<String>,
+    /// Shared by every config generation.
+    pub metrics: Arc<Metrics>,
+    /// Upstream pools, reused by the next generation where its upstream is
+    /// unchanged.
+    pub pools: Arc<Pools>,
+    /// Settled payments, when `ledger_path` is set. Opened once at startup.
+    pub ledger: Option<Arc<Ledger>>,
+    /// Prepaid balances, when `credits` is set. Opened once at startup.
+    pub credits: Option<Arc<CreditStore>>,
 }
 
 impl AppState {
@@ -19,13 +35,56 @@ impl AppState {
             .expect("Failed to build HTTP client");
 
         Self {
-            config,
             http_client,
             signing_key: load_signing_key().await,
+            attestation_url: attestation_url(),
+            metrics: Arc::new(Metrics::new()),
+            pools: Default::default(),
+            ledger: config.ledger_path.as_deref().map(open_ledger),
+            credits: config
+                .credits
+                .as_ref()
+                .map(|credits| open_credits(&credits.db_path)),
+            config,
+        }
+    }
+
+    /// State for a reloaded config, keeping the HTTP client, signing key,
+    /// metrics, upstream pools, ledger and credit store.
+    pub fn with_config(&self, config: Config) -> Self {
+        Self {
+            config,
+            http_client: self.http_client.clone(),
+            signing_key: self.signing_key.clone(),
+            attestation_url: self.attestation_url.clone(),
+            metrics: self.metrics.clone(),
+            pools: self.pools.clone(),
+            ledger: self.ledger.clone(),
+            credits: self.credits.clone(),
         }
     }
 }
 
+/// `ATTESTATION_URL` if set (empty disables attestation); otherwise the
+/// enclave attestation server, unless the signing key came from the
+/// environment and so is not bound to an enclave.
+fn attestation_url() -> Option<String> {
+    match env::var("ATTESTATION_URL") {
+        Ok(url) if url.is_empty() => None,
+        Ok(url) => Some(url),
+        Err(_) if env::var("SIGNING_PRIVATE_KEY_HEX").is_ok() => None,
+        Err(_) => Some("http://127.0.0.1:1300/attestation/raw".to_string()),
+    }
+}
+
+fn open_ledger(path: &str) -> Arc<Ledger> {
+    Arc::new(Ledger::open(path).unwrap_or_else(|e| panic!("{}", e)))
+}
+
+fn open_credits(path: &str) -> Arc<CreditStore> {
+    Arc::new(CreditStore::open(path).unwrap_or_else(|e| panic!("{}", e)))
+}
+
 async fn load_signing_key() -> SigningKey {
     if let Ok(private_key_hex) = env::var("SIGNING_PRIVATE_KEY_HEX") {
         let decoded = hex::decode(private_key_hex)
@@ -61,7 +120,8 @@ async fn load_signing_key() -> SigningKey {
 mod tests {
     use super::*;
     use crate::config::ProtectedRoute;
-    use std::sync::{Mutex, OnceLock};
+    use std::sync::OnceLock;
+    use tokio::sync::Mutex;
 
     fn env_lock() -> &'static Mutex<()> {
         static ENV_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
@@ -72,18 +132,20 @@ mod tests {
         Config {
             gateway_port: 8080,
             facilitator_url: "https://example.com".to_string(),
-            target_api_url: "http://localhost:3001".to_string(),
+            target_api_url: Some("http://localhost:3001".to_string()),
             networks: vec![],
             protected_routes: vec![ProtectedRoute {
                 path: "/paid".to_string(),
-                usdc_amount: 100,
+                usdc_amount: Some(100),
+                ..Default::default()
             }],
+            ..Default::default()
         }
     }
 
     #[tokio::test]
     async fn test_app_state_new() {
-        let _guard = env_lock().lock().unwrap();
+        let _guard = env_lock().lock().await;
         let config = make_test_config();
         unsafe {
             std::env::set_var(
@@ -94,9 +156,12 @@ mod tests {
         let state = AppState::new(config).await;
         assert_eq!(state.config.gateway_port, 8080);
         assert_eq!(state.config.facilitator_url, "https://example.com");
-        assert_eq!(state.config.target_api_url, "http://localhost:3001");
+        assert_eq!(
+            state.config.target_api_url.as_deref(),
+            Some("http://localhost:3001")
+        );
         assert_eq!(state.config.protected_routes.len(), 1);
-        assert_eq!(state.config.protected_routes[0].usdc_amount, 100);
+        assert_eq!(state.config.protected_routes[0].usdc_amount, Some(100));
         unsafe {
             std::env::remove_var("SIGNING_PRIVATE_KEY_HEX");
         }
@@ -104,7 +169,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_app_state_clone() {
-        let _guard = env_lock().lock().unwrap();
+        let _guard = env_lock().lock().await;
         let config = make_test_config();
         unsafe {
             std::env::set_var(
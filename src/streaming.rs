@@ -0,0 +1,355 @@
+use crate::metrics::Metrics;
+use axum::{
+    BoxError,
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, header},
+};
+use futures_util::{Stream, StreamExt, future, stream};
+use http_body::Frame;
+use http_body_util::StreamBody;
+use k256::ecdsa::SigningKey;
+use std::{
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tracing::warn;
+use x402_gateway::events::{EventFormat, EventSigner, MAX_EVENT_BYTES};
+use x402_gateway::signing::{SIGNATURE_HEADER, SIGNATURE_HEX_LEN, SigningHasher};
+
+/// Signing state shared between the request body that is being uploaded and
+/// the response body that is being streamed back.
+pub type SharedHasher = Arc<Mutex<SigningHasher>>;
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send>>;
+
+/// Bodies up to this size are read whole: a response so its signature can go
+/// in the `X-Signature` header, and a chunked upload so v2 and v3 can prefix
+/// it with its length.
+pub const MAX_BUFFERED_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Response extension counting the body bytes the upstream sent, without the
+/// signature records or final chunk the gateway adds. The count is complete
+/// once the response body has been read to the end.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamBytes(Arc<AtomicU64>);
+
+impl UpstreamBytes {
+    pub fn add(&self, len: usize) {
+        self.0.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether the client announced that it can receive trailer fields (`TE: trailers`).
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("trailers"))
+}
+
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Read `body` to its end if it is at most `limit` bytes. A longer body comes
+/// back as `Err`, a body that replays what was read and streams the rest.
+pub async fn buffer_body(body: Body, limit: u64) -> Result<Result<Bytes, Body>, axum::Error> {
+    let mut chunks = body.into_data_stream();
+    let mut buffered = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        buffered.extend_from_slice(&chunk?);
+        if buffered.len() as u64 > limit {
+            let read = stream::once(future::ready(Ok(Bytes::from(buffered))));
+            return Ok(Err(Body::from_stream(read.chain(chunks))));
+        }
+    }
+    Ok(Ok(Bytes::from(buffered)))
+}
+
+/// Forward the client's request body chunk by chunk while feeding it into the hasher.
+///
+/// The body must already have been announced on the hasher, with its
+/// declared length or as a streamed body that is finished when the client's
+/// body ends. A body that runs past its declared length is aborted.
+pub fn hash_request_body(body: Body, hasher: SharedHasher) -> reqwest::Body {
+    let end = hasher.clone();
+    let chunks = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk.map_err(BoxError::from)?;
+        hasher
+            .lock()
+            .expect("signing hasher poisoned")
+            .update(&chunk)?;
+        Ok::<_, BoxError>(chunk)
+    });
+    let end = stream::once(async move {
+        end.lock().expect("signing hasher poisoned").finish_body();
+        None
+    })
+    .filter_map(future::ready);
+    reqwest::Body::wrap_stream(chunks.chain(end))
+}
+
+/// Where the whole-response `X-Signature` goes once the body has been hashed.
+pub enum SignatureDelivery {
+    /// An HTTP trailer, for clients that sent `TE: trailers`.
+    Trailer,
+    /// A final chunk after the body, announced in `X-Signature-Chunk`. It is
+    /// all zeros when the body could not be signed, so clients can always
+    /// strip it.
+    FinalChunk,
+    /// The body is forwarded as is.
+    Unsigned,
+}
+
+/// How a streamed response body is signed.
+pub struct StreamSigning {
+    pub signature: SignatureDelivery,
+    /// Append a chained signature record after every event of this format.
+    pub events: Option<EventFormat>,
+}
+
+/// Stream the upstream response back to the client as it arrives.
+///
+/// The body is hashed as it passes through and the whole-response
+/// `X-Signature` is delivered as [`StreamSigning::signature`] says once the
+/// last chunk has been hashed. A body of unknown length needs a hasher in the
+/// v4 layout, which takes the length at the end.
+///
+/// With [`StreamSigning::events`] every event is forwarded as soon as it is
+/// complete, followed by its own signature record. The whole-response
+/// signature always covers the upstream bytes, without those records.
+pub fn signed_stream_body<S, E>(
+    upstream: S,
+    hasher: SharedHasher,
+    response_length: Option<u64>,
+    signing_key: SigningKey,
+    metrics: Arc<Metrics>,
+    signing: StreamSigning,
+) -> Body
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError>,
+{
+    let state = StreamSigner {
+        upstream: Box::pin(upstream.map(|chunk| chunk.map_err(Into::into))),
+        hasher,
+        response_length,
+        signature: signing.signature,
+        events: signing.events.map(Events::Pending),
+        started: false,
+        ended: false,
+        failed: false,
+        signing_key,
+        metrics,
+    };
+
+    let frames = stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            if state.ended {
+                return state.finish().map(|frame| (Ok(frame), None));
+            }
+            match state.upstream.next().await {
+                Some(Ok(chunk)) => {
+                    let out = state.absorb(chunk);
+                    if !out.is_empty() {
+                        return Some((Ok(Frame::data(out)), Some(state)));
+                    }
+                }
+                Some(Err(err)) => return Some((Err(err), None)),
+                None => {
+                    state.ended = true;
+                    let tail = state.flush_events();
+                    if !tail.is_empty() {
+                        return Some((Ok(Frame::data(tail)), Some(state)));
+                    }
+                }
+            }
+        }
+    });
+
+    Body::new(StreamBody::new(frames))
+}
+
+enum Events {
+    /// Waiting for the first chunk; the request digest is taken then.
+    Pending(EventFormat),
+    Active(Box<EventSigner>),
+    /// Events are forwarded untouched because the request could not be
+    /// anchored or an event was too long to hold back.
+    Disabled,
+}
+
+struct StreamSigner {
+    upstream: ByteStream,
+    hasher: SharedHasher,
+    response_length: Option<u64>,
+    signature: SignatureDelivery,
+    events: Option<Events>,
+    started: bool,
+    ended: bool,
+    failed: bool,
+    signing_key: SigningKey,
+    metrics: Arc<Metrics>,
+}
+
+impl StreamSigner {
+    fn absorb(&mut self, chunk: Bytes) -> Bytes {
+        if let Some(Events::Pending(format)) = self.events {
+            let anchor = self
+                .hasher
+                .lock()
+                .expect("signing hasher poisoned")
+                .digest();
+            self.events = Some(match anchor {
+                Ok(anchor) => Events::Active(Box::new(EventSigner::new(
+                    format,
+                    anchor,
+                    self.signing_key.clone(),
+                ))),
+                Err(err) => {
+                    warn!(error = %err, "Request body was not fully forwarded before the response; not signing events");
+                    Events::Disabled
+                }
+            });
+        }
+
+        if !matches!(self.signature, SignatureDelivery::Unsigned) {
+            self.hash(&chunk);
+        }
+
+        let Some(Events::Active(signer)) = self.events.as_mut() else {
+            return chunk;
+        };
+        let out = Bytes::from(signer.push(&chunk));
+        if signer.is_passthrough() {
+            warn!(
+                limit = MAX_EVENT_BYTES,
+                "Event is too long to hold back; forwarding the rest without records"
+            );
+            self.events = Some(Events::Disabled);
+        }
+        out
+    }
+
+    fn flush_events(&mut self) -> Bytes {
+        match self.events.as_mut() {
+            Some(Events::Active(signer)) => Bytes::from(signer.flush()),
+            _ => Bytes::new(),
+        }
+    }
+
+    fn hash(&mut self, chunk: &[u8]) {
+        self.start();
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self
+            .hasher
+            .lock()
+            .expect("signing hasher poisoned")
+            .update(chunk)
+        {
+            warn!(error = %err, "Upstream response overran its Content-Length; not signing");
+            self.failed = true;
+        }
+    }
+
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        let mut hasher = self.hasher.lock().expect("signing hasher poisoned");
+        let begun = match self.response_length {
+            Some(len) => hasher.begin_body(len),
+            None => hasher.begin_streamed_body(),
+        };
+        if let Err(err) = begun {
+            warn!(error = %err, "Request body was not fully forwarded before the response; not signing");
+            self.failed = true;
+        }
+    }
+
+    fn finish(mut self) -> Option<Frame<Bytes>> {
+        let signature = self.sign();
+        match self.signature {
+            SignatureDelivery::Trailer => {
+                let mut trailers = HeaderMap::new();
+                trailers.insert(
+                    SIGNATURE_HEADER,
+                    HeaderValue::from_str(&signature?).expect("hex is a valid header value"),
+                );
+                Some(Frame::trailers(trailers))
+            }
+            SignatureDelivery::FinalChunk => {
+                let signature = signature.unwrap_or_else(|| "0".repeat(SIGNATURE_HEX_LEN));
+                Some(Frame::data(Bytes::from(signature)))
+            }
+            SignatureDelivery::Unsigned => None,
+        }
+    }
+
+    fn sign(&mut self) -> Option<String> {
+        if matches!(self.signature, SignatureDelivery::Unsigned) {
+            return None;
+        }
+        self.start();
+        if self.failed {
+            return None;
+        }
+
+        let signature = {
+            let mut hasher = self.hasher.lock().expect("signing hasher poisoned");
+            hasher.finish_body();
+            self.metrics.time_signing(|| hasher.sign(&self.signing_key))
+        };
+        match signature {
+            Ok(signature) => Some(signature),
+            Err(err) => {
+                warn!(error = %err, "Upstream response ended early; not signing");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_trailers() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_trailers(&headers));
+        headers.insert(header::TE, HeaderValue::from_static("gzip, Trailers"));
+        assert!(accepts_trailers(&headers));
+        headers.insert(header::TE, HeaderValue::from_static("gzip"));
+        assert!(!accepts_trailers(&headers));
+    }
+
+    #[test]
+    fn test_content_length() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(content_length(&headers), None);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("42"));
+        assert_eq!(content_length(&headers), Some(42));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("nope"));
+        assert_eq!(content_length(&headers), None);
+    }
+}
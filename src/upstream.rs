@@ -0,0 +1,543 @@
+use crate::config::{Balance, Config, HealthCheck, PassiveHealth, Upstream, UpstreamTarget};
+use axum::{
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Marks a response the gateway produced because no backend could be reached.
+/// Such a response is never settled, whatever the settlement policy says.
+#[derive(Debug, Clone, Copy)]
+pub struct Unrouted;
+
+pub fn unrouted(status: StatusCode) -> Response {
+    let mut response = status.into_response();
+    response.extensions_mut().insert(Unrouted);
+    response
+}
+
+/// One replica of an upstream and what the gateway knows about its health.
+struct Backend {
+    url: String,
+    /// Result of the last active probe; backends start out healthy.
+    healthy: AtomicBool,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    in_flight: AtomicUsize,
+    /// Moving average of the time to response headers; 0 until measured.
+    latency_micros: AtomicU64,
+}
+
+impl Backend {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            healthy: AtomicBool::new(true),
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            in_flight: AtomicUsize::new(0),
+            latency_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn available(&self, now: Instant) -> bool {
+        let ejected = self
+            .ejected_until
+            .lock()
+            .expect("ejection lock poisoned")
+            .is_some_and(|until| now < until);
+        self.healthy.load(Ordering::Relaxed) && !ejected
+    }
+}
+
+/// The backends of one upstream and the policy for picking between them.
+pub struct Pool {
+    name: String,
+    /// What the pool was built from, so a reload can tell whether to keep it.
+    upstream: Upstream,
+    backends: Vec<Backend>,
+    balance: Balance,
+    passive: Option<PassiveHealth>,
+    next: AtomicUsize,
+    /// Running weights of the smooth weighted round-robin used by
+    /// [`Balance::LatencyWeighted`].
+    weights: Mutex<Vec<i64>>,
+}
+
+impl Pool {
+    pub fn new(name: &str, upstream: &Upstream) -> Self {
+        let backends: Vec<Backend> = upstream.urls().map(Backend::new).collect();
+        Self {
+            name: name.to_string(),
+            upstream: upstream.clone(),
+            weights: Mutex::new(vec![0; backends.len()]),
+            backends,
+            balance: upstream.balance,
+            passive: upstream.passive_health.clone(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick a backend for one request; `None` when every backend is down.
+    fn pick(self: &Arc<Self>) -> Option<Lease> {
+        let now = Instant::now();
+        let available: Vec<usize> = (0..self.backends.len())
+            .filter(|&i| self.backends[i].available(now))
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+        // Rotating start so ties are spread over the backends
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut rotated = (0..available.len()).map(|k| available[(start + k) % available.len()]);
+
+        let index = match self.balance {
+            Balance::RoundRobin => available[start % available.len()],
+            Balance::LeastConnections => rotated
+                .min_by_key(|&i| self.backends[i].in_flight.load(Ordering::Relaxed))
+                .expect("at least one backend is available"),
+            Balance::LatencyWeighted => {
+                let unmeasured =
+                    rotated.find(|&i| self.backends[i].latency_micros.load(Ordering::Relaxed) == 0);
+                unmeasured.unwrap_or_else(|| self.pick_by_latency(&available))
+            }
+        };
+        self.backends[index]
+            .in_flight
+            .fetch_add(1, Ordering::Relaxed);
+        Some(Lease {
+            pool: self.clone(),
+            index,
+            started: Instant::now(),
+        })
+    }
+
+    /// Smooth weighted round-robin with weights inversely proportional to
+    /// latency: a backend twice as fast gets twice the requests.
+    fn pick_by_latency(&self, available: &[usize]) -> usize {
+        let mut weights = self.weights.lock().expect("weights lock poisoned");
+        let mut total = 0;
+        let mut best = available[0];
+        for &i in available {
+            let latency = self.backends[i]
+                .latency_micros
+                .load(Ordering::Relaxed)
+                .max(1);
+            let weight = (1_000_000_000 / latency).max(1) as i64;
+            weights[i] += weight;
+            total += weight;
+            if weights[i] > weights[best] {
+                best = i;
+            }
+        }
+        weights[best] -= total;
+        best
+    }
+
+    fn record_success(&self, index: usize, latency: Duration) {
+        let backend = &self.backends[index];
+        backend.consecutive_failures.store(0, Ordering::Relaxed);
+        let sample = (latency.as_micros() as u64).max(1);
+        let _ = backend
+            .latency_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+                Some(if old == 0 {
+                    sample
+                } else {
+                    (old * 7 + sample) / 8
+                })
+            });
+    }
+
+    fn record_failure(&self, index: usize) {
+        let Some(passive) = &self.passive else {
+            return;
+        };
+        let backend = &self.backends[index];
+        let failures = backend.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= passive.max_failures {
+            backend.consecutive_failures.store(0, Ordering::Relaxed);
+            let until = Instant::now() + Duration::from_secs(passive.ejection_secs);
+            *backend
+                .ejected_until
+                .lock()
+                .expect("ejection lock poisoned") = Some(until);
+            warn!(upstream = %self.name, backend = %backend.url, failures, seconds = passive.ejection_secs, "Ejecting failing backend");
+        }
+    }
+
+    /// Probe every backend at the configured interval until the pool is
+    /// dropped with the router that owns it.
+    fn spawn_probes(self: &Arc<Self>, check: HealthCheck, client: reqwest::Client) {
+        let weak = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(check.interval_secs));
+            loop {
+                ticker.tick().await;
+                let Some(pool) = weak.upgrade() else {
+                    break;
+                };
+                for backend in &pool.backends {
+                    let healthy = client
+                        .get(format!("{}{}", backend.url, check.path))
+                        .timeout(Duration::from_secs(check.timeout_secs))
+                        .send()
+                        .await
+                        .is_ok_and(|response| response.status().as_u16() < 400);
+                    if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                        if healthy {
+                            info!(upstream = %pool.name, backend = %backend.url, "Backend passed its health check");
+                        } else {
+                            warn!(upstream = %pool.name, backend = %backend.url, "Backend failed its health check");
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// A backend picked for one request. Counts as in flight until dropped.
+pub struct Lease {
+    pool: Arc<Pool>,
+    index: usize,
+    started: Instant,
+}
+
+impl Lease {
+    pub fn backend_url(&self) -> &str {
+        &self.pool.backends[self.index].url
+    }
+
+    /// The backend answered; `502`, `503` and `504` count as failures.
+    pub fn record_response(&self, status: StatusCode) {
+        match status {
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => self.pool.record_failure(self.index),
+            _ => self.pool.record_success(self.index, self.started.elapsed()),
+        }
+    }
+
+    /// The backend could not be reached.
+    pub fn record_failure(&self) {
+        self.pool.record_failure(self.index);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.backends[self.index]
+            .in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The pools of the running config by upstream name, kept across reloads so
+/// that health and ejection state survive them.
+#[derive(Default)]
+pub struct Pools(Mutex<HashMap<String, Arc<Pool>>>);
+
+/// The upstream pools of one config generation, shared by its routes.
+pub struct Upstreams {
+    pools: HashMap<String, Arc<Pool>>,
+}
+
+impl Upstreams {
+    /// Take a pool per upstream from `previous` when its config is unchanged,
+    /// or build one and start its health probes. `previous` is left holding
+    /// this generation's pools. The config must have passed
+    /// [`Config::validate`].
+    pub fn new(config: &Config, client: &reqwest::Client, previous: &Pools) -> Self {
+        let named = config
+            .upstreams
+            .keys()
+            .map(|name| Some(name.as_str()))
+            .chain([None]);
+        let mut previous = previous.0.lock().expect("pools lock poisoned");
+        let mut pools = HashMap::new();
+        for name in named {
+            let (name, upstream) = config.upstream(name).expect("config validated upstream");
+            if pools.contains_key(name) {
+                continue;
+            }
+            let pool = match previous.get(name) {
+                Some(pool) if pool.upstream == *upstream => pool.clone(),
+                _ => {
+                    let pool = Arc::new(Pool::new(name, &upstream));
+                    if let Some(check) = &upstream.health_check {
+                        pool.spawn_probes(check.clone(), client.clone());
+                    }
+                    pool
+                }
+            };
+            pools.insert(name.to_string(), pool);
+        }
+        previous.clone_from(&pools);
+        Self { pools }
+    }
+
+    /// Where requests for a route with `target` go.
+    pub fn forward(&self, config: &Config, target: &UpstreamTarget) -> Forward {
+        let (name, _) = config
+            .upstream(target.upstream.as_deref())
+            .expect("config validated upstream");
+        Forward {
+            pool: self.pools[name].clone(),
+            strip_prefix: target.strip_prefix.clone(),
+            rewrite_prefix: target.rewrite_prefix.clone(),
+        }
+    }
+}
+
+/// Where a request is proxied: the upstream pool and the path rewrite of the
+/// route it matched. The router attaches it to every request as an extension.
+#[derive(Clone)]
+pub struct Forward {
+    pool: Arc<Pool>,
+    strip_prefix: Option<String>,
+    rewrite_prefix: Option<String>,
+}
+
+impl Forward {
+    /// The forward the router attached to `req`; a request without one
+    /// did not come through the router and is an internal error.
+    pub fn for_request<B>(req: &Request<B>) -> Result<Self, StatusCode> {
+        req.extensions().get::<Self>().cloned().ok_or_else(|| {
+            error!(path = %req.uri().path(), "Request reached the proxy without a route");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.pool.name
+    }
+
+    /// Pick a backend for one request; `None` when every backend is down.
+    pub fn pick(&self) -> Option<Lease> {
+        self.pool.pick()
+    }
+
+    /// Path sent upstream: `strip_prefix` removed when it matches whole
+    /// segments, then `rewrite_prefix` put in front.
+    fn upstream_path(&self, path: &str) -> String {
+        let rest = self
+            .strip_prefix
+            .as_deref()
+            .and_then(|prefix| path.strip_prefix(prefix))
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(path);
+        let path = format!("{}{}", self.rewrite_prefix.as_deref().unwrap_or(""), rest);
+        if path.is_empty() {
+            "/".to_string()
+        } else {
+            path
+        }
+    }
+
+    /// URL on the leased backend for a request path and query.
+    pub fn url(&self, lease: &Lease, path: &str, query: Option<&str>) -> String {
+        let query = query.map(|q| format!("?{}", q)).unwrap_or_default();
+        format!(
+            "{}{}{}",
+            lease.backend_url(),
+            self.upstream_path(path),
+            query
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(urls: &[&str], balance: Balance) -> Upstream {
+        Upstream {
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            balance,
+            ..Default::default()
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            upstreams: [
+                (
+                    "ollama".to_string(),
+                    Upstream {
+                        url: Some("http://127.0.0.1:11434".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "search".to_string(),
+                    Upstream {
+                        url: Some("http://search.internal/api/".to_string()),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+            fallback_upstream: Some("ollama".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn target(upstream: &str, strip: Option<&str>, rewrite: Option<&str>) -> UpstreamTarget {
+        UpstreamTarget {
+            upstream: Some(upstream.to_string()),
+            strip_prefix: strip.map(str::to_string),
+            rewrite_prefix: rewrite.map(str::to_string),
+        }
+    }
+
+    fn url(forward: &Forward, path: &str, query: Option<&str>) -> String {
+        forward.url(&forward.pick().unwrap(), path, query)
+    }
+
+    #[tokio::test]
+    async fn test_fallback_keeps_path() {
+        let config = config();
+        let upstreams = Upstreams::new(&config, &reqwest::Client::new(), &Pools::default());
+        let forward = upstreams.forward(&config, &UpstreamTarget::default());
+        assert_eq!(forward.upstream(), "ollama");
+        assert_eq!(
+            url(&forward, "/api/chat", Some("stream=true")),
+            "http://127.0.0.1:11434/api/chat?stream=true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strip_and_rewrite_prefix() {
+        let config = config();
+        let upstreams = Upstreams::new(&config, &reqwest::Client::new(), &Pools::default());
+        let strip = upstreams.forward(&config, &target("search", Some("/search"), None));
+        assert_eq!(
+            url(&strip, "/search/web", None),
+            "http://search.internal/api/web"
+        );
+        assert_eq!(url(&strip, "/search", None), "http://search.internal/api/");
+        // Only whole segments are stripped
+        assert_eq!(
+            url(&strip, "/searches", None),
+            "http://search.internal/api/searches"
+        );
+
+        let rewrite = upstreams.forward(&config, &target("search", Some("/search"), Some("/v2")));
+        assert_eq!(
+            url(&rewrite, "/search/web", Some("q=rust")),
+            "http://search.internal/api/v2/web?q=rust"
+        );
+        assert_eq!(
+            url(&rewrite, "/search", None),
+            "http://search.internal/api/v2"
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = Arc::new(Pool::new(
+            "ollama",
+            &upstream(&["http://a", "http://b", "http://c"], Balance::RoundRobin),
+        ));
+        let picked: Vec<String> = (0..4)
+            .map(|_| pool.pick().unwrap().backend_url().to_string())
+            .collect();
+        assert_eq!(picked, ["http://a", "http://b", "http://c", "http://a"]);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = Arc::new(Pool::new(
+            "ollama",
+            &upstream(&["http://a", "http://b"], Balance::LeastConnections),
+        ));
+        let first = pool.pick().unwrap();
+        let second = pool.pick().unwrap();
+        assert_ne!(first.backend_url(), second.backend_url());
+
+        // `a` finishes; it is the only idle backend left
+        let busy = second.backend_url().to_string();
+        drop(first);
+        for _ in 0..3 {
+            let lease = pool.pick().unwrap();
+            assert_ne!(lease.backend_url(), busy);
+        }
+    }
+
+    #[test]
+    fn test_latency_weighted() {
+        let pool = Arc::new(Pool::new(
+            "ollama",
+            &upstream(&["http://fast", "http://slow"], Balance::LatencyWeighted),
+        ));
+        pool.record_success(0, Duration::from_millis(10));
+        pool.record_success(1, Duration::from_millis(40));
+
+        let fast = (0..400)
+            .filter(|_| pool.pick().unwrap().backend_url() == "http://fast")
+            .count();
+        assert_eq!(fast, 320);
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let mut config = upstream(&["http://a", "http://b"], Balance::RoundRobin);
+        config.passive_health = Some(PassiveHealth {
+            max_failures: 2,
+            ejection_secs: 60,
+        });
+        let pool = Arc::new(Pool::new("ollama", &config));
+
+        pool.record_failure(0);
+        assert!(pool.backends[0].available(Instant::now()));
+        pool.record_failure(0);
+        assert!(!pool.backends[0].available(Instant::now()));
+        for _ in 0..3 {
+            assert_eq!(pool.pick().unwrap().backend_url(), "http://b");
+        }
+
+        pool.backends[1].healthy.store(false, Ordering::Relaxed);
+        assert!(pool.pick().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pools_survive_reload() {
+        let mut config = config();
+        config.upstreams.get_mut("ollama").unwrap().passive_health = Some(PassiveHealth {
+            max_failures: 1,
+            ejection_secs: 60,
+        });
+        let client = reqwest::Client::new();
+        let pools = Pools::default();
+        let fallback = UpstreamTarget::default();
+
+        let upstreams = Upstreams::new(&config, &client, &pools);
+        upstreams
+            .forward(&config, &fallback)
+            .pick()
+            .unwrap()
+            .record_failure();
+
+        // Same upstream: the ejection carries over
+        let reloaded = Upstreams::new(&config, &client, &pools);
+        assert!(reloaded.forward(&config, &fallback).pick().is_none());
+
+        // Changed upstream: a fresh pool
+        config.upstreams.get_mut("ollama").unwrap().url =
+            Some("http://127.0.0.1:11435".to_string());
+        let changed = Upstreams::new(&config, &client, &pools);
+        assert_eq!(
+            url(&changed.forward(&config, &fallback), "/", None),
+            "http://127.0.0.1:11435/"
+        );
+    }
+}
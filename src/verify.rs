@@ -0,0 +1,390 @@
+use crate::receipt::RequestDigest;
+use crate::signing::{
+    ResponseHead, SIGNATURE_NONCE_HEADER, SIGNATURE_TIMESTAMP_HEADER, SIGNATURE_VERSION_HEADER,
+    SIGNED_HEADERS_HEADER, SIGNED_PAYMENT_HEADER, SignatureVersion, SignedPayment,
+    build_request_prefix, build_signing_message, build_signing_message_v3,
+    build_signing_message_v4, encode_response_head,
+};
+use alloy_primitives::Address;
+use http::{HeaderMap, Method, StatusCode};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+use std::fmt;
+
+/// Why a signature could not be checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The signature is not 65 bytes of `r || s || v` hex with `v` 27 or 28.
+    MalformedSignature,
+    /// No public key recovers from the signature and message.
+    Unrecoverable,
+    /// The signed message could not be rebuilt from what the gateway sent.
+    Message(String),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedSignature => write!(f, "expected a 65-byte r || s || v signature"),
+            Self::Unrecoverable => write!(f, "no public key recovers from the signature"),
+            Self::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Split `r || s || v` hex, as the gateway writes it, into the signature and
+/// its recovery ID.
+pub fn parse_signature(signature_hex: &str) -> Result<(Signature, RecoveryId), VerifyError> {
+    let bytes = hex::decode(signature_hex.trim()).map_err(|_| VerifyError::MalformedSignature)?;
+    if bytes.len() != 65 {
+        return Err(VerifyError::MalformedSignature);
+    }
+    let signature =
+        Signature::from_slice(&bytes[..64]).map_err(|_| VerifyError::MalformedSignature)?;
+    let recovery_id = bytes[64]
+        .checked_sub(27)
+        .and_then(RecoveryId::from_byte)
+        .ok_or(VerifyError::MalformedSignature)?;
+    Ok((signature, recovery_id))
+}
+
+/// The key that signed an already-populated Keccak256 state.
+pub fn recover_digest(digest: Keccak256, signature_hex: &str) -> Result<VerifyingKey, VerifyError> {
+    let (signature, recovery_id) = parse_signature(signature_hex)?;
+    VerifyingKey::recover_from_digest(digest, &signature, recovery_id)
+        .map_err(|_| VerifyError::Unrecoverable)
+}
+
+/// The key that signed the Keccak256 of `message`.
+pub fn recover_message(message: &[u8], signature_hex: &str) -> Result<VerifyingKey, VerifyError> {
+    recover_digest(Keccak256::new_with_prefix(message), signature_hex)
+}
+
+/// Uncompressed public key without the `04` prefix, as hex. This is what
+/// `/.well-known/oyster`, the verifier and `oyster-cvm kms-derive` print.
+pub fn public_key_hex(key: &VerifyingKey) -> String {
+    hex::encode(&key.to_encoded_point(false).as_bytes()[1..])
+}
+
+/// Ethereum address of `key`.
+pub fn address(key: &VerifyingKey) -> Address {
+    Address::from_raw_public_key(&key.to_encoded_point(false).as_bytes()[1..])
+}
+
+/// A response as the client received it, with the request it answers.
+pub struct SignedResponse<'a> {
+    pub method: &'a Method,
+    /// The path and query the client sent, not any upstream rewrite of it.
+    pub path_and_query: &'a str,
+    pub request_body: &'a [u8],
+    pub status: StatusCode,
+    pub headers: &'a HeaderMap,
+    pub body: &'a [u8],
+}
+
+impl SignedResponse<'_> {
+    /// The layout named by the response's [`SIGNATURE_VERSION_HEADER`]; v2
+    /// when it names none.
+    pub fn version(&self) -> Result<SignatureVersion, VerifyError> {
+        let Some(value) = self.headers.get(SIGNATURE_VERSION_HEADER) else {
+            return Ok(SignatureVersion::V2);
+        };
+        value
+            .to_str()
+            .ok()
+            .and_then(SignatureVersion::from_name)
+            .ok_or_else(|| {
+                VerifyError::Message(format!("unsupported signature version {:?}", value))
+            })
+    }
+
+    /// Rebuild the message the gateway signed.
+    pub fn signing_message(&self) -> Result<Vec<u8>, VerifyError> {
+        Ok(match self.version()? {
+            SignatureVersion::V2 => build_signing_message(
+                self.method,
+                self.path_and_query,
+                self.request_body,
+                self.body,
+            ),
+            SignatureVersion::V3 => build_signing_message_v3(
+                self.method,
+                self.path_and_query,
+                self.request_body,
+                &encode_response_head(
+                    self.status,
+                    self.headers,
+                    signed_payment(self.headers)?.as_ref(),
+                ),
+                self.body,
+            ),
+            SignatureVersion::V4 => build_signing_message_v4(
+                self.method,
+                self.path_and_query,
+                self.request_body,
+                &signed_response_head(self.status, self.headers)?,
+                self.body,
+            ),
+        })
+    }
+
+    /// The digest a payment receipt for this request names.
+    pub fn request_digest(&self) -> Result<RequestDigest, VerifyError> {
+        let prefix = build_request_prefix(
+            self.version()?,
+            self.method,
+            self.path_and_query,
+            self.request_body,
+        );
+        Ok(RequestDigest(Keccak256::digest(prefix).into()))
+    }
+
+    /// The key behind `signature_hex`, from the `X-Signature` header or
+    /// trailer.
+    pub fn recover(&self, signature_hex: &str) -> Result<VerifyingKey, VerifyError> {
+        recover_message(&self.signing_message()?, signature_hex)
+    }
+}
+
+/// Rebuild the [`ResponseHead::encode`] output a v4 signature covers from the
+/// headers the gateway sent alongside it.
+pub fn signed_response_head(
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Result<Vec<u8>, VerifyError> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .ok_or_else(|| VerifyError::Message(format!("no {} header", name)))?
+            .to_str()
+            .map_err(|_| VerifyError::Message(format!("{} header is not text", name)))
+    };
+
+    let timestamp = header(SIGNATURE_TIMESTAMP_HEADER)?.parse().map_err(|_| {
+        VerifyError::Message(format!(
+            "{} is not Unix seconds",
+            SIGNATURE_TIMESTAMP_HEADER
+        ))
+    })?;
+    let nonce = hex::decode(header(SIGNATURE_NONCE_HEADER)?)
+        .ok()
+        .and_then(|nonce| nonce.try_into().ok())
+        .ok_or_else(|| {
+            VerifyError::Message(format!("expected 16-byte {}", SIGNATURE_NONCE_HEADER))
+        })?;
+    let signed_headers: Vec<String> = header(SIGNED_HEADERS_HEADER)?
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let payment = signed_payment(headers)?;
+
+    Ok(ResponseHead {
+        status,
+        timestamp,
+        nonce,
+        headers,
+        signed_headers: &signed_headers,
+        payment: payment.as_ref(),
+    }
+    .encode())
+}
+
+/// The payment in [`SIGNED_PAYMENT_HEADER`]; `None` for unpaid requests.
+fn signed_payment(headers: &HeaderMap) -> Result<Option<SignedPayment>, VerifyError> {
+    let Some(json) = headers.get(SIGNED_PAYMENT_HEADER) else {
+        return Ok(None);
+    };
+    serde_json::from_slice(json.as_bytes())
+        .map(Some)
+        .map_err(|e| {
+            VerifyError::Message(format!(
+                "{} is not a signed payment: {}",
+                SIGNED_PAYMENT_HEADER, e
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::{SigningHasher, sign_message};
+    use http::HeaderValue;
+    use k256::ecdsa::SigningKey;
+
+    fn test_signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[1u8; 32].into()).unwrap()
+    }
+
+    #[test]
+    fn test_key_formats() {
+        let key = *test_signing_key().verifying_key();
+        assert_eq!(
+            public_key_hex(&key),
+            "1b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f70beaf8f588b541507fed6a642c5ab42dfdf8120a7f639de5122d47a69a8e8d1"
+        );
+        assert_eq!(
+            address(&key).to_checksum(None),
+            "0x1a642f0E3c3aF545E7AcBD38b07251B3990914F1"
+        );
+    }
+
+    #[test]
+    fn test_parse_signature_rejects_malformed() {
+        let signature = sign_message(&test_signing_key(), b"message");
+        assert!(parse_signature(&signature).is_ok());
+        assert_eq!(
+            parse_signature(&signature[..128]).unwrap_err(),
+            VerifyError::MalformedSignature
+        );
+        let bad_v = format!("{}01", &signature[..128]);
+        assert_eq!(
+            parse_signature(&bad_v).unwrap_err(),
+            VerifyError::MalformedSignature
+        );
+        assert!(parse_signature("zz").is_err());
+    }
+
+    #[test]
+    fn test_v2_response_recovers_signer() {
+        let key = test_signing_key();
+        let headers = HeaderMap::new();
+        let response = SignedResponse {
+            method: &Method::POST,
+            path_and_query: "/api/chat",
+            request_body: b"hello",
+            status: StatusCode::OK,
+            headers: &headers,
+            body: b"world!",
+        };
+        let signature = sign_message(
+            &key,
+            &build_signing_message(&Method::POST, "/api/chat", b"hello", b"world!"),
+        );
+        assert_eq!(response.recover(&signature).unwrap(), *key.verifying_key());
+
+        let mut hasher = SigningHasher::new(&Method::POST, "/api/chat");
+        hasher.begin_body(5).unwrap();
+        hasher.update(b"hello").unwrap();
+        assert_eq!(
+            response.request_digest().unwrap().0,
+            hasher.digest().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_v3_response_head_from_headers() {
+        let key = test_signing_key();
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let payment = SignedPayment {
+            scheme: "exact".to_string(),
+            network: "eip155:84532".to_string(),
+            amount: "10".to_string(),
+            ..Default::default()
+        };
+        let head = encode_response_head(StatusCode::CREATED, &headers, Some(&payment));
+        let message = build_signing_message_v3(&Method::GET, "/paid", b"", &head, b"{}");
+
+        headers.insert(
+            SIGNATURE_VERSION_HEADER,
+            HeaderValue::from_static("oyster-signature-v3"),
+        );
+        headers.insert(
+            SIGNED_PAYMENT_HEADER,
+            serde_json::to_string(&payment).unwrap().parse().unwrap(),
+        );
+        let response = SignedResponse {
+            method: &Method::GET,
+            path_and_query: "/paid",
+            request_body: b"",
+            status: StatusCode::CREATED,
+            headers: &headers,
+            body: b"{}",
+        };
+        assert_eq!(response.version().unwrap(), SignatureVersion::V3);
+        assert_eq!(response.signing_message().unwrap(), message);
+        let signature = sign_message(&key, &message);
+        assert_eq!(response.recover(&signature).unwrap(), *key.verifying_key());
+
+        let mut tampered = headers.clone();
+        tampered.insert(SIGNED_PAYMENT_HEADER, HeaderValue::from_static("{}"));
+        let response = SignedResponse {
+            headers: &tampered,
+            ..response
+        };
+        assert!(response.signing_message().is_err());
+    }
+
+    #[test]
+    fn test_v4_response_recovers_signer() {
+        let key = test_signing_key();
+        let mut upstream = HeaderMap::new();
+        upstream.insert("content-type", HeaderValue::from_static("application/json"));
+        let payment = SignedPayment {
+            scheme: "exact".to_string(),
+            network: "eip155:84532".to_string(),
+            amount: "10".to_string(),
+            ..Default::default()
+        };
+        let signed_headers = ["content-type".to_string()];
+        let head = ResponseHead::new(StatusCode::OK, &upstream, &signed_headers, Some(&payment));
+        let message = build_signing_message_v4(
+            &Method::POST,
+            "/api/chat",
+            b"hello",
+            &head.encode(),
+            b"world!",
+        );
+
+        let mut headers = upstream.clone();
+        headers.insert(
+            SIGNATURE_VERSION_HEADER,
+            HeaderValue::from_static("oyster-signature-v4"),
+        );
+        headers.insert(
+            SIGNATURE_TIMESTAMP_HEADER,
+            head.timestamp.to_string().parse().unwrap(),
+        );
+        headers.insert(
+            SIGNATURE_NONCE_HEADER,
+            hex::encode(head.nonce).parse().unwrap(),
+        );
+        headers.insert(
+            SIGNED_HEADERS_HEADER,
+            head.signed_header_names().parse().unwrap(),
+        );
+        headers.insert(
+            SIGNED_PAYMENT_HEADER,
+            serde_json::to_string(&payment).unwrap().parse().unwrap(),
+        );
+        let response = SignedResponse {
+            method: &Method::POST,
+            path_and_query: "/api/chat",
+            request_body: b"hello",
+            status: StatusCode::OK,
+            headers: &headers,
+            body: b"world!",
+        };
+        assert_eq!(response.version().unwrap(), SignatureVersion::V4);
+        assert_eq!(response.signing_message().unwrap(), message);
+        let signature = sign_message(&key, &message);
+        assert_eq!(response.recover(&signature).unwrap(), *key.verifying_key());
+
+        let mut hasher =
+            SigningHasher::with_version(SignatureVersion::V4, &Method::POST, "/api/chat");
+        hasher.begin_streamed_body().unwrap();
+        hasher.update(b"hello").unwrap();
+        hasher.finish_body();
+        assert_eq!(
+            response.request_digest().unwrap().0,
+            hasher.digest().unwrap()
+        );
+
+        headers.remove(SIGNATURE_NONCE_HEADER);
+        assert!(signed_response_head(StatusCode::OK, &headers).is_err());
+    }
+}
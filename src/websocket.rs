@@ -0,0 +1,419 @@
+use crate::config::WebSocketBilling;
+use crate::metrics::UpstreamError;
+use crate::state::AppState;
+use crate::upstream::{Forward, unrouted};
+use axum::{
+    body::Body,
+    extract::{
+        FromRequestParts,
+        ws::{self, WebSocket, WebSocketUpgrade, close_code},
+    },
+    http::{HeaderMap, Request, StatusCode, header},
+    response::Response,
+};
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{self, client::IntoClientRequest, protocol::frame::coding::CloseCode},
+};
+use tracing::{debug, error, info};
+use x402_gateway::receipt::RequestDigest;
+use x402_gateway::signing::SigningHasher;
+
+type UpstreamSocket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Handshake headers passed on to the upstream WebSocket server.
+const FORWARDED_HEADERS: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::ORIGIN,
+    header::SEC_WEBSOCKET_PROTOCOL,
+];
+
+/// Per-route limits for a WebSocket session, attached to protected routes as a
+/// request extension. Free routes carry no limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WebSocketSession {
+    /// Client messages allowed before the session has to be paid for again.
+    pub message_limit: Option<u64>,
+}
+
+impl From<&WebSocketBilling> for WebSocketSession {
+    fn from(billing: &WebSocketBilling) -> Self {
+        match billing {
+            WebSocketBilling::PerConnection => Self::default(),
+            WebSocketBilling::PerMessage {
+                messages_per_payment,
+            } => Self {
+                message_limit: Some(*messages_per_payment),
+            },
+        }
+    }
+}
+
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Translate the HTTP target into the matching `ws://` or `wss://` URL.
+fn websocket_url(url: &str) -> Option<String> {
+    if let Some(rest) = url.strip_prefix("https://") {
+        Some(format!("wss://{}", rest))
+    } else {
+        url.strip_prefix("http://")
+            .map(|rest| format!("ws://{}", rest))
+    }
+}
+
+/// Complete a WebSocket handshake with the upstream first, then accept the
+/// client's upgrade and relay frames in both directions.
+///
+/// The upstream is connected before answering so that an unreachable backend
+/// surfaces as `502` and a paid handshake is not settled.
+pub async fn proxy_websocket(
+    state: Arc<AppState>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    let session = req
+        .extensions()
+        .get::<WebSocketSession>()
+        .copied()
+        .unwrap_or_default();
+    let forward = Forward::for_request(&req)?;
+    let Some(lease) = forward.pick() else {
+        error!(upstream = %forward.upstream(), "No healthy backend for the WebSocket");
+        state
+            .metrics
+            .upstream_error(forward.upstream(), UpstreamError::Unavailable);
+        return Ok(unrouted(StatusCode::SERVICE_UNAVAILABLE));
+    };
+    let http_url = forward.url(&lease, req.uri().path(), req.uri().query());
+
+    // The handshake has no body, so the request digest is known up front
+    let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let mut hasher = SigningHasher::new(req.method(), path_and_query);
+    hasher
+        .begin_body(0)
+        .expect("no body has been announced yet");
+    let request_digest = hasher.digest().expect("an empty body is complete");
+
+    let (mut parts, _body) = req.into_parts();
+    let upgrade = WebSocketUpgrade::from_request_parts(&mut parts, &())
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Invalid WebSocket upgrade request");
+            StatusCode::BAD_REQUEST
+        })?;
+
+    let target_url = websocket_url(&http_url).ok_or(StatusCode::BAD_GATEWAY)?;
+    debug!(url = %target_url, "Forwarding WebSocket upgrade upstream");
+
+    let mut upstream_req = target_url.as_str().into_client_request().map_err(|e| {
+        error!(error = %e, "Invalid upstream WebSocket URL");
+        StatusCode::BAD_GATEWAY
+    })?;
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = parts.headers.get(&name) {
+            upstream_req.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    let (upstream, upstream_resp) = match connect_async(upstream_req).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!(error = %e, "Upstream WebSocket handshake failed");
+            lease.record_failure();
+            state
+                .metrics
+                .upstream_error(forward.upstream(), UpstreamError::Connect);
+            return Ok(unrouted(StatusCode::BAD_GATEWAY));
+        }
+    };
+    lease.record_response(upstream_resp.status());
+
+    let mut upgrade = upgrade;
+    if let Some(protocol) = upstream_resp
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+    {
+        upgrade = upgrade.protocols([protocol.to_string()]);
+    }
+
+    let mut response = upgrade.on_upgrade(move |client| async move {
+        relay(client, upstream, session).await;
+        // The backend counts as busy for the whole session
+        drop(lease);
+    });
+    response
+        .extensions_mut()
+        .insert(RequestDigest(request_digest));
+    Ok(response)
+}
+
+async fn relay(client: WebSocket, upstream: UpstreamSocket, session: WebSocketSession) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+
+    let client_to_upstream = async {
+        let mut forwarded = 0u64;
+        while let Some(Ok(message)) = client_rx.next().await {
+            let counted = matches!(message, ws::Message::Text(_) | ws::Message::Binary(_));
+            if counted
+                && session
+                    .message_limit
+                    .is_some_and(|limit| forwarded >= limit)
+            {
+                info!(
+                    messages = forwarded,
+                    "WebSocket session used up its payment"
+                );
+                return Some(ws::CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "payment required: reconnect with a new payment".into(),
+                });
+            }
+            let closing = matches!(message, ws::Message::Close(_));
+            if upstream_tx.send(to_upstream(message)).await.is_err() || closing {
+                break;
+            }
+            if counted {
+                forwarded += 1;
+            }
+        }
+        None
+    };
+
+    let upstream_to_client = async {
+        while let Some(Ok(message)) = upstream_rx.next().await {
+            let Some(message) = to_client(message) else {
+                continue;
+            };
+            let closing = matches!(message, ws::Message::Close(_));
+            if client_tx.send(message).await.is_err() || closing {
+                break;
+            }
+        }
+    };
+
+    let limit_close = tokio::select! {
+        close = client_to_upstream => close,
+        _ = upstream_to_client => None,
+    };
+
+    if let Some(frame) = limit_close {
+        let _ = client_tx.send(ws::Message::Close(Some(frame))).await;
+        let _ = upstream_tx.close().await;
+    }
+}
+
+fn to_upstream(message: ws::Message) -> tungstenite::Message {
+    match message {
+        ws::Message::Text(text) => tungstenite::Message::Text(text.as_str().into()),
+        ws::Message::Binary(data) => tungstenite::Message::Binary(data),
+        ws::Message::Ping(data) => tungstenite::Message::Ping(data),
+        ws::Message::Pong(data) => tungstenite::Message::Pong(data),
+        ws::Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|frame| tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(frame.code),
+                reason: frame.reason.as_str().into(),
+            }))
+        }
+    }
+}
+
+fn to_client(message: tungstenite::Message) -> Option<ws::Message> {
+    Some(match message {
+        tungstenite::Message::Text(text) => ws::Message::Text(text.as_str().into()),
+        tungstenite::Message::Binary(data) => ws::Message::Binary(data),
+        tungstenite::Message::Ping(data) => ws::Message::Ping(data),
+        tungstenite::Message::Pong(data) => ws::Message::Pong(data),
+        tungstenite::Message::Close(frame) => {
+            ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason.as_str().into(),
+            }))
+        }
+        tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ProtectedRoute, UpstreamTarget};
+    use crate::handlers::proxy_request;
+    use crate::upstream::Upstreams;
+    use axum::{Extension, Router, routing::any};
+    use k256::ecdsa::SigningKey;
+    use tokio::net::TcpListener;
+
+    fn make_state(target_url: &str) -> Arc<AppState> {
+        Arc::new(AppState {
+            config: Config {
+                gateway_port: 3000,
+                facilitator_url: "https://www.x402.org/facilitator".to_string(),
+                target_api_url: Some(target_url.to_string()),
+                networks: vec![],
+                protected_routes: vec![ProtectedRoute {
+                    path: "/ws".to_string(),
+                    usdc_amount: Some(1000),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            http_client: reqwest::Client::new(),
+            signing_key: SigningKey::from_bytes(&[1u8; 32].into()).unwrap(),
+            attestation_url: None,
+            metrics: Default::default(),
+            pools: Default::default(),
+            ledger: None,
+            credits: None,
+        })
+    }
+
+    /// Upstream that echoes every text message back.
+    async fn spawn_echo_upstream() -> String {
+        let app = Router::new().route(
+            "/ws",
+            any(|upgrade: WebSocketUpgrade| async move {
+                upgrade.on_upgrade(|mut socket| async move {
+                    while let Some(Ok(message)) = socket.recv().await {
+                        if let ws::Message::Text(_) = message
+                            && socket.send(message).await.is_err()
+                        {
+                            break;
+                        }
+                    }
+                })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    async fn spawn_gateway(state: Arc<AppState>, session: WebSocketSession) -> String {
+        let upstreams = Upstreams::new(&state.config, &state.http_client, &state.pools);
+        let forward = upstreams.forward(&state.config, &UpstreamTarget::default());
+        let app = Router::new()
+            .route("/ws", any(proxy_request))
+            .layer(Extension(session))
+            .layer(Extension(forward))
+            .with_state(state);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("ws://{}", address)
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_websocket_upgrade(&headers));
+        headers.insert(header::UPGRADE, "WebSocket".parse().unwrap());
+        assert!(is_websocket_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_websocket_url() {
+        assert_eq!(
+            websocket_url("http://127.0.0.1:3001/ws?x=1").unwrap(),
+            "ws://127.0.0.1:3001/ws?x=1"
+        );
+        assert_eq!(
+            websocket_url("https://api.example.com/ws").unwrap(),
+            "wss://api.example.com/ws"
+        );
+        assert!(websocket_url("ftp://example.com/ws").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wss_upstreams_are_supported() {
+        // Accepts TCP and hangs up, so the TLS handshake is attempted and fails
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                drop(socket);
+            }
+        });
+
+        let err = connect_async(format!("wss://{}/ws", address))
+            .await
+            .unwrap_err();
+        assert!(
+            !matches!(
+                err,
+                tungstenite::Error::Url(tungstenite::error::UrlError::TlsFeatureNotEnabled)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_proxy_relays_messages() {
+        let upstream = spawn_echo_upstream().await;
+        let gateway = spawn_gateway(make_state(&upstream), WebSocketSession::default()).await;
+
+        let (mut socket, _) = connect_async(format!("{}/ws", gateway)).await.unwrap();
+        socket
+            .send(tungstenite::Message::Text("hello".into()))
+            .await
+            .unwrap();
+        let reply = socket.next().await.unwrap().unwrap();
+        assert_eq!(reply, tungstenite::Message::Text("hello".into()));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_proxy_closes_after_paid_messages() {
+        let upstream = spawn_echo_upstream().await;
+        let session = WebSocketSession::from(&WebSocketBilling::PerMessage {
+            messages_per_payment: 2,
+        });
+        let gateway = spawn_gateway(make_state(&upstream), session).await;
+
+        let (mut socket, _) = connect_async(format!("{}/ws", gateway)).await.unwrap();
+        for text in ["one", "two"] {
+            socket
+                .send(tungstenite::Message::Text(text.into()))
+                .await
+                .unwrap();
+            let reply = socket.next().await.unwrap().unwrap();
+            assert_eq!(reply, tungstenite::Message::Text(text.into()));
+        }
+
+        socket
+            .send(tungstenite::Message::Text("three".into()))
+            .await
+            .unwrap();
+        match socket.next().await.unwrap().unwrap() {
+            tungstenite::Message::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), close_code::POLICY);
+            }
+            other => panic!("expected close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_proxy_upstream_down() {
+        let gateway = spawn_gateway(
+            make_state("http://127.0.0.1:1"),
+            WebSocketSession::default(),
+        )
+        .await;
+        let err = connect_async(format!("{}/ws", gateway)).await.unwrap_err();
+        match err {
+            tungstenite::Error::Http(response) => {
+                assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+            }
+            other => panic!("expected HTTP error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::state::AppState;
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::error;
+use x402_gateway::events::EVENT_SIGNATURE_VERSION;
+use x402_gateway::receipt::RECEIPT_VERSION;
+use x402_gateway::signing::SignatureVersion;
+use x402_gateway::verify::{address, public_key_hex};
+
+pub const WELL_KNOWN_PATH: &str = "/.well-known/oyster";
+
+/// What a client needs to check the gateway's signatures before paying.
+#[derive(Debug, Serialize)]
+pub struct OysterInfo {
+    /// Uncompressed secp256k1 public key without the `04` prefix, as hex, the
+    /// same format the verifier and `oyster-cvm kms-derive` print.
+    pub public_key: String,
+    /// EIP-55 Ethereum address of the signing key.
+    pub address: String,
+    pub signature_versions: Vec<&'static str>,
+    pub event_signature_version: &'static str,
+    pub receipt_version: &'static str,
+    /// Hex of the enclave attestation document; `null` outside an enclave.
+    pub attestation: Option<String>,
+}
+
+/// `GET /.well-known/oyster`. Answers `502` when the attestation server is
+/// configured but cannot be reached, rather than omitting the document.
+pub async fn oyster_info(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<OysterInfo>, StatusCode> {
+    let key = state.signing_key.verifying_key();
+
+    let attestation = match &state.attestation_url {
+        Some(url) => Some(hex::encode(
+            fetch_attestation(&state.http_client, url).await?,
+        )),
+        None => None,
+    };
+
+    Ok(Json(OysterInfo {
+        public_key: public_key_hex(key),
+        address: address(key).to_checksum(None),
+        signature_versions: SignatureVersion::ALL.map(SignatureVersion::as_str).to_vec(),
+        event_signature_version: EVENT_SIGNATURE_VERSION,
+        receipt_version: RECEIPT_VERSION,
+        attestation,
+    }))
+}
+
+async fn fetch_attestation(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, StatusCode> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            error!(url, error = %e, "Failed to fetch attestation");
+            StatusCode::BAD_GATEWAY
+        })?;
+    let document = response.bytes().await.map_err(|e| {
+        error!(url, error = %e, "Failed to read attestation");
+        StatusCode::BAD_GATEWAY
+    })?;
+    Ok(document.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::{Router, body::Body, http::Request, routing::get};
+    use k256::ecdsa::SigningKey;
+    use tower::ServiceExt;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn make_state(attestation_url: Option<String>) -> Arc<AppState> {
+        Arc::new(AppState {
+            config: Config::default(),
+            http_client: reqwest::Client::new(),
+            signing_key: SigningKey::from_bytes(&[1u8; 32].into()).unwrap(),
+            attestation_url,
+            metrics: Default::default(),
+            pools: Default::default(),
+            ledger: None,
+            credits: None,
+        })
+    }
+
+    async fn get_info(state: Arc<AppState>) -> (StatusCode, serde_json::Value) {
+        let router = Router::new()
+            .route(WELL_KNOWN_PATH, get(oyster_info))
+            .with_state(state);
+        let req = Request::builder()
+            .uri(WELL_KNOWN_PATH)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(req).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_info_includes_attestation() {
+        let attestation_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/attestation/raw"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0x84, 0x44, 0xa1, 0x01]))
+            .expect(1)
+            .mount(&attestation_server)
+            .await;
+
+        let url = format!("{}/attestation/raw", attestation_server.uri());
+        let (status, info) = get_info(make_state(Some(url))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            info["public_key"],
+            "1b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f70beaf8f588b541507fed6a642c5ab42dfdf8120a7f639de5122d47a69a8e8d1"
+        );
+        assert_eq!(
+            info["address"],
+            "0x1a642f0E3c3aF545E7AcBD38b07251B3990914F1"
+        );
+        assert_eq!(
+            info["signature_versions"],
+            serde_json::json!([
+                "oyster-signature-v2",
+                "oyster-signature-v3",
+                "oyster-signature-v4"
+            ])
+        );
+        assert_eq!(info["receipt_version"], RECEIPT_VERSION);
+        assert_eq!(info["attestation"], "8444a101");
+    }
+
+    #[tokio::test]
+    async fn test_info_without_attestation() {
+        let (status, info) = get_info(make_state(None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(info["attestation"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_info_attestation_unavailable() {
+        let attestation_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&attestation_server)
+            .await;
+
+        let url = format!("{}/attestation/raw", attestation_server.uri());
+        let (status, _) = get_info(make_state(Some(url))).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+}
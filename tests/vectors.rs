@@ -0,0 +1,287 @@
+//! Checks the library against `tests/vectors.json`. A change that breaks one
+//! of these breaks every client that already verifies gateway signatures, so
+//! regenerate the vectors only alongside a new protocol version.
+
+use http::{HeaderMap, HeaderName, Method, StatusCode};
+use k256::ecdsa::SigningKey;
+use serde_json::Value;
+use sha3::Digest;
+use x402_gateway::credit::CreditAuthorization;
+use x402_gateway::events::event_digest;
+use x402_gateway::pass::AccessPass;
+use x402_gateway::receipt::Receipt;
+use x402_gateway::signing::{
+    SignatureVersion, SigningHasher, build_signing_message, build_signing_message_v3,
+    build_signing_message_v4, sign_digest, sign_message,
+};
+use x402_gateway::verify::{SignedResponse, address, public_key_hex, recover_digest};
+
+fn vectors() -> Value {
+    serde_json::from_str(include_str!("vectors.json")).unwrap()
+}
+
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap()
+}
+
+fn bytes(value: &Value) -> Vec<u8> {
+    hex::decode(text(value)).unwrap()
+}
+
+fn signing_key(vectors: &Value) -> SigningKey {
+    SigningKey::from_slice(&bytes(&vectors["key"]["private_key"])).unwrap()
+}
+
+#[test]
+fn test_key() {
+    let vectors = vectors();
+    let key = *signing_key(&vectors).verifying_key();
+    assert_eq!(public_key_hex(&key), text(&vectors["key"]["public_key"]));
+    assert_eq!(
+        address(&key).to_checksum(None),
+        text(&vectors["key"]["address"])
+    );
+}
+
+#[test]
+fn test_signature_v2() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["signature_v2"];
+    let method = Method::from_bytes(text(&case["method"]).as_bytes()).unwrap();
+    let request_body = bytes(&case["request_body"]);
+    let response_body = bytes(&case["response_body"]);
+
+    let message = build_signing_message(
+        &method,
+        text(&case["path_and_query"]),
+        &request_body,
+        &response_body,
+    );
+    assert_eq!(hex::encode(&message), text(&case["message"]));
+    assert_eq!(sign_message(&key, &message), text(&case["signature"]));
+
+    let headers = HeaderMap::new();
+    let response = SignedResponse {
+        method: &method,
+        path_and_query: text(&case["path_and_query"]),
+        request_body: &request_body,
+        status: StatusCode::OK,
+        headers: &headers,
+        body: &response_body,
+    };
+    assert_eq!(response.version().unwrap(), SignatureVersion::V2);
+    assert_eq!(
+        response.recover(text(&case["signature"])).unwrap(),
+        *key.verifying_key()
+    );
+}
+
+#[test]
+fn test_signature_v3() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["signature_v3"];
+    let method = Method::from_bytes(text(&case["method"]).as_bytes()).unwrap();
+    let request_body = bytes(&case["request_body"]);
+    let response_body = bytes(&case["response_body"]);
+
+    let message = build_signing_message_v3(
+        &method,
+        text(&case["path_and_query"]),
+        &request_body,
+        &bytes(&case["response_head"]),
+        &response_body,
+    );
+    assert_eq!(hex::encode(&message), text(&case["message"]));
+    assert_eq!(sign_message(&key, &message), text(&case["signature"]));
+
+    // The head must also rebuild from the headers a client actually sees.
+    let mut headers = HeaderMap::new();
+    for (name, value) in case["response_headers"].as_object().unwrap() {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            text(value).parse().unwrap(),
+        );
+    }
+    let response = SignedResponse {
+        method: &method,
+        path_and_query: text(&case["path_and_query"]),
+        request_body: &request_body,
+        status: StatusCode::from_u16(case["status"].as_u64().unwrap() as u16).unwrap(),
+        headers: &headers,
+        body: &response_body,
+    };
+    assert_eq!(response.version().unwrap(), SignatureVersion::V3);
+    assert_eq!(response.signing_message().unwrap(), message);
+    assert_eq!(
+        hex::encode(response.request_digest().unwrap().0),
+        text(&case["request_digest"])
+    );
+    assert_eq!(
+        response.recover(text(&case["signature"])).unwrap(),
+        *key.verifying_key()
+    );
+}
+
+#[test]
+fn test_signature_v4() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["signature_v4"];
+    let method = Method::from_bytes(text(&case["method"]).as_bytes()).unwrap();
+    let request_body = bytes(&case["request_body"]);
+    let response_body = bytes(&case["response_body"]);
+
+    let message = build_signing_message_v4(
+        &method,
+        text(&case["path_and_query"]),
+        &request_body,
+        &bytes(&case["response_head"]),
+        &response_body,
+    );
+    assert_eq!(hex::encode(&message), text(&case["message"]));
+    assert_eq!(sign_message(&key, &message), text(&case["signature"]));
+
+    // Streaming both bodies without their lengths gives the same signature.
+    let mut hasher =
+        SigningHasher::with_version(SignatureVersion::V4, &method, text(&case["path_and_query"]));
+    hasher.begin_streamed_body().unwrap();
+    hasher.update(&request_body).unwrap();
+    hasher.finish_body();
+    hasher.set_response_head(bytes(&case["response_head"]));
+    hasher.begin_streamed_body().unwrap();
+    hasher.update(&response_body).unwrap();
+    hasher.finish_body();
+    assert_eq!(hasher.sign(&key).unwrap(), text(&case["signature"]));
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in case["response_headers"].as_object().unwrap() {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            text(value).parse().unwrap(),
+        );
+    }
+    let response = SignedResponse {
+        method: &method,
+        path_and_query: text(&case["path_and_query"]),
+        request_body: &request_body,
+        status: StatusCode::from_u16(case["status"].as_u64().unwrap() as u16).unwrap(),
+        headers: &headers,
+        body: &response_body,
+    };
+    assert_eq!(response.version().unwrap(), SignatureVersion::V4);
+    assert_eq!(response.signing_message().unwrap(), message);
+    assert_eq!(
+        hex::encode(response.request_digest().unwrap().0),
+        text(&case["request_digest"])
+    );
+    assert_eq!(
+        response.recover(text(&case["signature"])).unwrap(),
+        *key.verifying_key()
+    );
+}
+
+#[test]
+fn test_receipt() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["receipt"];
+    let receipt: Receipt = serde_json::from_value(case["receipt"].clone()).unwrap();
+
+    let digest = receipt.digest().unwrap();
+    assert_eq!(
+        hex::encode(digest.clone().finalize()),
+        text(&case["digest"])
+    );
+    assert_eq!(sign_digest(&key, digest), receipt.signature);
+    assert_eq!(
+        Receipt::from_header(receipt.to_header().as_bytes()).unwrap(),
+        receipt
+    );
+    assert_eq!(receipt.recover().unwrap(), *key.verifying_key());
+}
+
+#[test]
+fn test_access_pass() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["access_pass"];
+    let pass: AccessPass = serde_json::from_value(case["pass"].clone()).unwrap();
+
+    let digest = pass.digest();
+    assert_eq!(
+        hex::encode(digest.clone().finalize()),
+        text(&case["digest"])
+    );
+    assert_eq!(sign_digest(&key, digest), pass.signature);
+    assert_eq!(
+        AccessPass::from_header(pass.to_header().as_bytes()).unwrap(),
+        pass
+    );
+    assert_eq!(pass.recover().unwrap(), *key.verifying_key());
+}
+
+#[test]
+fn test_events() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["events"];
+
+    let mut previous: [u8; 32] = bytes(&case["anchor"]).try_into().unwrap();
+    for event in case["events"].as_array().unwrap() {
+        let digest = event_digest(
+            &previous,
+            event["index"].as_u64().unwrap(),
+            &bytes(&event["event"]),
+        );
+        assert_eq!(sign_digest(&key, digest.clone()), text(&event["signature"]));
+        assert_eq!(
+            recover_digest(digest.clone(), text(&event["signature"])).unwrap(),
+            *key.verifying_key()
+        );
+        previous = digest.finalize().into();
+        assert_eq!(hex::encode(previous), text(&event["digest"]));
+    }
+}
+
+#[test]
+fn test_credit() {
+    let vectors = vectors();
+    let key = signing_key(&vectors);
+    let case = &vectors["credit"];
+    let method = Method::from_bytes(text(&case["method"]).as_bytes()).unwrap();
+    let path_and_query = text(&case["path_and_query"]);
+    let body = bytes(&case["body"]);
+
+    let authorization = CreditAuthorization::sign(
+        &key,
+        case["timestamp"].as_u64().unwrap(),
+        bytes(&case["nonce"]).try_into().unwrap(),
+        &method,
+        path_and_query,
+        &body,
+    );
+    assert_eq!(
+        hex::encode(
+            authorization
+                .digest(&method, path_and_query, &body)
+                .finalize()
+        ),
+        text(&case["digest"])
+    );
+    assert_eq!(authorization.signature, text(&case["signature"]));
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in case["headers"].as_object().unwrap() {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            text(value).parse().unwrap(),
+        );
+    }
+    let decoded = CreditAuthorization::from_headers(&headers)
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded, authorization);
+    decoded.verify(&method, path_and_query, &body).unwrap();
+}